@@ -0,0 +1,327 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local, SecondsFormat};
+use parking_lot::Mutex;
+
+/// The line formats the access log can be written in, selected with --access-log-format (or the
+/// `format` setting of the `[access_log]` section of a configuration file).
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessLogFormat {
+    /// Apache's combined log format, followed by the request body size, the upstream, the
+    /// upstream latency and the total latency
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+/// Writes a line about every request to a file, renaming the file out of the way once it grows
+/// past `max_size` bytes: `access.log` becomes `access.log.1`, `access.log.1` becomes
+/// `access.log.2`, and so on, keeping up to `max_files` old files.
+///
+/// The file is written (and rotated) by a thread of its own, so that requests never wait on the
+/// disk. Lines are buffered while more are queued up and flushed as soon as the queue is empty,
+/// so little is lost if balancebeam is killed.
+pub struct AccessLog {
+    path: PathBuf,
+    format: Mutex<AccessLogFormat>,
+    sender: mpsc::Sender<Message>,
+}
+
+/// What the writer thread is asked to do
+enum Message {
+    Line(String),
+    SetLimits { max_size: u64, max_files: usize },
+}
+
+struct LogFile {
+    file: BufWriter<File>,
+    /// Bytes in the current file
+    size: u64,
+    /// Size past which the file is rotated (0 = never)
+    max_size: u64,
+    /// How many rotated files are kept
+    max_files: usize,
+}
+
+impl AccessLog {
+    /// Opens (or creates) the access log at `path`, appending to whatever it already holds, and
+    /// starts the thread that writes to it.
+    pub fn open(
+        path: &Path,
+        format: AccessLogFormat,
+        max_size: u64,
+        max_files: usize,
+    ) -> std::io::Result<AccessLog> {
+        let file = open_for_append(path)?;
+        let size = file.metadata()?.len();
+        let log_file = LogFile {
+            file: BufWriter::new(file),
+            size,
+            max_size,
+            max_files,
+        };
+        let (sender, receiver) = mpsc::channel();
+        let thread_path = path.to_path_buf();
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || log_file.run(&thread_path, receiver))?;
+        Ok(AccessLog {
+            path: path.to_path_buf(),
+            format: Mutex::new(format),
+            sender,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Changes the format and rotation limits (e.g. after the configuration is reloaded). The
+    /// file stays where it is.
+    pub fn set_options(&self, format: AccessLogFormat, max_size: u64, max_files: usize) {
+        *self.format.lock() = format;
+        let _ = self.sender.send(Message::SetLimits {
+            max_size,
+            max_files,
+        });
+    }
+
+    fn write(&self, entry: &Entry) {
+        let mut line = match *self.format.lock() {
+            AccessLogFormat::Combined => entry.to_combined(),
+            AccessLogFormat::Json => entry.to_json(),
+        };
+        line.push('\n');
+        // The writer thread only stops if it panicked
+        let _ = self.sender.send(Message::Line(line));
+    }
+}
+
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AccessLog({})", self.path.display())
+    }
+}
+
+impl LogFile {
+    /// Handles messages until every AccessLog sending them is gone, flushing whenever it runs out
+    /// of messages.
+    fn run(mut self, path: &Path, receiver: mpsc::Receiver<Message>) {
+        while let Ok(mut message) = receiver.recv() {
+            loop {
+                match message {
+                    Message::Line(line) => self.write(path, &line),
+                    Message::SetLimits {
+                        max_size,
+                        max_files,
+                    } => {
+                        self.max_size = max_size;
+                        self.max_files = max_files;
+                    }
+                }
+                message = match receiver.try_recv() {
+                    Ok(message) => message,
+                    Err(_) => break,
+                };
+            }
+            if let Err(err) = self.file.flush() {
+                log::error!("Could not write to access log {}: {}", path.display(), err);
+            }
+        }
+    }
+
+    fn write(&mut self, path: &Path, line: &str) {
+        if self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            if let Err(err) = self.rotate(path) {
+                log::error!("Could not rotate access log {}: {}", path.display(), err);
+            }
+        }
+        match self.file.write_all(line.as_bytes()) {
+            Ok(()) => self.size += line.len() as u64,
+            Err(err) => log::error!("Could not write to access log {}: {}", path.display(), err),
+        }
+    }
+
+    /// Shifts the rotated files along (dropping the oldest) and starts a fresh file at `path`.
+    fn rotate(&mut self, path: &Path) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            std::fs::remove_file(path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                let older = rotated_path(path, n);
+                if older.exists() {
+                    std::fs::rename(older, rotated_path(path, n + 1))?;
+                }
+            }
+            std::fs::rename(path, rotated_path(path, 1))?;
+        }
+        self.file = BufWriter::new(open_for_append(path)?);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_for_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Returns the path of the `n`th most recent rotated file (e.g. access.log.2 for n = 2).
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", n));
+    PathBuf::from(rotated)
+}
+
+/// What happened to a request. The request handling code fills it in as it goes, and the entry is
+/// written to the access log (if there is one) when it is dropped, however the request ended.
+pub struct Entry {
+    log: Option<Arc<AccessLog>>,
+    client_ip: IpAddr,
+    /// When the request's headers had been received
+    time: DateTime<Local>,
+    started: Instant,
+    method: String,
+    uri: String,
+    version: http::Version,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    /// Status of the response sent to the client (None if the connection broke off before one
+    /// was sent)
+    pub status: Option<http::StatusCode>,
+    /// Bytes of request body received from the client
+    pub bytes_in: u64,
+    /// Bytes of response body sent to the client
+    pub bytes_out: u64,
+    /// The upstream the request was (last) sent to, if any
+    pub upstream: Option<String>,
+    /// How long the upstream took to receive the request and send back the whole response
+    pub upstream_latency: Option<Duration>,
+}
+
+impl Entry {
+    pub fn new(
+        log: Option<Arc<AccessLog>>,
+        client_ip: IpAddr,
+        request: &http::Request<Vec<u8>>,
+    ) -> Entry {
+        let header = |name| {
+            request
+                .headers()
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        };
+        Entry {
+            log,
+            client_ip,
+            time: Local::now(),
+            started: Instant::now(),
+            method: request.method().to_string(),
+            uri: request.uri().to_string(),
+            version: request.version(),
+            referer: header(http::header::REFERER),
+            user_agent: header(http::header::USER_AGENT),
+            status: None,
+            bytes_in: 0,
+            bytes_out: 0,
+            upstream: None,
+            upstream_latency: None,
+        }
+    }
+
+    /// Records a response generated by balancebeam itself (e.g. an error), body and all
+    pub fn record_response(&mut self, response: &http::Response<Vec<u8>>) {
+        self.status = Some(response.status());
+        self.bytes_out = response.body().len() as u64;
+    }
+
+    /// Formats the entry in Apache's combined log format (`%h %l %u %t "%r" %>s %b
+    /// "%{Referer}i" "%{User-agent}i"`), followed by the request body size, the upstream in quotes,
+    /// and the upstream and total latencies in seconds. Missing values are written as `-`.
+    fn to_combined(&self) -> String {
+        let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {} \"{}\" {} {:.3}",
+            self.client_ip,
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(&self.method),
+            escape(&self.uri),
+            version_str(self.version),
+            or_dash(self.status.map(|status| status.as_u16().to_string())),
+            // Apache writes `-` rather than 0 for an empty body
+            or_dash(
+                Some(self.bytes_out)
+                    .filter(|&bytes| bytes > 0)
+                    .map(|b| b.to_string())
+            ),
+            escape(self.referer.as_deref().unwrap_or("-")),
+            escape(self.user_agent.as_deref().unwrap_or("-")),
+            self.bytes_in,
+            escape(self.upstream.as_deref().unwrap_or("-")),
+            or_dash(
+                self.upstream_latency
+                    .map(|latency| format!("{:.3}", latency.as_secs_f64()))
+            ),
+            self.started.elapsed().as_secs_f64(),
+        )
+    }
+
+    /// Formats the entry as a JSON object. Latencies are in seconds.
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "time": self.time.to_rfc3339_opts(SecondsFormat::Millis, false),
+            "client_ip": self.client_ip.to_string(),
+            "method": self.method,
+            "uri": self.uri,
+            "protocol": version_str(self.version),
+            "status": self.status.map(|status| status.as_u16()),
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out,
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+            "upstream": self.upstream,
+            "upstream_latency": self.upstream_latency.map(|latency| latency.as_secs_f64()),
+            "total_latency": self.started.elapsed().as_secs_f64(),
+        })
+        .to_string()
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        if let Some(log) = self.log.take() {
+            log.write(self);
+        }
+    }
+}
+
+fn version_str(version: http::Version) -> &'static str {
+    match version {
+        http::Version::HTTP_09 => "HTTP/0.9",
+        http::Version::HTTP_10 => "HTTP/1.0",
+        http::Version::HTTP_2 => "HTTP/2.0",
+        http::Version::HTTP_3 => "HTTP/3.0",
+        _ => "HTTP/1.1",
+    }
+}
+
+/// Escapes quotes, backslashes and control characters the way Apache does, so that a field can't
+/// break out of its quotes (or its line).
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
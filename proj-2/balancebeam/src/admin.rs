@@ -0,0 +1,264 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::RwLock;
+
+use crate::circuit_breaker::CircuitState;
+use crate::routing::DEFAULT_POOL;
+use crate::{body, health, metrics, request, response, ProxyState};
+
+/// Largest request body the admin API accepts
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/// Serves the admin endpoints on `listener`. These are kept off the client-facing listener so that
+/// they can be bound to an address only operators can reach. Besides /metrics, there is a small
+/// JSON API for managing upstreams at runtime, e.g. during deploys:
+///
+/// * `GET /upstreams` lists the upstreams of every pool with their health, draining and circuit
+///   breaker status
+/// * `POST /upstreams` with a body of `{"address": "10.0.0.3:8080"}` adds an upstream to the
+///   default pool, or to another one with `{"address": "10.0.0.3:8080", "pool": "api"}`
+/// * `DELETE /upstreams/<address>` removes an upstream from every pool it is in
+/// * `POST /upstreams/<address>/drain` stops sending new requests to an upstream while letting
+///   in-flight ones finish; `DELETE /upstreams/<address>/drain` undoes this
+/// * `POST /health-check` runs an active health check right away
+///
+/// Changes made through the API last until the configuration file is reloaded.
+pub async fn serve(listener: TcpListener, state: Arc<RwLock<ProxyState>>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                log::warn!("Could not accept admin connection: {}", err);
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            handle_connection(stream, &state).await;
+        });
+    }
+}
+
+async fn handle_connection(mut conn: TcpStream, state: &RwLock<ProxyState>) {
+    // Bytes of the next request that were read along with the previous one
+    let mut pipelined = Vec::new();
+    loop {
+        let read_result = request::read_headers(&mut conn, std::mem::take(&mut pipelined)).await;
+        let mut request = match read_result {
+            Ok(request) => request,
+            Err(request::Error::IncompleteRequest(0)) | Err(request::Error::ConnectionError(_)) => {
+                return;
+            }
+            Err(error) => {
+                log::debug!("Error parsing admin request: {}", error);
+                let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+                let _ = response::write_to_stream(&response, &mut conn).await;
+                return;
+            }
+        };
+        let framing = match request::body_framing(&mut request) {
+            Ok(body::Framing::Length(length)) if length > MAX_BODY_SIZE => {
+                let response = response::make_http_error(http::StatusCode::PAYLOAD_TOO_LARGE);
+                let _ = response::write_to_stream(&response, &mut conn).await;
+                return;
+            }
+            Ok(framing) => framing,
+            Err(_) => {
+                let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+                let _ = response::write_to_stream(&response, &mut conn).await;
+                return;
+            }
+        };
+        let mut body_start = std::mem::take(request.body_mut());
+        if body::copy_body(&mut conn, &mut body_start, framing, request.body_mut())
+            .await
+            .is_err()
+        {
+            return;
+        }
+        pipelined = body_start;
+
+        let response = route(&request, state).await;
+        log::info!(
+            "admin: {} -> {}",
+            request::format_request_line(&request),
+            response.status()
+        );
+        if let Err(error) = response::write_to_stream(&response, &mut conn).await {
+            log::warn!("Failed to send admin response: {}", error);
+            return;
+        }
+    }
+}
+
+/// An upstream as reported by GET /upstreams. Upstreams that are in several pools are listed once
+/// for each.
+#[derive(Debug, Serialize)]
+struct UpstreamStatus {
+    address: String,
+    pool: String,
+    /// Whether the upstream is passing health checks
+    healthy: bool,
+    /// Whether the upstream is being kept from receiving new requests
+    draining: bool,
+    /// State of the upstream's circuit breaker
+    circuit: CircuitState,
+    /// Requests the upstream is currently handling
+    active_requests: u64,
+}
+
+/// Body of POST /upstreams
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewUpstream {
+    address: String,
+    #[serde(default = "default_pool")]
+    pool: String,
+}
+
+fn default_pool() -> String {
+    DEFAULT_POOL.to_string()
+}
+
+async fn route(
+    request: &http::Request<Vec<u8>>,
+    state: &RwLock<ProxyState>,
+) -> http::Response<Vec<u8>> {
+    let path = request.uri().path();
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let method = request.method();
+    match segments.as_slice() {
+        ["metrics"] if method == http::Method::GET => {
+            let text = metrics::render(&*state.read().await);
+            make_response(
+                http::StatusCode::OK,
+                "text/plain; version=0.0.4",
+                text.into_bytes(),
+            )
+        }
+        ["upstreams"] if method == http::Method::GET => list_upstreams(state).await,
+        ["upstreams"] if method == http::Method::POST => {
+            let new_upstream: NewUpstream = match serde_json::from_slice(request.body()) {
+                Ok(new_upstream) => new_upstream,
+                Err(err) => {
+                    return make_json_error(
+                        http::StatusCode::BAD_REQUEST,
+                        &format!("invalid upstream: {}", err),
+                    )
+                }
+            };
+            let added = state
+                .write()
+                .await
+                .add_upstream(&new_upstream.pool, &new_upstream.address);
+            match added {
+                Some(true) => {}
+                Some(false) => {
+                    return make_json_error(
+                        http::StatusCode::CONFLICT,
+                        &format!(
+                            "{} is already an upstream of pool {}",
+                            new_upstream.address, new_upstream.pool
+                        ),
+                    )
+                }
+                None => {
+                    return make_json_error(
+                        http::StatusCode::NOT_FOUND,
+                        &format!("there is no pool named {}", new_upstream.pool),
+                    )
+                }
+            }
+            log::info!(
+                "Added upstream {} to pool {} through the admin API",
+                new_upstream.address,
+                new_upstream.pool
+            );
+            list_upstreams(state).await
+        }
+        ["upstreams", address] if method == http::Method::DELETE => {
+            if !state.write().await.remove_upstream(address) {
+                return unknown_upstream(address);
+            }
+            log::info!("Removed upstream {} through the admin API", address);
+            list_upstreams(state).await
+        }
+        ["upstreams", address, "drain"]
+            if method == http::Method::POST || method == http::Method::DELETE =>
+        {
+            let draining = method == http::Method::POST;
+            if !state.write().await.set_draining(address, draining) {
+                return unknown_upstream(address);
+            }
+            if draining {
+                log::info!("Draining upstream {}", address);
+            } else {
+                log::info!("Stopped draining upstream {}", address);
+            }
+            list_upstreams(state).await
+        }
+        ["health-check"] if method == http::Method::POST => {
+            health::health_check(state).await;
+            list_upstreams(state).await
+        }
+        ["metrics"]
+        | ["upstreams"]
+        | ["upstreams", _]
+        | ["upstreams", _, "drain"]
+        | ["health-check"] => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        _ => response::make_http_error(http::StatusCode::NOT_FOUND),
+    }
+}
+
+async fn list_upstreams(state: &RwLock<ProxyState>) -> http::Response<Vec<u8>> {
+    let state_read = state.read().await;
+    let upstreams: Vec<UpstreamStatus> = state_read
+        .pools
+        .iter()
+        .flat_map(|pool| {
+            pool.configured_upstreams
+                .iter()
+                .map(|upstream| UpstreamStatus {
+                    address: upstream.clone(),
+                    pool: pool.name.clone(),
+                    healthy: pool.upstream_addresses.contains(upstream),
+                    draining: pool.draining_upstreams.contains(upstream),
+                    circuit: state_read.circuit_breakers.state(upstream),
+                    active_requests: state_read.metrics.active_requests(upstream),
+                })
+        })
+        .collect();
+    make_response(
+        http::StatusCode::OK,
+        "application/json",
+        serde_json::to_vec(&upstreams).unwrap(),
+    )
+}
+
+fn unknown_upstream(address: &str) -> http::Response<Vec<u8>> {
+    make_json_error(
+        http::StatusCode::NOT_FOUND,
+        &format!("{} is not an upstream", address),
+    )
+}
+
+fn make_json_error(status: http::StatusCode, message: &str) -> http::Response<Vec<u8>> {
+    let body = serde_json::json!({ "error": message });
+    make_response(status, "application/json", body.to_string().into_bytes())
+}
+
+fn make_response(
+    status: http::StatusCode,
+    content_type: &str,
+    body: Vec<u8>,
+) -> http::Response<Vec<u8>> {
+    http::Response::builder()
+        .status(status)
+        .header("Content-Type", content_type)
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
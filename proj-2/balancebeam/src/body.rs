@@ -0,0 +1,218 @@
+use std::cmp::min;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::chunked;
+
+/// Size of the buffer used to pipe body bytes from one stream to another. Only this much of a body
+/// is held in memory at a time, however large the body is.
+const COPY_BUFFER_SIZE: usize = 16 * 1024;
+
+/// How the end of a message body is determined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// The message has no body
+    Empty,
+    /// The body is exactly this many bytes long (Content-Length)
+    Length(u64),
+    /// The body uses the chunked transfer coding
+    Chunked,
+    /// The body continues until the sender closes the connection (only possible for responses)
+    UntilClose,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The sender hung up before the end of the body
+    Truncated,
+    /// The sender's chunked encoding could not be decoded
+    MalformedChunkedBody,
+    /// Encountered an I/O error when reading from the sender
+    Read(std::io::Error),
+    /// Encountered an I/O error when writing to the recipient
+    Write(std::io::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "sender hung up before the end of the body"),
+            Error::MalformedChunkedBody => write!(f, "malformed chunked body"),
+            Error::Read(err) => write!(f, "error reading body: {}", err),
+            Error::Write(err) => write!(f, "error writing body: {}", err),
+        }
+    }
+}
+
+/// Pipes a message body from `reader` to `writer` without buffering more than COPY_BUFFER_SIZE
+/// bytes of it. Since every write is awaited before the next read, a slow recipient slows down
+/// the sender (backpressure) rather than making us buffer.
+///
+/// `already_read` holds body bytes that were read from `reader` along with the headers. Anything
+/// read past the end of the body (the start of a pipelined message) is left in it. Chunked bodies
+/// are re-encoded as chunked (with trailers), and bodies delimited by the connection closing are
+/// sent as chunked so that the recipient's connection can be kept alive; the caller is responsible
+/// for having sent matching headers. Returns the number of body bytes copied.
+pub async fn copy_body<R, W>(
+    reader: &mut R,
+    already_read: &mut Vec<u8>,
+    framing: Framing,
+    writer: &mut W,
+) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    copy(reader, already_read, framing, writer, true).await
+}
+
+/// Like copy_body, but sends chunked bodies and bodies delimited by the connection closing as they
+/// are, without any framing (dropping trailers). This is for HTTP/1.0 recipients, which don't
+/// understand the chunked coding; the caller has to close the connection afterwards to mark the end
+/// of the body.
+pub async fn copy_body_unchunked<R, W>(
+    reader: &mut R,
+    already_read: &mut Vec<u8>,
+    framing: Framing,
+    writer: &mut W,
+) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    copy(reader, already_read, framing, writer, false).await
+}
+
+async fn copy<R, W>(
+    reader: &mut R,
+    already_read: &mut Vec<u8>,
+    framing: Framing,
+    writer: &mut W,
+    chunked_output: bool,
+) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let copied = match framing {
+        Framing::Empty => 0,
+        Framing::Length(length) => copy_length(reader, already_read, length, writer).await?,
+        Framing::Chunked => copy_chunked(reader, already_read, writer, chunked_output).await?,
+        Framing::UntilClose => {
+            copy_until_close(reader, std::mem::take(already_read), writer, chunked_output).await?
+        }
+    };
+    writer.flush().await.map_err(Error::Write)?;
+    Ok(copied)
+}
+
+/// Writes part of a body, as a chunk if `chunked_output` is set
+async fn write_data<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    data: &[u8],
+    chunked_output: bool,
+) -> Result<(), Error> {
+    let written = if chunked_output {
+        chunked::write_chunk(writer, data).await
+    } else {
+        writer.write_all(data).await
+    };
+    written.map_err(Error::Write)
+}
+
+async fn copy_length<R, W>(
+    reader: &mut R,
+    already_read: &mut Vec<u8>,
+    length: u64,
+    writer: &mut W,
+) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // Anything past the end of the body is the start of the next message, so it stays behind
+    let body_start: Vec<u8> = already_read
+        .drain(..min(already_read.len() as u64, length) as usize)
+        .collect();
+    writer.write_all(&body_start).await.map_err(Error::Write)?;
+    let mut copied = body_start.len() as u64;
+    let mut buffer = vec![0_u8; COPY_BUFFER_SIZE];
+    while copied < length {
+        let to_read = min(buffer.len() as u64, length - copied) as usize;
+        let bytes_read = reader
+            .read(&mut buffer[..to_read])
+            .await
+            .map_err(Error::Read)?;
+        if bytes_read == 0 {
+            return Err(Error::Truncated);
+        }
+        writer
+            .write_all(&buffer[..bytes_read])
+            .await
+            .map_err(Error::Write)?;
+        copied += bytes_read as u64;
+    }
+    Ok(copied)
+}
+
+async fn copy_chunked<R, W>(
+    reader: &mut R,
+    already_read: &mut Vec<u8>,
+    writer: &mut W,
+    chunked_output: bool,
+) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut chunked_reader = chunked::ChunkedReader::new(reader, std::mem::take(already_read));
+    let mut copied = 0;
+    loop {
+        match chunked_reader.next().await {
+            Ok(Some(data)) => {
+                write_data(writer, &data, chunked_output).await?;
+                copied += data.len() as u64;
+            }
+            Ok(None) => break,
+            Err(chunked::Error::Io(err)) => return Err(Error::Read(err)),
+            Err(chunked::Error::Truncated) => return Err(Error::Truncated),
+            Err(chunked::Error::Malformed) => return Err(Error::MalformedChunkedBody),
+        }
+    }
+    *already_read = chunked_reader.take_unread();
+    if chunked_output {
+        let trailers = chunked::Trailers(chunked_reader.into_trailers());
+        chunked::write_last_chunk(writer, Some(&trailers))
+            .await
+            .map_err(Error::Write)?;
+    }
+    Ok(copied)
+}
+
+async fn copy_until_close<R, W>(
+    reader: &mut R,
+    already_read: Vec<u8>,
+    writer: &mut W,
+    chunked_output: bool,
+) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    write_data(writer, &already_read, chunked_output).await?;
+    let mut copied = already_read.len() as u64;
+    let mut buffer = vec![0_u8; COPY_BUFFER_SIZE];
+    loop {
+        let bytes_read = reader.read(&mut buffer).await.map_err(Error::Read)?;
+        if bytes_read == 0 {
+            break;
+        }
+        write_data(writer, &buffer[..bytes_read], chunked_output).await?;
+        copied += bytes_read as u64;
+    }
+    if chunked_output {
+        chunked::write_last_chunk(writer, None)
+            .await
+            .map_err(Error::Write)?;
+    }
+    Ok(copied)
+}
@@ -0,0 +1,564 @@
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::DateTime;
+use http::{HeaderMap, HeaderName, HeaderValue};
+use lru::LruCache;
+use parking_lot::Mutex;
+use tokio::io::AsyncWrite;
+
+use crate::{body, hop_by_hop};
+
+/// Header telling the client whether its response came out of the cache: `HIT` if it did, `MISS`
+/// if it came from an upstream, or `REVALIDATED` if an upstream confirmed that the stored copy was
+/// still good.
+pub const STATUS_HEADER: &str = "x-cache";
+
+/// Statuses whose responses are cacheable without explicit freshness information being required
+/// (RFC 9110 section 15.1), minus the ones balancebeam doesn't produce bodies for
+const CACHEABLE_STATUSES: [u16; 7] = [200, 203, 300, 301, 308, 404, 410];
+
+/// What a request uniquely asks for, apart from the headers the response varies on
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    /// The pool the request was routed to. Routes can pick a pool by the request's headers, so
+    /// the same URI can lead to different upstreams.
+    pool: String,
+    method: http::Method,
+    /// Lowercased Host header (or authority of an absolute-form request target)
+    host: String,
+    uri: String,
+}
+
+/// A response kept in the cache
+#[derive(Debug, Clone)]
+struct Stored {
+    status: http::StatusCode,
+    version: http::Version,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    /// Values the request had for each of the headers named in the response's Vary header
+    vary: Vec<(HeaderName, Vec<HeaderValue>)>,
+    /// When the response was received (or last revalidated)
+    stored_at: Instant,
+    /// How old the response already was when we got it (its Age header)
+    initial_age: Duration,
+    /// How long after its Date the response stays fresh
+    freshness_lifetime: Duration,
+}
+
+impl Stored {
+    fn age(&self) -> Duration {
+        self.initial_age + self.stored_at.elapsed()
+    }
+
+    fn is_fresh(&self) -> bool {
+        self.age() < self.freshness_lifetime
+    }
+
+    fn has_validator(&self) -> bool {
+        self.headers.contains_key(http::header::ETAG)
+            || self.headers.contains_key(http::header::LAST_MODIFIED)
+    }
+
+    /// Returns true if this is the variant `request` asks for, going by the Vary header.
+    fn matches(&self, request: &http::Request<Vec<u8>>) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, values)| vary_values(request.headers(), name) == *values)
+    }
+
+    /// Roughly how many bytes of memory the response takes up
+    fn size(&self) -> usize {
+        let headers_size: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        self.body.len() + headers_size
+    }
+
+    /// Builds the response to send a client, with its Age and how it was served noted in the
+    /// headers.
+    fn to_response(&self, cache_status: &'static str) -> http::Response<Vec<u8>> {
+        let mut response = http::Response::builder()
+            .status(self.status)
+            .version(self.version)
+            .body(self.body.clone())
+            .unwrap();
+        *response.headers_mut() = self.headers.clone();
+        let headers = response.headers_mut();
+        headers.insert(http::header::AGE, HeaderValue::from(self.age().as_secs()));
+        headers.insert(STATUS_HEADER, HeaderValue::from_static(cache_status));
+        response
+    }
+}
+
+/// In-memory cache of upstream responses to GET and HEAD requests, shared by all connections.
+/// Responses are kept for as long as their Cache-Control or Expires headers allow, and stale ones
+/// with an ETag or Last-Modified header are revalidated with a conditional request. Once the
+/// responses take up more than `max_size` bytes, the least recently used ones are dropped.
+///
+/// Only responses whose body has a known length of at most `max_entry_size` bytes are stored, so
+/// that a response can be recorded as it is streamed to the client.
+pub struct ResponseCache {
+    /// Total size of the stored responses in bytes (0 = caching disabled). This and
+    /// max_entry_size can be changed by a configuration reload, so they are atomic.
+    max_size: AtomicUsize,
+    max_entry_size: AtomicUsize,
+    entries: Mutex<Entries>,
+}
+
+struct Entries {
+    /// The variants stored for each key, least recently used key first
+    variants: LruCache<Key, Vec<Stored>>,
+    /// Sum of the sizes of all stored variants
+    size: usize,
+}
+
+/// The result of looking a request up in the cache
+pub enum Lookup {
+    /// A fresh response to send the client instead of forwarding the request
+    Hit(http::Response<Vec<u8>>),
+    /// The request has to be forwarded to an upstream
+    Miss(Box<Pending>),
+}
+
+impl ResponseCache {
+    pub fn new(max_size: usize, max_entry_size: usize) -> ResponseCache {
+        ResponseCache {
+            max_size: AtomicUsize::new(max_size),
+            max_entry_size: AtomicUsize::new(max_entry_size),
+            entries: Mutex::new(Entries {
+                variants: LruCache::unbounded(),
+                size: 0,
+            }),
+        }
+    }
+
+    /// Changes the size limits (e.g. after the configuration is reloaded), dropping responses
+    /// until the cache fits.
+    pub fn set_limits(&self, max_size: usize, max_entry_size: usize) {
+        self.max_size.store(max_size, Ordering::Relaxed);
+        self.max_entry_size.store(max_entry_size, Ordering::Relaxed);
+        self.evict(&mut self.entries.lock());
+    }
+
+    /// Looks up the response to `request`, which was routed to `pool`. Returns None if the cache is
+    /// disabled or the request can't be answered from it (i.e. it isn't a GET or HEAD request);
+    /// requests with other methods drop whatever the pool has stored for their URI, since they are
+    /// likely to change it.
+    ///
+    /// If a stored response has gone stale but can be revalidated, the request is made conditional
+    /// so that the upstream can answer 304 (Not Modified) instead of sending the body again.
+    pub fn lookup(
+        self: &Arc<Self>,
+        request: &mut http::Request<Vec<u8>>,
+        pool: &str,
+    ) -> Option<Lookup> {
+        if self.max_size.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        let key = Key {
+            pool: pool.to_string(),
+            method: request.method().clone(),
+            host: request_host(request),
+            uri: request.uri().to_string(),
+        };
+        if request.method() != http::Method::GET && request.method() != http::Method::HEAD {
+            self.invalidate(&key);
+            return None;
+        }
+        let request_directives = cache_control(request.headers());
+        let no_cache = request_directives.iter().any(|(name, value)| {
+            name == "no-cache" || (name == "max-age" && value.as_deref() == Some("0"))
+        }) || request
+            .headers()
+            .get_all(http::header::PRAGMA)
+            .iter()
+            .any(|value| value.as_bytes().eq_ignore_ascii_case(b"no-cache"));
+        // A shared cache mustn't hand one user's authorized response to another, and we don't
+        // serve parts of responses
+        let storable = !request_directives
+            .iter()
+            .any(|(name, _)| name == "no-store")
+            && !request.headers().contains_key(http::header::AUTHORIZATION)
+            && !request.headers().contains_key(http::header::RANGE);
+        let mut pending = Pending {
+            cache: self.clone(),
+            key,
+            request_headers: request.headers().clone(),
+            storable,
+            stale: None,
+        };
+        if !storable {
+            return Some(Lookup::Miss(Box::new(pending)));
+        }
+
+        let stored = {
+            let mut entries = self.entries.lock();
+            entries
+                .variants
+                .get(&pending.key)
+                .and_then(|variants| variants.iter().find(|stored| stored.matches(request)))
+                .cloned()
+        };
+        let stored = match stored {
+            Some(stored) => stored,
+            None => return Some(Lookup::Miss(Box::new(pending))),
+        };
+        if stored.is_fresh() && !no_cache {
+            if etag_matches(request.headers(), &stored.headers) {
+                let mut not_modified = stored.to_response("HIT");
+                *not_modified.status_mut() = http::StatusCode::NOT_MODIFIED;
+                not_modified.body_mut().clear();
+                return Some(Lookup::Hit(not_modified));
+            }
+            return Some(Lookup::Hit(stored.to_response("HIT")));
+        }
+        // If the client has validators of its own, its request is passed on as is, and the
+        // upstream's answer to it goes back to the client
+        if stored.has_validator() && !is_conditional(request.headers()) {
+            let headers = request.headers_mut();
+            if let Some(etag) = stored.headers.get(http::header::ETAG) {
+                headers.insert(http::header::IF_NONE_MATCH, etag.clone());
+            }
+            if let Some(last_modified) = stored.headers.get(http::header::LAST_MODIFIED) {
+                headers.insert(http::header::IF_MODIFIED_SINCE, last_modified.clone());
+            }
+            pending.stale = Some(stored);
+        }
+        Some(Lookup::Miss(Box::new(pending)))
+    }
+
+    fn insert(&self, key: Key, stored: Stored) {
+        let mut entries = self.entries.lock();
+        let entries = &mut *entries;
+        let size = stored.size();
+        let variants = entries.variants.get_or_insert_mut(key, Vec::new);
+        if let Some(index) = variants
+            .iter()
+            .position(|variant| variant.vary == stored.vary)
+        {
+            entries.size -= variants.swap_remove(index).size();
+        }
+        variants.push(stored);
+        entries.size += size;
+        self.evict(entries);
+    }
+
+    /// Drops the responses to GET and HEAD requests for the pool and URI `key` is for.
+    fn invalidate(&self, key: &Key) {
+        let mut entries = self.entries.lock();
+        for method in [http::Method::GET, http::Method::HEAD] {
+            let key = Key {
+                method,
+                ..key.clone()
+            };
+            if let Some(variants) = entries.variants.pop(&key) {
+                entries.size -= variants.iter().map(Stored::size).sum::<usize>();
+            }
+        }
+    }
+
+    /// Drops the least recently used responses until the cache fits in max_size.
+    fn evict(&self, entries: &mut Entries) {
+        let max_size = self.max_size.load(Ordering::Relaxed);
+        while entries.size > max_size {
+            match entries.variants.pop_lru() {
+                Some((_, variants)) => {
+                    entries.size -= variants.iter().map(Stored::size).sum::<usize>()
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for ResponseCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let entries = self.entries.lock();
+        write!(
+            f,
+            "ResponseCache({} URIs, {} of {} bytes)",
+            entries.variants.len(),
+            entries.size,
+            self.max_size.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// A cacheable request that is being forwarded to an upstream
+pub struct Pending {
+    cache: Arc<ResponseCache>,
+    key: Key,
+    /// Headers of the request, to pick out the ones the response varies on
+    request_headers: HeaderMap,
+    /// Whether the request allows its response to be stored
+    storable: bool,
+    /// The stored response that is being revalidated, if any
+    stale: Option<Stored>,
+}
+
+impl Pending {
+    /// If `response` is the upstream confirming (with a 304) that the stale stored response is
+    /// still good, refreshes the stored response with its headers and returns the response to send
+    /// the client in its place.
+    pub fn revalidated(
+        &self,
+        response: &http::Response<Vec<u8>>,
+    ) -> Option<http::Response<Vec<u8>>> {
+        if response.status() != http::StatusCode::NOT_MODIFIED {
+            return None;
+        }
+        let mut stored = self.stale.clone()?;
+        for name in response.headers().keys() {
+            // The 304 describes the stored response, apart from how its body is framed
+            if name != http::header::CONTENT_LENGTH && name != http::header::TRANSFER_ENCODING {
+                stored.headers.remove(name);
+                for value in response.headers().get_all(name) {
+                    stored.headers.append(name, value.clone());
+                }
+            }
+        }
+        hop_by_hop::remove_hop_by_hop_headers(&mut stored.headers);
+        stored.stored_at = Instant::now();
+        stored.initial_age = initial_age(&stored.headers);
+        stored.freshness_lifetime = freshness_lifetime(&stored.headers).unwrap_or_default();
+        let revalidated = stored.to_response("REVALIDATED");
+        self.cache.insert(self.key.clone(), stored);
+        Some(revalidated)
+    }
+
+    /// Starts recording `response` (whose body is framed as `framing`) for the cache, if it may be
+    /// stored. The body is recorded through a Tee as it is sent to the client.
+    pub fn start_recording(
+        &self,
+        response: &http::Response<Vec<u8>>,
+        framing: body::Framing,
+    ) -> Option<Recording> {
+        let max_entry_size = self.cache.max_entry_size.load(Ordering::Relaxed);
+        let body_fits = match framing {
+            body::Framing::Empty => true,
+            body::Framing::Length(length) => length <= max_entry_size as u64,
+            // There's no telling how big these are until it's too late
+            body::Framing::Chunked | body::Framing::UntilClose => false,
+        };
+        if !self.storable
+            || !body_fits
+            || !CACHEABLE_STATUSES.contains(&response.status().as_u16())
+            // Cookies are meant for one client only
+            || response.headers().contains_key(http::header::SET_COOKIE)
+        {
+            return None;
+        }
+        let directives = cache_control(response.headers());
+        if directives
+            .iter()
+            .any(|(name, _)| name == "no-store" || name == "private")
+        {
+            return None;
+        }
+        let mut vary = Vec::new();
+        for value in response.headers().get_all(http::header::VARY) {
+            for name in value.to_str().ok()?.split(',') {
+                let name = name.trim();
+                if name == "*" {
+                    return None;
+                }
+                let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+                let values = vary_values(&self.request_headers, &name);
+                vary.push((name, values));
+            }
+        }
+        let mut headers = response.headers().clone();
+        hop_by_hop::remove_hop_by_hop_headers(&mut headers);
+        let freshness_lifetime = freshness_lifetime(&headers).unwrap_or_default();
+        let stored = Stored {
+            status: response.status(),
+            version: response.version(),
+            initial_age: initial_age(&headers),
+            headers,
+            body: Vec::new(),
+            vary,
+            stored_at: Instant::now(),
+            freshness_lifetime,
+        };
+        // Responses that are never fresh are only worth keeping if they can be revalidated
+        if freshness_lifetime.is_zero() && !stored.has_validator() {
+            return None;
+        }
+        Some(Recording {
+            stored,
+            max_entry_size,
+            too_large: false,
+        })
+    }
+
+    /// Stores a response once its whole body has been recorded.
+    pub fn finish(&self, recording: Recording) {
+        if recording.too_large {
+            return;
+        }
+        self.cache.insert(self.key.clone(), recording.stored);
+    }
+}
+
+/// A response that is being recorded for the cache as it is sent to the client
+pub struct Recording {
+    stored: Stored,
+    max_entry_size: usize,
+    /// Set if more body arrived than the Content-Length promised and the entry limit allows
+    too_large: bool,
+}
+
+impl Recording {
+    fn record(&mut self, data: &[u8]) {
+        if self.too_large {
+            return;
+        }
+        if self.stored.body.len() + data.len() > self.max_entry_size {
+            self.too_large = true;
+            self.stored.body = Vec::new();
+            return;
+        }
+        self.stored.body.extend_from_slice(data);
+    }
+}
+
+/// Passes writes through to a writer, recording what was written (if there is a recording).
+pub struct Tee<'a, W> {
+    writer: &'a mut W,
+    recording: Option<&'a mut Recording>,
+}
+
+impl<'a, W> Tee<'a, W> {
+    pub fn new(writer: &'a mut W, recording: Option<&'a mut Recording>) -> Tee<'a, W> {
+        Tee { writer, recording }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Tee<'_, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let written = Pin::new(&mut *this.writer).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(n)), Some(recording)) = (&written, this.recording.as_mut()) {
+            recording.record(&buf[..*n]);
+        }
+        written
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().writer).poll_shutdown(cx)
+    }
+}
+
+/// Returns the (lowercase) host a request is for, port and all.
+fn request_host(request: &http::Request<Vec<u8>>) -> String {
+    match request.uri().authority() {
+        Some(authority) => authority.as_str().to_ascii_lowercase(),
+        None => request
+            .headers()
+            .get(http::header::HOST)
+            .map(|host| String::from_utf8_lossy(host.as_bytes()).to_ascii_lowercase())
+            .unwrap_or_default(),
+    }
+}
+
+fn vary_values(headers: &HeaderMap, name: &HeaderName) -> Vec<HeaderValue> {
+    headers.get_all(name).iter().cloned().collect()
+}
+
+/// Parses the Cache-Control directives in `headers` into lowercase names and (unquoted) values.
+fn cache_control(headers: &HeaderMap) -> Vec<(String, Option<String>)> {
+    headers
+        .get_all(http::header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter(|directive| !directive.trim().is_empty())
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(value.trim().trim_matches('"').to_string()),
+            ),
+            None => (directive.trim().to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+/// Works out how long a response stays fresh from its Cache-Control (s-maxage, which is meant for
+/// shared caches like us, or else max-age) or Expires header. Returns None if it doesn't say.
+fn freshness_lifetime(headers: &HeaderMap) -> Option<Duration> {
+    let directives = cache_control(headers);
+    if directives.iter().any(|(name, _)| name == "no-cache") {
+        return Some(Duration::ZERO);
+    }
+    for wanted in ["s-maxage", "max-age"] {
+        if let Some((_, value)) = directives.iter().find(|(name, _)| name == wanted) {
+            let seconds = value.as_deref().and_then(|v| v.parse().ok()).unwrap_or(0);
+            return Some(Duration::from_secs(seconds));
+        }
+    }
+    let expires = headers.get(http::header::EXPIRES)?;
+    // An invalid date (e.g. "0") means the response has already expired
+    let expires = match http_date(expires) {
+        Some(expires) => expires,
+        None => return Some(Duration::ZERO),
+    };
+    let date = headers
+        .get(http::header::DATE)
+        .and_then(http_date)
+        .unwrap_or_else(SystemTime::now);
+    Some(expires.duration_since(date).unwrap_or_default())
+}
+
+fn initial_age(headers: &HeaderMap) -> Duration {
+    let age = headers
+        .get(http::header::AGE)
+        .and_then(|age| age.to_str().ok()?.trim().parse().ok())
+        .unwrap_or(0);
+    Duration::from_secs(age)
+}
+
+/// Parses an HTTP date (e.g. `Sun, 06 Nov 1994 08:49:37 GMT`).
+fn http_date(value: &HeaderValue) -> Option<SystemTime> {
+    let date = DateTime::parse_from_rfc2822(value.to_str().ok()?).ok()?;
+    Some(SystemTime::from(date))
+}
+
+/// Returns true if the request carries validators of its own.
+fn is_conditional(headers: &HeaderMap) -> bool {
+    headers.contains_key(http::header::IF_NONE_MATCH)
+        || headers.contains_key(http::header::IF_MODIFIED_SINCE)
+}
+
+/// Returns true if the request's If-None-Match header lists the stored response's ETag (compared
+/// weakly, as RFC 9110 section 13.1.2 says to).
+fn etag_matches(request_headers: &HeaderMap, stored_headers: &HeaderMap) -> bool {
+    let etag = match stored_headers.get(http::header::ETAG) {
+        Some(etag) => weak(etag.as_bytes()),
+        None => return false,
+    };
+    request_headers
+        .get_all(http::header::IF_NONE_MATCH)
+        .iter()
+        .flat_map(|value| value.as_bytes().split(|&b| b == b','))
+        .map(|tag| tag.trim_ascii())
+        .any(|tag| tag == b"*" || weak(tag) == etag)
+}
+
+fn weak(etag: &[u8]) -> &[u8] {
+    etag.strip_prefix(b"W/").unwrap_or(etag)
+}
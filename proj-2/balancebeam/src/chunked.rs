@@ -0,0 +1,230 @@
+use std::cmp::min;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Longest chunk-size or trailer line we are willing to buffer
+const MAX_LINE_SIZE: usize = 4096;
+/// Most trailer fields we accept after the last chunk
+const MAX_NUM_TRAILERS: usize = 32;
+/// Number of bytes to read from the stream at a time
+const READ_SIZE: usize = 8192;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A chunk-size line, chunk terminator or trailer field could not be parsed
+    Malformed,
+    /// The stream ended before the last chunk and trailers were received
+    Truncated,
+    /// Encountered an I/O error when reading from the stream
+    Io(std::io::Error),
+}
+
+/// Trailer fields that followed the last chunk of a chunked body. These are stored in the
+/// extensions of the http::Request or http::Response the body belongs to, so that they can be sent
+/// along when the message is forwarded.
+#[derive(Debug, Clone, Default)]
+pub struct Trailers(pub http::HeaderMap);
+
+/// Returns true if the message body is sent with the chunked transfer coding, i.e. if chunked is
+/// the final coding listed in Transfer-Encoding.
+pub fn is_chunked(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all(http::header::TRANSFER_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .last()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum State {
+    /// Expecting a chunk-size line
+    Size,
+    /// In the middle of a chunk's data
+    Data,
+    /// Expecting the CRLF that ends a chunk's data
+    DataEnd,
+    /// Read the last chunk and trailers
+    Done,
+}
+
+/// Decodes a chunked body from a stream, handing back the chunk data piece by piece.
+pub struct ChunkedReader<'a, S> {
+    stream: &'a mut S,
+    /// Bytes read from the stream that haven't been decoded yet
+    buffer: Vec<u8>,
+    /// Bytes of the current chunk's data that haven't been returned yet
+    remaining: usize,
+    state: State,
+    trailers: http::HeaderMap,
+}
+
+impl<'a, S: AsyncRead + Unpin> ChunkedReader<'a, S> {
+    /// Creates a reader for a chunked body on `stream`. `already_read` holds any bytes of the body
+    /// that were read from the stream along with the headers.
+    pub fn new(stream: &'a mut S, already_read: Vec<u8>) -> ChunkedReader<'a, S> {
+        ChunkedReader {
+            stream,
+            buffer: already_read,
+            remaining: 0,
+            state: State::Size,
+            trailers: http::HeaderMap::new(),
+        }
+    }
+
+    /// Returns the next piece of decoded body data, or Ok(None) once the last chunk (and any
+    /// trailers) have been read.
+    pub async fn next(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        loop {
+            match self.state {
+                State::Size => {
+                    let line = self.read_line().await?;
+                    let size = parse_chunk_size(&line)?;
+                    if size == 0 {
+                        self.read_trailers().await?;
+                        self.state = State::Done;
+                        return Ok(None);
+                    }
+                    self.remaining = size;
+                    self.state = State::Data;
+                }
+                State::Data => {
+                    if self.buffer.is_empty() {
+                        self.fill().await?;
+                    }
+                    let len = min(self.remaining, self.buffer.len());
+                    let data: Vec<u8> = self.buffer.drain(..len).collect();
+                    self.remaining -= len;
+                    if self.remaining == 0 {
+                        self.state = State::DataEnd;
+                    }
+                    return Ok(Some(data));
+                }
+                State::DataEnd => {
+                    if !self.read_line().await?.is_empty() {
+                        return Err(Error::Malformed);
+                    }
+                    self.state = State::Size;
+                }
+                State::Done => return Ok(None),
+            }
+        }
+    }
+
+    /// Takes the bytes that were read from the stream past the end of the body (once next() has
+    /// returned Ok(None)), which belong to the next message.
+    pub fn take_unread(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+
+    /// Trailer fields received after the last chunk. Only complete once next() has returned
+    /// Ok(None).
+    pub fn into_trailers(self) -> http::HeaderMap {
+        self.trailers
+    }
+
+    /// Reads more bytes from the stream into the buffer.
+    async fn fill(&mut self) -> Result<(), Error> {
+        let mut chunk = [0_u8; READ_SIZE];
+        let bytes_read = self.stream.read(&mut chunk).await.map_err(Error::Io)?;
+        if bytes_read == 0 {
+            return Err(Error::Truncated);
+        }
+        self.buffer.extend_from_slice(&chunk[..bytes_read]);
+        Ok(())
+    }
+
+    /// Reads a CRLF-terminated line, returning it without the CRLF.
+    async fn read_line(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|w| w == b"\r\n") {
+                let line = self.buffer[..end].to_vec();
+                self.buffer.drain(..end + 2);
+                return Ok(line);
+            }
+            if self.buffer.len() > MAX_LINE_SIZE {
+                return Err(Error::Malformed);
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Reads trailer fields up to and including the empty line that ends the chunked body.
+    async fn read_trailers(&mut self) -> Result<(), Error> {
+        loop {
+            let line = self.read_line().await?;
+            if line.is_empty() {
+                return Ok(());
+            }
+            if self.trailers.len() >= MAX_NUM_TRAILERS {
+                return Err(Error::Malformed);
+            }
+            let colon = line
+                .iter()
+                .position(|b| *b == b':')
+                .ok_or(Error::Malformed)?;
+            let name =
+                http::HeaderName::from_bytes(&line[..colon]).map_err(|_| Error::Malformed)?;
+            let value = http::HeaderValue::from_bytes(line[colon + 1..].trim_ascii())
+                .map_err(|_| Error::Malformed)?;
+            self.trailers.append(name, value);
+        }
+    }
+}
+
+/// Returns true if `data`, the beginning of a chunked body, already shows that the body is
+/// malformed. This lets a bad body be rejected before anything is forwarded.
+pub fn has_malformed_start(data: &[u8]) -> bool {
+    match data.windows(2).position(|window| window == b"\r\n") {
+        Some(line_end) => parse_chunk_size(&data[..line_end]).is_err(),
+        None => false,
+    }
+}
+
+/// Parses a chunk-size line (a hex number, optionally followed by ;chunk-extensions).
+fn parse_chunk_size(line: &[u8]) -> Result<usize, Error> {
+    let size = match line.iter().position(|b| *b == b';') {
+        Some(extensions_start) => &line[..extensions_start],
+        None => line,
+    }
+    .trim_ascii();
+    if size.is_empty() || !size.iter().all(u8::is_ascii_hexdigit) {
+        return Err(Error::Malformed);
+    }
+    let size = std::str::from_utf8(size).map_err(|_| Error::Malformed)?;
+    usize::from_str_radix(size, 16).map_err(|_| Error::Malformed)
+}
+
+/// Writes `data` to the stream as a single chunk. Empty data is skipped, since a zero-length chunk
+/// would mark the end of the body.
+pub async fn write_chunk<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    data: &[u8],
+) -> Result<(), std::io::Error> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    stream
+        .write_all(format!("{:x}\r\n", data.len()).as_bytes())
+        .await?;
+    stream.write_all(data).await?;
+    stream.write_all(b"\r\n").await?;
+    Ok(())
+}
+
+/// Writes the last (zero-length) chunk followed by `trailers`, ending a chunked body.
+pub async fn write_last_chunk<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    trailers: Option<&Trailers>,
+) -> Result<(), std::io::Error> {
+    stream.write_all(b"0\r\n").await?;
+    if let Some(Trailers(trailers)) = trailers {
+        for (name, value) in trailers {
+            stream.write_all(format!("{}: ", name).as_bytes()).await?;
+            stream.write_all(value.as_bytes()).await?;
+            stream.write_all(b"\r\n").await?;
+        }
+    }
+    stream.write_all(b"\r\n").await?;
+    Ok(())
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+/// The state of an upstream's circuit, as reported by the metrics and the admin API
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CircuitState {
+    /// Requests flow normally
+    Closed,
+    /// The upstream has failed too many times in a row and is not sent any requests until the
+    /// cool-down has passed
+    Open,
+    /// The cool-down has passed and a single trial request has been let through to find out
+    /// whether the upstream has recovered
+    HalfOpen,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed,
+    Open { since: Instant },
+    HalfOpen { trial_started: Instant },
+}
+
+#[derive(Debug)]
+struct Circuit {
+    state: State,
+    /// Failures since the last success
+    consecutive_failures: usize,
+}
+
+impl Default for Circuit {
+    fn default() -> Circuit {
+        Circuit {
+            state: State::Closed,
+            consecutive_failures: 0,
+        }
+    }
+}
+
+/// Passive health checking for the upstreams. Every request outcome is reported here: connection
+/// errors, failed exchanges (including timeouts) and 5xx responses count as failures, anything
+/// else as a success. Once an upstream has failed `failure_threshold` times in a row its circuit
+/// opens and it is passed over for `cool_down`, after which one trial request decides whether the
+/// circuit closes again or stays open for another cool-down. A single transient failure therefore
+/// doesn't take an upstream out of rotation.
+#[derive(Debug)]
+pub struct CircuitBreakers {
+    /// Consecutive failures that open a circuit (0 = never open). This can be changed by a
+    /// configuration reload, so it is atomic.
+    failure_threshold: AtomicUsize,
+    /// Seconds an open circuit waits before letting a trial request through
+    cool_down: AtomicU64,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(failure_threshold: usize, cool_down: usize) -> CircuitBreakers {
+        CircuitBreakers {
+            failure_threshold: AtomicUsize::new(failure_threshold),
+            cool_down: AtomicU64::new(cool_down as u64),
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_limits(&self, failure_threshold: usize, cool_down: usize) {
+        self.failure_threshold
+            .store(failure_threshold, Ordering::Relaxed);
+        self.cool_down.store(cool_down as u64, Ordering::Relaxed);
+    }
+
+    fn cool_down(&self) -> Duration {
+        Duration::from_secs(self.cool_down.load(Ordering::Relaxed))
+    }
+
+    /// Returns true if a request could be sent to `upstream` right now, without claiming the trial
+    /// request of a circuit that is ready to be half-opened.
+    pub fn is_available(&self, upstream: &str) -> bool {
+        let cool_down = self.cool_down();
+        match self.circuits.lock().get(upstream) {
+            None => true,
+            Some(circuit) => is_available(circuit, cool_down),
+        }
+    }
+
+    /// Claims a request to `upstream`. Returns false if its circuit is open, or half-open with the
+    /// trial request still in flight. A trial that never reports back (e.g. because the client hung
+    /// up) is given up on after another cool-down.
+    pub fn try_acquire(&self, upstream: &str) -> bool {
+        let cool_down = self.cool_down();
+        let mut circuits = self.circuits.lock();
+        let circuit = match circuits.get_mut(upstream) {
+            Some(circuit) => circuit,
+            None => return true,
+        };
+        if !is_available(circuit, cool_down) {
+            return false;
+        }
+        if !matches!(circuit.state, State::Closed) {
+            log::info!(
+                "Circuit for upstream {} is half-open, sending a trial request",
+                upstream
+            );
+            circuit.state = State::HalfOpen {
+                trial_started: Instant::now(),
+            };
+        }
+        true
+    }
+
+    pub fn record_success(&self, upstream: &str) {
+        let mut circuits = self.circuits.lock();
+        let circuit = match circuits.get_mut(upstream) {
+            Some(circuit) => circuit,
+            // Upstreams that have never failed don't need an entry
+            None => return,
+        };
+        if !matches!(circuit.state, State::Closed) {
+            log::info!("Circuit for upstream {} closed", upstream);
+        }
+        circuits.remove(upstream);
+    }
+
+    pub fn record_failure(&self, upstream: &str) {
+        let failure_threshold = self.failure_threshold.load(Ordering::Relaxed);
+        let mut circuits = self.circuits.lock();
+        let circuit = circuits.entry(upstream.to_string()).or_default();
+        circuit.consecutive_failures += 1;
+        let open = match circuit.state {
+            // The trial failed, so wait out another cool-down
+            State::HalfOpen { .. } => true,
+            State::Open { .. } => false,
+            State::Closed => {
+                failure_threshold > 0 && circuit.consecutive_failures >= failure_threshold
+            }
+        };
+        if open {
+            log::warn!(
+                "Circuit for upstream {} opened after {} consecutive failures",
+                upstream,
+                circuit.consecutive_failures
+            );
+            circuit.state = State::Open {
+                since: Instant::now(),
+            };
+        }
+    }
+
+    pub fn state(&self, upstream: &str) -> CircuitState {
+        let circuits = self.circuits.lock();
+        match circuits.get(upstream).map(|circuit| &circuit.state) {
+            None | Some(State::Closed) => CircuitState::Closed,
+            Some(State::Open { .. }) => CircuitState::Open,
+            Some(State::HalfOpen { .. }) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Forgets about the failures of `upstream`, closing its circuit.
+    pub fn reset(&self, upstream: &str) {
+        self.circuits.lock().remove(upstream);
+    }
+}
+
+fn is_available(circuit: &Circuit, cool_down: Duration) -> bool {
+    match circuit.state {
+        State::Closed => true,
+        State::Open { since } => since.elapsed() >= cool_down,
+        State::HalfOpen { trial_started } => trial_started.elapsed() >= cool_down,
+    }
+}
@@ -0,0 +1,359 @@
+use std::io::Write;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use http::{HeaderMap, HeaderValue};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::body;
+
+/// Brotli quality level (0-11). Higher levels compress better but are too slow to use on the fly.
+const BROTLI_QUALITY: u32 = 5;
+/// Base-2 logarithm of the brotli window size
+const BROTLI_WINDOW: u32 = 22;
+/// Size of the buffer the brotli encoder collects output in
+const BROTLI_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Content types that are worth compressing, besides text/* and the +json and +xml types.
+const COMPRESSIBLE_TYPES: [&str; 5] = [
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/wasm",
+    "image/svg+xml",
+];
+
+/// When responses are compressed on their way to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub enabled: bool,
+    /// Responses with a smaller body (going by Content-Length) are sent as they are, since
+    /// compressing them would gain little
+    pub min_size: u64,
+}
+
+/// The content codings responses can be compressed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+}
+
+impl Compression {
+    /// Decides whether a response (whose body is framed as `framing`) should be compressed on its
+    /// way to the client that sent `request`, and if so with which coding. Only uncompressed
+    /// responses of a compressible Content-Type whose body is (or may be, if its length isn't
+    /// known) at least min_size bytes are compressed.
+    pub fn choose(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        response: &http::Response<Vec<u8>>,
+        framing: body::Framing,
+    ) -> Option<Encoding> {
+        if !self.enabled {
+            return None;
+        }
+        let big_enough = match framing {
+            body::Framing::Empty => false,
+            body::Framing::Length(length) => length >= self.min_size,
+            body::Framing::Chunked | body::Framing::UntilClose => true,
+        };
+        let headers = response.headers();
+        let already_encoded = headers
+            .get(http::header::CONTENT_ENCODING)
+            .is_some_and(|coding| !coding.as_bytes().eq_ignore_ascii_case(b"identity"));
+        // no-transform asks intermediaries like us to leave the body alone
+        let no_transform = headers
+            .get_all(http::header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+        if !big_enough
+            || already_encoded
+            || no_transform
+            || response.status() == http::StatusCode::PARTIAL_CONTENT
+            || !is_compressible(headers)
+        {
+            return None;
+        }
+        negotiate(request.headers())
+    }
+}
+
+/// Returns true if the response's Content-Type is one that compresses well.
+fn is_compressible(headers: &HeaderMap) -> bool {
+    let content_type = match headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(content_type) => content_type,
+        None => return false,
+    };
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    // Event streams have to reach the client event by event, which compression would hold up
+    (media_type.starts_with("text/") && media_type != "text/event-stream")
+        || media_type.ends_with("+json")
+        || media_type.ends_with("+xml")
+        || COMPRESSIBLE_TYPES.contains(&media_type.as_str())
+}
+
+/// Picks the coding the client prefers out of its Accept-Encoding header (preferring brotli if it
+/// likes both equally). Returns None if it accepts neither.
+fn negotiate(request_headers: &HeaderMap) -> Option<Encoding> {
+    let mut gzip = None;
+    let mut brotli = None;
+    let mut any = None;
+    for value in request_headers.get_all(http::header::ACCEPT_ENCODING) {
+        for item in value.to_str().ok()?.split(',') {
+            let mut params = item.split(';');
+            let coding = params
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            match coding.as_str() {
+                "gzip" | "x-gzip" => gzip = Some(quality),
+                "br" => brotli = Some(quality),
+                "*" => any = Some(quality),
+                _ => {}
+            }
+        }
+    }
+    let gzip = gzip.or(any).unwrap_or(0.0);
+    let brotli = brotli.or(any).unwrap_or(0.0);
+    if brotli > 0.0 && brotli >= gzip {
+        Some(Encoding::Brotli)
+    } else if gzip > 0.0 {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+/// Updates the headers of a response whose body is about to be compressed with `encoding`: the
+/// Content-Length no longer applies, Content-Encoding says how the body is compressed, and Vary
+/// tells caches that the body depends on the request's Accept-Encoding. A strong ETag is weakened,
+/// since the compressed body isn't byte-for-byte what it stands for.
+pub fn prepare_headers(headers: &mut HeaderMap, encoding: Encoding) {
+    headers.remove(http::header::CONTENT_LENGTH);
+    headers.insert(
+        http::header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.name()),
+    );
+    let already_varies = headers
+        .get_all(http::header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| name.trim() == "*" || name.trim().eq_ignore_ascii_case("accept-encoding"));
+    if !already_varies {
+        headers.append(
+            http::header::VARY,
+            HeaderValue::from_static("Accept-Encoding"),
+        );
+    }
+    if let Some(etag) = headers.get(http::header::ETAG) {
+        if !etag.as_bytes().starts_with(b"W/") {
+            let weak = [b"W/", etag.as_bytes()].concat();
+            headers.insert(http::header::ETAG, HeaderValue::from_bytes(&weak).unwrap());
+        }
+    }
+}
+
+/// Compresses the body of a response held in memory (e.g. one sent out of the cache) with
+/// `encoding`, updating its headers to match.
+pub fn compress_response(response: &mut http::Response<Vec<u8>>, encoding: Encoding) {
+    let mut encoder = Encoder::new(encoding);
+    let compressed = encoder
+        .write(response.body())
+        .and_then(|()| encoder.finish());
+    let compressed = match compressed {
+        Ok(compressed) => compressed,
+        Err(error) => {
+            // Writing to memory can't really fail, but if it does the response goes out as is
+            log::warn!("Could not compress response: {}", error);
+            return;
+        }
+    };
+    prepare_headers(response.headers_mut(), encoding);
+    response.headers_mut().insert(
+        http::header::CONTENT_LENGTH,
+        HeaderValue::from(compressed.len()),
+    );
+    *response.body_mut() = compressed;
+}
+
+/// Compresses data into memory
+enum Encoder {
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Encoder {
+        match encoding {
+            Encoding::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::default(),
+            )),
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write_all(data),
+            Encoder::Brotli(encoder) => encoder.write_all(data),
+        }
+    }
+
+    /// Takes whatever compressed output the encoder has produced so far.
+    fn take_output(&mut self) -> Vec<u8> {
+        match self {
+            Encoder::Gzip(encoder) => std::mem::take(encoder.get_mut()),
+            Encoder::Brotli(encoder) => std::mem::take(encoder.get_mut()),
+        }
+    }
+
+    /// Ends the compressed stream, returning the output that hasn't been taken yet.
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+        }
+    }
+}
+
+/// Compresses what is written to it on its way to `writer`, as chunks if `chunked_output` is set.
+/// The compressed stream has to be ended with finish() once the whole body has been written.
+pub struct Compressor<W> {
+    writer: W,
+    /// Taken out by finish()
+    encoder: Option<Encoder>,
+    chunked_output: bool,
+    /// Compressed (and framed) output that has yet to be written to `writer`
+    pending: Vec<u8>,
+    /// How much of `pending` has been written
+    written: usize,
+    /// Compressed body bytes produced so far
+    bytes_out: u64,
+}
+
+impl<W: AsyncWrite + Unpin> Compressor<W> {
+    pub fn new(writer: W, encoding: Encoding, chunked_output: bool) -> Compressor<W> {
+        Compressor {
+            writer,
+            encoder: Some(Encoder::new(encoding)),
+            chunked_output,
+            pending: Vec::new(),
+            written: 0,
+            bytes_out: 0,
+        }
+    }
+
+    /// Ends the compressed stream (and the chunked body, if it is chunked) and flushes it out.
+    /// Returns the number of compressed body bytes written.
+    pub async fn finish(mut self) -> std::io::Result<u64> {
+        let rest = match self.encoder.take() {
+            Some(encoder) => encoder.finish()?,
+            None => Vec::new(),
+        };
+        self.queue(&rest);
+        if self.chunked_output {
+            self.pending.extend_from_slice(b"0\r\n\r\n");
+        }
+        self.writer.write_all(&self.pending[self.written..]).await?;
+        self.writer.flush().await?;
+        Ok(self.bytes_out)
+    }
+
+    /// Adds compressed output to what is waiting to be written.
+    fn queue(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.bytes_out += data.len() as u64;
+        if self.chunked_output {
+            self.pending
+                .extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+            self.pending.extend_from_slice(data);
+            self.pending.extend_from_slice(b"\r\n");
+        } else {
+            self.pending.extend_from_slice(data);
+        }
+    }
+
+    /// Writes out the pending output.
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.written < self.pending.len() {
+            match Pin::new(&mut self.writer).poll_write(cx, &self.pending[self.written..]) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()))
+                }
+                Poll::Ready(Ok(n)) => self.written += n,
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Compressor<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        // Don't take on more data until the previous output is out, so that a slow client slows
+        // down the upstream instead of making us buffer
+        ready!(this.poll_write_pending(cx))?;
+        let encoder = match this.encoder.as_mut() {
+            Some(encoder) => encoder,
+            None => return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into())),
+        };
+        encoder.write(buf)?;
+        let output = encoder.take_output();
+        this.queue(&output);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.writer).poll_shutdown(cx)
+    }
+}
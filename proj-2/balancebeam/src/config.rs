@@ -0,0 +1,713 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::RwLock;
+
+use crate::access_log::AccessLogFormat;
+use crate::compression::Compression;
+use crate::forwarded::{self, Forwarding};
+use crate::routing::{PoolConfig, Route, DEFAULT_POOL};
+use crate::strategy::StrategyKind;
+use crate::timeout::Timeouts;
+use crate::tls::{self, SniCertificate, UpstreamTls};
+use crate::{CmdOptions, ProxyState};
+
+/// The complete configuration of balancebeam, built from the command line and (optionally) a
+/// configuration file given with --config.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind: String,
+    /// Where the admin endpoints are served, if anywhere
+    pub admin_bind: Option<String>,
+    /// Where HTTPS connections are accepted, if anywhere
+    pub tls_bind: Option<String>,
+    /// Certificate and private key presented to HTTPS clients by default
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Certificates presented to HTTPS clients that ask for a particular host through SNI
+    pub tls_sni_certs: Vec<SniCertificate>,
+    /// TLS settings built from the above when tls_bind is set (filled in by load())
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+    /// Upstream addresses of the default pool. Ones starting with `https://` are connected to over
+    /// TLS.
+    pub upstreams: Vec<String>,
+    /// How to connect to `https://` upstreams
+    pub upstream_tls: UpstreamTls,
+    /// TLS settings for upstream connections built from the above (filled in by load())
+    pub upstream_client_config: Option<Arc<rustls::ClientConfig>>,
+    /// Weights for the weighted strategy. Upstreams that aren't listed have a weight of 1.
+    pub weights: HashMap<String, u32>,
+    pub strategy: StrategyKind,
+    pub active_health_check_interval: usize,
+    pub active_health_check_path: String,
+    pub active_health_check_timeout: usize,
+    pub max_requests_per_minute: usize,
+    pub max_retries: usize,
+    pub shutdown_grace_period: usize,
+    pub pool_max_idle: usize,
+    pub pool_idle_timeout: usize,
+    pub circuit_breaker_threshold: usize,
+    pub circuit_breaker_cool_down: usize,
+    pub timeouts: Timeouts,
+    /// Where a line about every request is written, if anywhere (changing it requires a restart)
+    pub access_log: Option<PathBuf>,
+    pub access_log_format: AccessLogFormat,
+    /// Size in bytes past which the access log is rotated (0 = never)
+    pub access_log_max_size: u64,
+    /// How many rotated access log files are kept
+    pub access_log_max_files: usize,
+    /// Which proxies in front of us are believed about where requests came from
+    pub forwarding: Forwarding,
+    /// Named pools besides the default one (which is made up of the settings above)
+    pub pools: Vec<PoolConfig>,
+    /// Rules picking the pool each request goes to, tried in order
+    pub routes: Vec<Route>,
+    /// Memory in bytes the response cache may take up (0 = no caching)
+    pub cache_max_size: usize,
+    /// Size in bytes of the largest response body that is cached
+    pub cache_max_entry_size: usize,
+    /// When responses are compressed for clients that accept it
+    pub compression: Compression,
+}
+
+/// The contents of a configuration file. Every setting is optional; anything left out falls back
+/// to the value given on the command line (or its default).
+///
+/// An example TOML configuration file:
+///
+/// ```toml
+/// bind = "0.0.0.0:1100"
+/// admin_bind = "127.0.0.1:9100"
+/// strategy = "weighted"
+/// max_requests_per_minute = 100
+/// max_retries = 2
+/// shutdown_grace_period = 30
+///
+/// [tls]
+/// bind = "0.0.0.0:1443"
+/// cert = "/etc/balancebeam/default.pem"
+/// key = "/etc/balancebeam/default.key"
+///
+/// [[tls.sni]]
+/// host = "api.example.com"
+/// cert = "/etc/balancebeam/api.pem"
+/// key = "/etc/balancebeam/api.key"
+///
+/// [upstream_tls]
+/// ca = "/etc/balancebeam/internal-ca.pem"
+/// client_cert = "/etc/balancebeam/client.pem"
+/// client_key = "/etc/balancebeam/client.key"
+///
+/// [health_check]
+/// interval = 10
+/// path = "/health"
+/// timeout = 5
+///
+/// [connection_pool]
+/// max_idle = 16
+/// idle_timeout = 30
+///
+/// [circuit_breaker]
+/// failure_threshold = 5
+/// cool_down = 30
+///
+/// [timeouts]
+/// client_header = 10
+/// client_body = 30
+/// upstream_connect = 5
+/// upstream_response = 60
+/// keep_alive_idle = 60
+///
+/// [access_log]
+/// path = "/var/log/balancebeam/access.log"
+/// format = "json"
+/// max_size = 104857600
+/// max_files = 5
+///
+/// [forwarding]
+/// trusted_proxies = ["10.0.0.0/8", "192.168.1.10"]
+/// strip_untrusted = true
+///
+/// [cache]
+/// max_size = 67108864
+/// max_entry_size = 1048576
+///
+/// [compression]
+/// enabled = true
+/// min_size = 1024
+///
+/// [[upstreams]]
+/// address = "10.0.0.1:8080"
+/// weight = 3
+///
+/// [[upstreams]]
+/// address = "https://10.0.0.2:8443"
+///
+/// # Requests for api.example.com whose path starts with /v2 go to the "api" pool; everything
+/// # else goes to the default pool made up of the upstreams above
+/// [[routes]]
+/// pool = "api"
+/// host = "api.example.com"
+/// path_prefix = "/v2"
+/// methods = ["GET", "POST"]
+/// headers = { "X-Api-Version" = "2" }
+/// # /v2/users reaches the api pool as /users
+/// strip_path_prefix = true
+///
+/// # Regex replacements on the path and query, applied in order after strip_path_prefix
+/// [[routes.rewrites]]
+/// pattern = "^/users/([0-9]+)"
+/// replacement = "/users?id=$1"
+///
+/// # Header rules are applied in order. Values can use {client_ip}, {upstream} and {request_id}.
+/// [[routes.request_headers]]
+/// action = "set"
+/// name = "X-Request-Id"
+/// value = "{request_id}"
+///
+/// [[routes.response_headers]]
+/// action = "remove"
+/// name = "Server"
+///
+/// [[routes.response_headers]]
+/// action = "set"
+/// name = "Strict-Transport-Security"
+/// value = "max-age=31536000"
+///
+/// [[routes.response_headers]]
+/// action = "rename"
+/// name = "X-Powered-By"
+/// to = "X-Backend"
+///
+/// # Pools fall back to the top-level settings for anything they leave out
+/// [[pools]]
+/// name = "api"
+/// strategy = "least-connections"
+/// health_check_path = "/healthz"
+/// max_requests_per_minute = 600
+///
+/// [[pools.upstreams]]
+/// address = "10.0.1.1:8080"
+/// ```
+///
+/// YAML files use the same structure.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    bind: Option<String>,
+    admin_bind: Option<String>,
+    strategy: Option<StrategyKind>,
+    max_requests_per_minute: Option<usize>,
+    max_retries: Option<usize>,
+    shutdown_grace_period: Option<usize>,
+    tls: Option<TlsSection>,
+    upstream_tls: Option<UpstreamTlsSection>,
+    health_check: Option<HealthCheckSection>,
+    connection_pool: Option<ConnectionPoolSection>,
+    circuit_breaker: Option<CircuitBreakerSection>,
+    timeouts: Option<TimeoutsSection>,
+    access_log: Option<AccessLogSection>,
+    forwarding: Option<ForwardingSection>,
+    cache: Option<CacheSection>,
+    compression: Option<CompressionSection>,
+    upstreams: Option<Vec<UpstreamEntry>>,
+    pools: Option<Vec<PoolEntry>>,
+    routes: Option<Vec<Route>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsSection {
+    bind: Option<String>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    /// Replaces any --tls-sni-cert options
+    sni: Option<Vec<SniCertificate>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamTlsSection {
+    ca: Option<PathBuf>,
+    insecure_skip_verify: Option<bool>,
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HealthCheckSection {
+    /// Seconds between active health checks (0 = disabled)
+    interval: Option<usize>,
+    path: Option<String>,
+    /// Seconds an upstream has to answer a health check
+    timeout: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConnectionPoolSection {
+    /// Idle keep-alive connections kept per upstream (0 = no pooling)
+    max_idle: Option<usize>,
+    /// Seconds an idle connection is kept before it is closed
+    idle_timeout: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CircuitBreakerSection {
+    /// Consecutive failed requests that take an upstream out of rotation (0 = never)
+    failure_threshold: Option<usize>,
+    /// Seconds before an upstream that was taken out of rotation is tried again
+    cool_down: Option<usize>,
+}
+
+/// Seconds allowed for each phase of a request (0 = no limit)
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TimeoutsSection {
+    client_header: Option<usize>,
+    client_body: Option<usize>,
+    upstream_connect: Option<usize>,
+    upstream_response: Option<usize>,
+    keep_alive_idle: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccessLogSection {
+    path: Option<PathBuf>,
+    format: Option<AccessLogFormat>,
+    /// Size in bytes past which the file is rotated (0 = never)
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ForwardingSection {
+    /// Addresses or CIDR ranges (replaces any --trusted-proxy options)
+    #[serde(default, deserialize_with = "forwarded::deserialize_trusted_proxies")]
+    trusted_proxies: Option<Vec<ipnet::IpNet>>,
+    strip_untrusted: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CacheSection {
+    /// Bytes of memory the cached responses may take up (0 = no caching)
+    max_size: Option<usize>,
+    /// Bytes in the largest response body that is cached
+    max_entry_size: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CompressionSection {
+    enabled: Option<bool>,
+    /// Bytes in the smallest response body that is compressed
+    min_size: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamEntry {
+    address: String,
+    weight: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PoolEntry {
+    name: String,
+    upstreams: Vec<UpstreamEntry>,
+    strategy: Option<StrategyKind>,
+    health_check_path: Option<String>,
+    max_requests_per_minute: Option<usize>,
+}
+
+impl Config {
+    /// Builds the configuration purely from command-line options.
+    pub fn from_options(options: &CmdOptions) -> Config {
+        Config {
+            bind: options.bind.clone(),
+            admin_bind: options.admin_bind.clone(),
+            tls_bind: options.tls_bind.clone(),
+            tls_cert: options.tls_cert.clone(),
+            tls_key: options.tls_key.clone(),
+            tls_sni_certs: options.tls_sni_cert.clone(),
+            tls: None,
+            upstreams: options.upstream.clone(),
+            upstream_tls: UpstreamTls {
+                ca: options.upstream_ca.clone(),
+                insecure_skip_verify: options.upstream_insecure_skip_verify,
+                client_cert: options.upstream_client_cert.clone(),
+                client_key: options.upstream_client_key.clone(),
+            },
+            upstream_client_config: None,
+            weights: options.upstream_weight.iter().cloned().collect(),
+            strategy: options.strategy,
+            active_health_check_interval: options.active_health_check_interval,
+            active_health_check_path: options.active_health_check_path.clone(),
+            active_health_check_timeout: options.active_health_check_timeout,
+            max_requests_per_minute: options.max_requests_per_minute,
+            max_retries: options.max_retries,
+            shutdown_grace_period: options.shutdown_grace_period,
+            pool_max_idle: options.pool_max_idle,
+            pool_idle_timeout: options.pool_idle_timeout,
+            circuit_breaker_threshold: options.circuit_breaker_threshold,
+            circuit_breaker_cool_down: options.circuit_breaker_cool_down,
+            timeouts: Timeouts {
+                client_header: options.client_header_timeout,
+                client_body: options.client_body_timeout,
+                upstream_connect: options.upstream_connect_timeout,
+                upstream_response: options.upstream_response_timeout,
+                keep_alive_idle: options.keep_alive_timeout,
+            },
+            access_log: options.access_log.clone(),
+            access_log_format: options.access_log_format,
+            access_log_max_size: options.access_log_max_size,
+            access_log_max_files: options.access_log_max_files,
+            forwarding: Forwarding {
+                trusted_proxies: options.trusted_proxy.clone(),
+                strip_untrusted: options.strip_untrusted_forwarded_headers,
+            },
+            pools: Vec::new(),
+            routes: Vec::new(),
+            cache_max_size: options.cache_max_size,
+            cache_max_entry_size: options.cache_max_entry_size,
+            compression: Compression {
+                enabled: options.compress,
+                min_size: options.compress_min_size,
+            },
+        }
+    }
+
+    /// Returns the settings of every pool, starting with the default one.
+    pub fn pool_configs(&self) -> Vec<PoolConfig> {
+        let default_pool = PoolConfig {
+            name: DEFAULT_POOL.to_string(),
+            upstreams: self.upstreams.clone(),
+            weights: self.weights.clone(),
+            strategy: self.strategy,
+            health_check_path: self.active_health_check_path.clone(),
+            max_requests_per_minute: self.max_requests_per_minute,
+        };
+        std::iter::once(default_pool)
+            .chain(self.pools.iter().cloned())
+            .collect()
+    }
+
+    /// Builds the configuration from the command-line options, overridden by whatever the
+    /// configuration file given with --config (if any) specifies.
+    pub fn load(options: &CmdOptions) -> Result<Config, String> {
+        let mut config = Config::from_options(options);
+        if let Some(path) = &options.config {
+            config.apply_file(read_config_file(path)?);
+        }
+        if config.upstreams.is_empty() && config.pools.iter().all(|p| p.upstreams.is_empty()) {
+            return Err(
+                "At least one upstream server must be specified using the --upstream \
+                option or in the configuration file."
+                    .to_string(),
+            );
+        }
+        config.check_routing()?;
+        if config.tls_bind.is_some() {
+            let default_cert = match (&config.tls_cert, &config.tls_key) {
+                (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+                (None, None) => None,
+                _ => return Err("--tls-cert and --tls-key must be given together".to_string()),
+            };
+            config.tls = Some(tls::server_config(default_cert, &config.tls_sni_certs)?);
+        }
+        config.upstream_client_config = Some(tls::client_config(&config.upstream_tls)?);
+        Ok(config)
+    }
+
+    /// Makes sure pool names are unique and that every route leads to a pool.
+    fn check_routing(&self) -> Result<(), String> {
+        let mut names = vec![DEFAULT_POOL];
+        for pool in &self.pools {
+            if names.contains(&pool.name.as_str()) {
+                return Err(format!("There is more than one pool named {:?}", pool.name));
+            }
+            names.push(&pool.name);
+        }
+        for route in &self.routes {
+            if !names.contains(&route.pool.as_str()) {
+                return Err(format!("A route leads to unknown pool {:?}", route.pool));
+            }
+            if let Some(name) = route
+                .headers
+                .keys()
+                .find(|name| http::HeaderName::from_bytes(name.as_bytes()).is_err())
+            {
+                return Err(format!(
+                    "A route to {:?} has invalid header name {:?}",
+                    route.pool, name
+                ));
+            }
+            if route.strip_path_prefix && route.path_prefix.is_none() {
+                return Err(format!(
+                    "A route to {:?} strips a path prefix but doesn't have one",
+                    route.pool
+                ));
+            }
+            for rule in route.request_headers.iter().chain(&route.response_headers) {
+                rule.check().map_err(|error| {
+                    format!(
+                        "A route to {:?} has an invalid header rule: {}",
+                        route.pool, error
+                    )
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_file(&mut self, file: ConfigFile) {
+        if let Some(bind) = file.bind {
+            self.bind = bind;
+        }
+        if file.admin_bind.is_some() {
+            self.admin_bind = file.admin_bind;
+        }
+        if let Some(tls) = file.tls {
+            if tls.bind.is_some() {
+                self.tls_bind = tls.bind;
+            }
+            if tls.cert.is_some() {
+                self.tls_cert = tls.cert;
+            }
+            if tls.key.is_some() {
+                self.tls_key = tls.key;
+            }
+            if let Some(sni) = tls.sni {
+                self.tls_sni_certs = sni;
+            }
+        }
+        if let Some(upstream_tls) = file.upstream_tls {
+            if upstream_tls.ca.is_some() {
+                self.upstream_tls.ca = upstream_tls.ca;
+            }
+            if let Some(insecure_skip_verify) = upstream_tls.insecure_skip_verify {
+                self.upstream_tls.insecure_skip_verify = insecure_skip_verify;
+            }
+            if upstream_tls.client_cert.is_some() {
+                self.upstream_tls.client_cert = upstream_tls.client_cert;
+            }
+            if upstream_tls.client_key.is_some() {
+                self.upstream_tls.client_key = upstream_tls.client_key;
+            }
+        }
+        if let Some(strategy) = file.strategy {
+            self.strategy = strategy;
+        }
+        if let Some(max_requests_per_minute) = file.max_requests_per_minute {
+            self.max_requests_per_minute = max_requests_per_minute;
+        }
+        if let Some(max_retries) = file.max_retries {
+            self.max_retries = max_retries;
+        }
+        if let Some(shutdown_grace_period) = file.shutdown_grace_period {
+            self.shutdown_grace_period = shutdown_grace_period;
+        }
+        if let Some(health_check) = file.health_check {
+            if let Some(interval) = health_check.interval {
+                self.active_health_check_interval = interval;
+            }
+            if let Some(path) = health_check.path {
+                self.active_health_check_path = path;
+            }
+            if let Some(timeout) = health_check.timeout {
+                self.active_health_check_timeout = timeout;
+            }
+        }
+        if let Some(connection_pool) = file.connection_pool {
+            if let Some(max_idle) = connection_pool.max_idle {
+                self.pool_max_idle = max_idle;
+            }
+            if let Some(idle_timeout) = connection_pool.idle_timeout {
+                self.pool_idle_timeout = idle_timeout;
+            }
+        }
+        if let Some(circuit_breaker) = file.circuit_breaker {
+            if let Some(failure_threshold) = circuit_breaker.failure_threshold {
+                self.circuit_breaker_threshold = failure_threshold;
+            }
+            if let Some(cool_down) = circuit_breaker.cool_down {
+                self.circuit_breaker_cool_down = cool_down;
+            }
+        }
+        if let Some(timeouts) = file.timeouts {
+            if let Some(client_header) = timeouts.client_header {
+                self.timeouts.client_header = client_header;
+            }
+            if let Some(client_body) = timeouts.client_body {
+                self.timeouts.client_body = client_body;
+            }
+            if let Some(upstream_connect) = timeouts.upstream_connect {
+                self.timeouts.upstream_connect = upstream_connect;
+            }
+            if let Some(upstream_response) = timeouts.upstream_response {
+                self.timeouts.upstream_response = upstream_response;
+            }
+            if let Some(keep_alive_idle) = timeouts.keep_alive_idle {
+                self.timeouts.keep_alive_idle = keep_alive_idle;
+            }
+        }
+        if let Some(access_log) = file.access_log {
+            if access_log.path.is_some() {
+                self.access_log = access_log.path;
+            }
+            if let Some(format) = access_log.format {
+                self.access_log_format = format;
+            }
+            if let Some(max_size) = access_log.max_size {
+                self.access_log_max_size = max_size;
+            }
+            if let Some(max_files) = access_log.max_files {
+                self.access_log_max_files = max_files;
+            }
+        }
+        if let Some(forwarding) = file.forwarding {
+            if let Some(trusted_proxies) = forwarding.trusted_proxies {
+                self.forwarding.trusted_proxies = trusted_proxies;
+            }
+            if let Some(strip_untrusted) = forwarding.strip_untrusted {
+                self.forwarding.strip_untrusted = strip_untrusted;
+            }
+        }
+        if let Some(cache) = file.cache {
+            if let Some(max_size) = cache.max_size {
+                self.cache_max_size = max_size;
+            }
+            if let Some(max_entry_size) = cache.max_entry_size {
+                self.cache_max_entry_size = max_entry_size;
+            }
+        }
+        if let Some(compression) = file.compression {
+            if let Some(enabled) = compression.enabled {
+                self.compression.enabled = enabled;
+            }
+            if let Some(min_size) = compression.min_size {
+                self.compression.min_size = min_size;
+            }
+        }
+        if let Some(upstreams) = file.upstreams {
+            (self.upstreams, self.weights) = addresses_and_weights(upstreams);
+        }
+        // Pools fall back to the top-level settings, so these have to come last
+        if let Some(pools) = file.pools {
+            self.pools = pools
+                .into_iter()
+                .map(|pool| {
+                    let (upstreams, weights) = addresses_and_weights(pool.upstreams);
+                    PoolConfig {
+                        name: pool.name,
+                        upstreams,
+                        weights,
+                        strategy: pool.strategy.unwrap_or(self.strategy),
+                        health_check_path: pool
+                            .health_check_path
+                            .unwrap_or_else(|| self.active_health_check_path.clone()),
+                        max_requests_per_minute: pool
+                            .max_requests_per_minute
+                            .unwrap_or(self.max_requests_per_minute),
+                    }
+                })
+                .collect();
+        }
+        if let Some(routes) = file.routes {
+            self.routes = routes;
+        }
+    }
+}
+
+/// Splits upstream entries into their addresses and the weights of the ones that have one.
+fn addresses_and_weights(upstreams: Vec<UpstreamEntry>) -> (Vec<String>, HashMap<String, u32>) {
+    let addresses = upstreams.iter().map(|u| u.address.clone()).collect();
+    let weights = upstreams
+        .into_iter()
+        .filter_map(|u| Some((u.address, u.weight?)))
+        .collect();
+    (addresses, weights)
+}
+
+/// Reads and parses a configuration file. The format (TOML or YAML) is picked based on the file
+/// extension.
+fn read_config_file(path: &Path) -> Result<ConfigFile, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("Could not read config file {}: {}", path.display(), err))?;
+    let parsed = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&contents).map_err(|e| e.to_string()),
+        Some("toml") => toml::from_str(&contents).map_err(|e| e.to_string()),
+        _ => {
+            return Err(format!(
+                "Config file {} must have a .toml, .yaml or .yml extension",
+                path.display()
+            ))
+        }
+    };
+    parsed.map_err(|err| format!("Could not parse config file {}: {}", path.display(), err))
+}
+
+/// Spawns a task that re-reads the configuration file whenever balancebeam receives SIGHUP and
+/// swaps the new settings into the shared state. Requests that are already in flight finish on the
+/// upstream they were sent to, so nothing is dropped; the next request on every connection is
+/// balanced according to the new settings.
+pub fn spawn_reload_on_sighup(options: CmdOptions, state: Arc<RwLock<ProxyState>>) {
+    let path: PathBuf = match &options.config {
+        Some(path) => path.clone(),
+        None => return,
+    };
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            log::error!(
+                "Could not listen for SIGHUP, config reloading is disabled: {}",
+                err
+            );
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            log::info!("Received SIGHUP, reloading {}", path.display());
+            let config = match Config::load(&options) {
+                Ok(config) => config,
+                Err(err) => {
+                    log::error!("{}. Keeping the previous configuration.", err);
+                    continue;
+                }
+            };
+            let mut state_write = state.write().await;
+            if config.bind != state_write.bind {
+                log::warn!(
+                    "Changing the bind address from {} to {} requires a restart",
+                    state_write.bind,
+                    config.bind
+                );
+            }
+            if config.tls_bind != state_write.tls_bind {
+                log::warn!("Changing the TLS bind address requires a restart");
+            }
+            let access_log_path = state_write.access_log.as_ref().map(|log| log.path());
+            if config.access_log.as_deref() != access_log_path {
+                log::warn!("Changing the access log file requires a restart");
+            }
+            state_write.apply_config(&config);
+            drop(state_write);
+            let pools: Vec<String> = config
+                .pool_configs()
+                .iter()
+                .map(|pool| format!("{} ({} upstreams)", pool.name, pool.upstreams.len()))
+                .collect();
+            log::info!("Reloaded configuration with pools {}", pools.join(", "));
+        }
+    });
+}
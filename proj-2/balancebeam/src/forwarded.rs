@@ -0,0 +1,191 @@
+use std::net::{IpAddr, SocketAddr};
+
+use ipnet::IpNet;
+
+use crate::request;
+
+/// Headers through which proxies tell the next hop about the original request
+const FORWARDING_HEADERS: [&str; 4] = [
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-forwarded-host",
+];
+
+/// Decides which forwarding headers (X-Forwarded-For, X-Forwarded-Proto, X-Forwarded-Host and
+/// RFC 7239 Forwarded) sent to us can be believed, and adds our own before requests are passed on.
+///
+/// Only peers in `trusted_proxies` (e.g. a CDN or another load balancer in front of us) are
+/// believed about where a request came from. Anyone else could be making the headers up, so their
+/// address is taken to be the client's.
+#[derive(Debug, Clone, Default)]
+pub struct Forwarding {
+    pub trusted_proxies: Vec<IpNet>,
+    /// Whether forwarding headers sent by peers that aren't trusted proxies are thrown away
+    /// (rather than passed along with our entries appended)
+    pub strip_untrusted: bool,
+}
+
+impl Forwarding {
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Returns the IP of the client a request from `peer` originally came from. Starting from
+    /// `peer`, the chain of addresses in X-Forwarded-For (or, failing that, Forwarded) is followed
+    /// backwards for as long as the address we got it from is a trusted proxy.
+    pub fn client_ip(&self, peer: IpAddr, request: &http::Request<Vec<u8>>) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+        let mut chain = forwarded_for_chain(request);
+        let mut client = peer;
+        while let Some(hop) = chain.pop() {
+            match hop {
+                Some(ip) => {
+                    client = ip;
+                    if !self.is_trusted(ip) {
+                        break;
+                    }
+                }
+                // An obfuscated or garbled address; we can't see past it
+                None => break,
+            }
+        }
+        client
+    }
+
+    /// Adds `peer` (the address that sent us the request) to the forwarding headers of `request`,
+    /// along with the protocol and host the client asked for. Lists sent by a trusted proxy are
+    /// extended, as are ones from other peers unless strip_untrusted is set. X-Forwarded-Proto and
+    /// X-Forwarded-Host are only kept if a trusted proxy set them.
+    pub fn add_headers(&self, request: &mut http::Request<Vec<u8>>, peer: IpAddr, is_tls: bool) {
+        let trusted = self.is_trusted(peer);
+        if !trusted && self.strip_untrusted {
+            for name in FORWARDING_HEADERS {
+                request.headers_mut().remove(name);
+            }
+        }
+        let proto = if is_tls { "https" } else { "http" };
+        let host = request
+            .headers()
+            .get(http::header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .map(str::to_string);
+
+        request::extend_header_value(request.headers_mut(), "x-forwarded-for", &peer.to_string());
+        let mut forwarded = format!("for={};proto={}", forwarded_node(peer), proto);
+        if let Some(host) = &host {
+            forwarded += &format!(";host={}", quote_if_needed(host));
+        }
+        request::extend_header_value(request.headers_mut(), "forwarded", &forwarded);
+
+        let headers = request.headers_mut();
+        if !trusted || !headers.contains_key("x-forwarded-proto") {
+            headers.insert("x-forwarded-proto", http::HeaderValue::from_static(proto));
+        }
+        if !trusted || !headers.contains_key("x-forwarded-host") {
+            match host.and_then(|host| http::HeaderValue::from_str(&host).ok()) {
+                Some(host) => headers.insert("x-forwarded-host", host),
+                None => headers.remove("x-forwarded-host"),
+            };
+        }
+    }
+}
+
+/// Returns the addresses listed in the request's X-Forwarded-For headers (or, if there are none,
+/// the `for=` parameters of its Forwarded headers), oldest first. Entries that aren't IP addresses
+/// (such as `unknown` or obfuscated identifiers) are None.
+fn forwarded_for_chain(request: &http::Request<Vec<u8>>) -> Vec<Option<IpAddr>> {
+    let x_forwarded_for = header_list(request, "x-forwarded-for");
+    if !x_forwarded_for.is_empty() {
+        return x_forwarded_for
+            .iter()
+            .map(|entry| parse_node(entry))
+            .collect();
+    }
+    header_list(request, "forwarded")
+        .iter()
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value.trim().trim_matches('"')))
+            })
+        })
+        .collect()
+}
+
+/// Returns the comma-separated entries of every `name` header, in order.
+fn header_list(request: &http::Request<Vec<u8>>, name: &str) -> Vec<String> {
+    request
+        .headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|entry| entry.trim().to_string())
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+/// Parses an address that may come with a port, with IPv6 addresses possibly in brackets
+/// (`10.0.0.1`, `10.0.0.1:1234`, `2001:db8::1`, `[2001:db8::1]:1234`).
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|node| node.strip_suffix(']'))
+                .and_then(|ip| ip.parse().ok())
+        })
+}
+
+/// Formats an address for a Forwarded header, where IPv6 addresses have to be bracketed and
+/// quoted.
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+/// Quotes a Forwarded parameter value unless it is a valid token.
+fn quote_if_needed(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Parses a --trusted-proxy value: a CIDR range (`10.0.0.0/8`) or a single address.
+pub fn parse_trusted_proxy(value: &str) -> Result<IpNet, String> {
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("{:?} is neither an IP address nor a CIDR range", value))
+}
+
+/// Deserializes a list of trusted proxies (see parse_trusted_proxy) from a configuration file.
+pub fn deserialize_trusted_proxies<'de, D>(deserializer: D) -> Result<Option<Vec<IpNet>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let values: Option<Vec<String>> = serde::Deserialize::deserialize(deserializer)?;
+    values
+        .map(|values| {
+            values
+                .iter()
+                .map(|value| parse_trusted_proxy(value))
+                .collect()
+        })
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
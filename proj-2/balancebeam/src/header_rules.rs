@@ -0,0 +1,227 @@
+use std::net::IpAddr;
+
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+
+use crate::rewrite;
+use crate::routing::Route;
+
+/// A change to the headers of a request or response. Values are templates: `{client_ip}`,
+/// `{upstream}` and `{request_id}` are replaced by the IP of the client, the address of the
+/// upstream the request went to (empty if there isn't one) and an ID generated for each request.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase", deny_unknown_fields)]
+pub enum HeaderRule {
+    /// Adds a value, keeping any the header already has
+    Add {
+        name: String,
+        value: String,
+    },
+    /// Replaces any values the header has
+    Set {
+        name: String,
+        value: String,
+    },
+    Remove {
+        name: String,
+    },
+    /// Moves every value of header `name` over to header `to`
+    Rename {
+        name: String,
+        to: String,
+    },
+}
+
+impl HeaderRule {
+    /// Makes sure the header names are valid and the value is a valid template.
+    pub fn check(&self) -> Result<(), String> {
+        let (name, other) = match self {
+            HeaderRule::Add { name, value } | HeaderRule::Set { name, value } => {
+                let variables = Variables {
+                    client_ip: "127.0.0.1",
+                    upstream: Some("127.0.0.1:80"),
+                    request_id: "0",
+                };
+                let expanded = expand(value, &variables)?;
+                if HeaderValue::from_str(&expanded).is_err() {
+                    return Err(format!("invalid header value {:?}", value));
+                }
+                (name, None)
+            }
+            HeaderRule::Remove { name } => (name, None),
+            HeaderRule::Rename { name, to } => (name, Some(to)),
+        };
+        for name in std::iter::once(name).chain(other) {
+            match HeaderName::from_bytes(name.as_bytes()) {
+                // Changing these would break the framing of the body
+                Ok(name)
+                    if name == http::header::CONTENT_LENGTH
+                        || name == http::header::TRANSFER_ENCODING =>
+                {
+                    return Err(format!("{} can't be changed", name));
+                }
+                Ok(_) => {}
+                Err(_) => return Err(format!("invalid header name {:?}", name)),
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&self, headers: &mut HeaderMap, variables: &Variables) {
+        // Names and values were checked when the configuration was loaded
+        let header_name = |name: &str| HeaderName::from_bytes(name.as_bytes()).ok();
+        let header_value = |value: &str| {
+            expand(value, variables)
+                .ok()
+                .and_then(|expanded| HeaderValue::from_str(&expanded).ok())
+        };
+        match self {
+            HeaderRule::Add { name, value } => {
+                if let (Some(name), Some(value)) = (header_name(name), header_value(value)) {
+                    headers.append(name, value);
+                }
+            }
+            HeaderRule::Set { name, value } => {
+                if let (Some(name), Some(value)) = (header_name(name), header_value(value)) {
+                    headers.insert(name, value);
+                }
+            }
+            HeaderRule::Remove { name } => {
+                if let Some(name) = header_name(name) {
+                    headers.remove(name);
+                }
+            }
+            HeaderRule::Rename { name, to } => {
+                if let (Some(name), Some(to)) = (header_name(name), header_name(to)) {
+                    let values: Vec<HeaderValue> = headers.get_all(&name).iter().cloned().collect();
+                    headers.remove(name);
+                    for value in values {
+                        headers.append(&to, value);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// What the variables in rule values stand for
+struct Variables<'a> {
+    client_ip: &'a str,
+    upstream: Option<&'a str>,
+    request_id: &'a str,
+}
+
+/// Replaces the `{variable}`s in `template`, returning an error if it refers to an unknown one.
+fn expand(template: &str, variables: &Variables) -> Result<String, String> {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed {{ in {:?}", template))?
+            + start;
+        expanded.push_str(match &rest[start + 1..end] {
+            "client_ip" => variables.client_ip,
+            "upstream" => variables.upstream.unwrap_or(""),
+            "request_id" => variables.request_id,
+            unknown => {
+                return Err(format!(
+                    "unknown variable {{{}}} in {:?}",
+                    unknown, template
+                ))
+            }
+        });
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Applies the header rules of the route a request took to the request as it is forwarded and to
+/// the response sent back, and makes redirects from the upstream lead back through us.
+pub struct HeaderRewriter {
+    request_rules: Vec<HeaderRule>,
+    response_rules: Vec<HeaderRule>,
+    client_ip: String,
+    request_id: String,
+    /// Host header of the client's request
+    client_host: Option<String>,
+    /// The path prefix the route strips off requests, if it does
+    stripped_prefix: Option<String>,
+}
+
+impl HeaderRewriter {
+    pub fn new(
+        route: Option<&Route>,
+        client_ip: IpAddr,
+        request: &http::Request<Vec<u8>>,
+    ) -> HeaderRewriter {
+        HeaderRewriter {
+            request_rules: route
+                .map(|route| route.request_headers.clone())
+                .unwrap_or_default(),
+            response_rules: route
+                .map(|route| route.response_headers.clone())
+                .unwrap_or_default(),
+            client_ip: client_ip.to_string(),
+            request_id: format!("{:032x}", rand::random::<u128>()),
+            client_host: request
+                .headers()
+                .get(http::header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .map(str::to_string),
+            stripped_prefix: route
+                .filter(|route| route.strip_path_prefix)
+                .and_then(|route| route.path_prefix.clone()),
+        }
+    }
+
+    /// Returns a copy of `request` with the request rules applied for sending it to `upstream`, or
+    /// None if there are no request rules.
+    pub fn rewrite_request(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        upstream: &str,
+    ) -> Option<http::Request<Vec<u8>>> {
+        if self.request_rules.is_empty() {
+            return None;
+        }
+        let mut rewritten = http::Request::new(Vec::new());
+        *rewritten.method_mut() = request.method().clone();
+        *rewritten.uri_mut() = request.uri().clone();
+        *rewritten.version_mut() = request.version();
+        *rewritten.headers_mut() = request.headers().clone();
+        let variables = self.variables(Some(upstream));
+        for rule in &self.request_rules {
+            rule.apply(rewritten.headers_mut(), &variables);
+        }
+        Some(rewritten)
+    }
+
+    /// Rewrites the Location of a redirect from `upstream` (see rewrite::rewrite_location).
+    pub fn rewrite_location(&self, response: &mut http::Response<Vec<u8>>, upstream: &str) {
+        rewrite::rewrite_location(
+            response,
+            upstream,
+            self.client_host.as_deref(),
+            self.stripped_prefix.as_deref(),
+        );
+    }
+
+    /// Applies the response rules to a response that came from `upstream` (or out of the cache).
+    pub fn rewrite_response(&self, response: &mut http::Response<Vec<u8>>, upstream: Option<&str>) {
+        let variables = self.variables(upstream);
+        for rule in &self.response_rules {
+            rule.apply(response.headers_mut(), &variables);
+        }
+    }
+
+    fn variables<'a>(&'a self, upstream: Option<&'a str>) -> Variables<'a> {
+        Variables {
+            client_ip: &self.client_ip,
+            upstream,
+            request_id: &self.request_id,
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::RwLock;
+use tokio::task::JoinSet;
+
+use crate::{request, response, tls, ProxyState};
+
+/// Spawns a background task that runs an active health check every active_health_check_interval
+/// seconds, independently of whether any clients are connected. The interval is re-read after every
+/// check so that configuration reloads take effect; an interval of 0 disables active health checks.
+pub fn spawn_active_health_checks(state: Arc<RwLock<ProxyState>>) {
+    tokio::spawn(async move {
+        loop {
+            let interval_secs = state.read().await.active_health_check_interval;
+            if interval_secs == 0 {
+                // Disabled for now, but a reload may turn them back on
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+            tokio::time::sleep(Duration::from_secs(interval_secs as u64)).await;
+            health_check(&state).await;
+        }
+    });
+}
+
+/// Probes every upstream of every pool concurrently (on the pool's health check path), then
+/// replaces each pool's set of live upstreams with the ones that responded successfully (restoring
+/// previously failed upstreams that have come back). An upstream that is in several pools with the
+/// same health check path is only probed once.
+pub async fn health_check(state: &RwLock<ProxyState>) {
+    let (targets, timeout, connector) = {
+        let state_read = state.read().await;
+        let mut targets: Vec<(String, String)> = Vec::new();
+        for pool in &state_read.pools {
+            for upstream in &pool.configured_upstreams {
+                let target = (upstream.clone(), pool.health_check_path.clone());
+                if !targets.contains(&target) {
+                    targets.push(target);
+                }
+            }
+        }
+        (
+            targets,
+            Duration::from_secs(state_read.active_health_check_timeout as u64),
+            state_read.upstream_connector.clone(),
+        )
+    };
+
+    let mut probes = JoinSet::new();
+    for (upstream, path) in targets.iter().cloned() {
+        let connector = connector.clone();
+        probes.spawn(async move {
+            log::info!("Performing active health check on {}{}", upstream, path);
+            let probed = probe(&connector, &upstream, &path);
+            let healthy = match tokio::time::timeout(timeout, probed).await {
+                Ok(Ok(())) => true,
+                Ok(Err(reason)) => {
+                    log::warn!(
+                        "Upstream {} failed active health check: {}",
+                        upstream,
+                        reason
+                    );
+                    false
+                }
+                Err(_) => {
+                    log::warn!(
+                        "Upstream {} failed active health check: no response within {:?}",
+                        upstream,
+                        timeout
+                    );
+                    false
+                }
+            };
+            (upstream, path, healthy)
+        });
+    }
+    let metrics = state.read().await.metrics.clone();
+    let mut healthy_targets = Vec::new();
+    while let Some(result) = probes.join_next().await {
+        match result {
+            Ok((upstream, path, healthy)) => {
+                metrics.record_health_check(&upstream, healthy);
+                if healthy {
+                    healthy_targets.push((upstream, path));
+                }
+            }
+            Err(err) => log::error!("Active health check task failed: {}", err),
+        }
+    }
+
+    let mut state_write = state.write().await;
+    let state_write = &mut *state_write;
+    for pool in &mut state_write.pools {
+        // The upstreams may have been changed (by a reload or the admin API) while we were
+        // probing. Upstreams that have been added since (or whose health check path changed) keep
+        // their current status until the next check, and ones that have been removed stay removed.
+        let mut live_upstreams = Vec::new();
+        for upstream in &pool.configured_upstreams {
+            let target = (upstream.clone(), pool.health_check_path.clone());
+            let was_live = pool.upstream_addresses.contains(upstream);
+            let is_live = if targets.contains(&target) {
+                healthy_targets.contains(&target)
+            } else {
+                was_live
+            };
+            if is_live && !was_live {
+                log::info!("Restored upstream {} in pool {}", upstream, pool.name);
+            } else if was_live && !is_live {
+                log::info!("Removed upstream {} from pool {}", upstream, pool.name);
+                state_write.connection_pool.clear(upstream);
+            }
+            // Keep the configured order so that order-dependent strategies stay predictable
+            if is_live {
+                live_upstreams.push(upstream.clone());
+            }
+        }
+        pool.upstream_addresses = live_upstreams;
+    }
+}
+
+/// Sends a GET request for `path` to `upstream` (over TLS for `https://` upstreams), returning
+/// Ok(()) if it answers with a 2xx status.
+async fn probe(connector: &tls::Connector, upstream: &str, path: &str) -> Result<(), String> {
+    let (_, address) = tls::parse_upstream(upstream);
+    let request = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(path)
+        .header("Host", address)
+        .body(Vec::new())
+        .unwrap();
+
+    let mut upstream_conn = connector
+        .connect(upstream)
+        .await
+        .map_err(|err| format!("could not connect: {}", err))?;
+    request::write_to_stream(&request, &mut upstream_conn)
+        .await
+        .map_err(|err| format!("could not send request: {}", err))?;
+    let response = response::read_from_stream(&mut upstream_conn, request.method())
+        .await
+        .map_err(|err| format!("could not read response: {}", err))?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("returned status {}", response.status()))
+    }
+}
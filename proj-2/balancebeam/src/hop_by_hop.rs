@@ -0,0 +1,152 @@
+use http::{HeaderMap, HeaderValue, Version};
+
+use crate::request;
+
+/// Name balancebeam goes by in Via headers
+const VIA_PSEUDONYM: &str = "balancebeam";
+
+/// Headers that only describe the connection they arrive on, so they must not be passed on to the
+/// next hop (RFC 9110 section 7.6.1). Transfer-Encoding and Trailer are hop-by-hop too, but they
+/// are left alone here: body::copy_body re-frames bodies itself, and response::body_framing and
+/// request::body_framing keep those headers in line with how the body is sent.
+const HOP_BY_HOP_HEADERS: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "upgrade",
+];
+
+/// Returns true if `option` is listed in the Connection header.
+pub fn has_connection_option(headers: &HeaderMap, option: &str) -> bool {
+    headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|listed| listed.trim().eq_ignore_ascii_case(option))
+}
+
+/// Returns true if the client wants to send more requests on its connection after this one:
+/// HTTP/1.1 connections stay open unless the client says `Connection: close`, while HTTP/1.0
+/// connections are closed unless the client says `Connection: keep-alive`.
+pub fn client_wants_keep_alive(request: &http::Request<Vec<u8>>) -> bool {
+    match request.version() {
+        Version::HTTP_10 => has_connection_option(request.headers(), "keep-alive"),
+        _ => !has_connection_option(request.headers(), "close"),
+    }
+}
+
+/// Returns the protocols a message asks to switch to (its Upgrade header), if it lists `upgrade`
+/// in its Connection header as it has to for the Upgrade header to count.
+pub fn upgrade_protocols(headers: &HeaderMap) -> Option<HeaderValue> {
+    if !has_connection_option(headers, "upgrade") {
+        return None;
+    }
+    headers.get(http::header::UPGRADE).cloned()
+}
+
+/// Puts back the headers that ask for (or agree to) switching to `protocols`, which
+/// remove_hop_by_hop_headers strips along with the rest.
+fn set_upgrade(headers: &mut HeaderMap, protocols: HeaderValue) {
+    headers.insert(
+        http::header::CONNECTION,
+        HeaderValue::from_static("upgrade"),
+    );
+    headers.insert(http::header::UPGRADE, protocols);
+}
+
+/// Removes the hop-by-hop headers from a message we are about to pass on, including any headers
+/// the sender listed in its Connection header.
+pub fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let listed: Vec<String> = headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+    for name in listed {
+        // Never let a sender make us drop the headers that frame the body
+        if name != "transfer-encoding" && name != "content-length" {
+            headers.remove(name.as_str());
+        }
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+/// Prepares a client's request for being sent upstream: strips its hop-by-hop headers, and notes
+/// that it passed through us in the Via header. The request keeps the client's HTTP version; an
+/// HTTP/1.0 request asks for the upstream connection to be kept alive so that it can be pooled.
+///
+/// A request to switch protocols (e.g. to WebSocket) keeps its Upgrade header, since the upstream
+/// has to see it for the client's connection to be upgraded end to end. HTTP/1.0 doesn't have
+/// upgrades, so they are ignored there (RFC 9110 section 7.8).
+pub fn prepare_request(request: &mut http::Request<Vec<u8>>) {
+    let upgrade =
+        upgrade_protocols(request.headers()).filter(|_| request.version() != Version::HTTP_10);
+    remove_hop_by_hop_headers(request.headers_mut());
+    if let Some(protocols) = upgrade {
+        set_upgrade(request.headers_mut(), protocols);
+    }
+    let via = format!("{} {}", via_version(request.version()), VIA_PSEUDONYM);
+    request::extend_header_value(request.headers_mut(), "via", &via);
+    if request.version() == Version::HTTP_10 {
+        request.headers_mut().insert(
+            http::header::CONNECTION,
+            HeaderValue::from_static("keep-alive"),
+        );
+    }
+}
+
+/// Prepares an upstream's response for being sent to the client: strips its hop-by-hop headers,
+/// notes that it passed through us in the Via header, and tells the client whether its connection
+/// stays open. The response goes out as HTTP/1.1, the version balancebeam speaks.
+pub fn prepare_response(
+    response: &mut http::Response<Vec<u8>>,
+    client_version: Version,
+    keep_client_alive: bool,
+) {
+    remove_hop_by_hop_headers(response.headers_mut());
+    let via = format!("{} {}", via_version(response.version()), VIA_PSEUDONYM);
+    let headers = response.headers_mut();
+    request::extend_header_value(headers, "via", &via);
+    if !keep_client_alive {
+        headers.insert(http::header::CONNECTION, HeaderValue::from_static("close"));
+    } else if client_version == Version::HTTP_10 {
+        headers.insert(
+            http::header::CONNECTION,
+            HeaderValue::from_static("keep-alive"),
+        );
+    }
+    *response.version_mut() = Version::HTTP_11;
+}
+
+/// Prepares an upstream's 101 (Switching Protocols) response for being sent to the client: like
+/// prepare_response, except that the headers saying which protocol the connection switches to are
+/// kept.
+pub fn prepare_upgrade_response(response: &mut http::Response<Vec<u8>>) {
+    let upgrade = response.headers().get(http::header::UPGRADE).cloned();
+    remove_hop_by_hop_headers(response.headers_mut());
+    let via = format!("{} {}", via_version(response.version()), VIA_PSEUDONYM);
+    request::extend_header_value(response.headers_mut(), "via", &via);
+    if let Some(protocols) = upgrade {
+        set_upgrade(response.headers_mut(), protocols);
+    }
+    *response.version_mut() = Version::HTTP_11;
+}
+
+/// Formats a version the way Via headers list it (the protocol name is left out for HTTP).
+fn via_version(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    }
+}
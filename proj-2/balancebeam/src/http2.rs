@@ -0,0 +1,396 @@
+use std::future::{poll_fn, Future};
+use std::time::Duration;
+
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{Reason, RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::task::JoinSet;
+
+use crate::shutdown::ShutdownSignal;
+use crate::stream::ClientStream;
+use crate::{body, chunked, hop_by_hop, request, response};
+
+/// Protocol name clients use to ask for HTTP/2 in the TLS handshake (ALPN)
+pub const ALPN_PROTOCOL: &[u8] = b"h2";
+/// What a client opens a plaintext connection with when it knows we speak HTTP/2 ("prior
+/// knowledge", RFC 9113 section 3.3)
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// Most streams a client may have open on one connection at a time
+const MAX_CONCURRENT_STREAMS: u32 = 128;
+/// Bytes that can be in flight between a stream and the HTTP/1.1 request handling it is bridged to
+const BRIDGE_BUFFER_SIZE: usize = 64 * 1024;
+/// Number of response body bytes to read at a time
+const READ_SIZE: usize = 16 * 1024;
+
+/// Why relaying a stream failed
+#[derive(Debug)]
+enum Error {
+    /// The client's side of the stream (or the whole connection) failed or was reset
+    Client(h2::Error),
+    /// The client stopped accepting data on the stream
+    StreamClosed,
+    /// The HTTP/1.1 request handling failed or sent something that isn't a valid response
+    Proxy(String),
+}
+
+impl From<h2::Error> for Error {
+    fn from(error: h2::Error) -> Error {
+        Error::Client(error)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Client(error) => write!(f, "client stream error: {}", error),
+            Error::StreamClosed => write!(f, "client closed the stream"),
+            Error::Proxy(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Reads from a plaintext client until it is clear whether it opened the connection with the
+/// HTTP/2 preface, returning true if it did. The bytes read are left in `already_read`: the
+/// whole preface, or the start of an HTTP/1.x request otherwise.
+pub async fn read_preface(
+    client_conn: &mut ClientStream,
+    already_read: &mut Vec<u8>,
+) -> std::io::Result<bool> {
+    let mut buffer = [0_u8; PREFACE.len()];
+    while already_read.len() < PREFACE.len() {
+        // Never read past the preface, which may be followed by HTTP/2 frames
+        let wanted = PREFACE.len() - already_read.len();
+        let n = client_conn.read(&mut buffer[..wanted]).await?;
+        if n == 0 {
+            return Ok(false);
+        }
+        already_read.extend_from_slice(&buffer[..n]);
+        if !PREFACE.starts_with(already_read) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Serves an HTTP/2 connection. Every stream the client opens is translated into an HTTP/1.1
+/// request, which `handle_stream` handles like a connection of its own (routing it, forwarding it
+/// upstream and so on), and the HTTP/1.1 response is translated back onto the stream. Streams are
+/// handled concurrently, and each is subject to HTTP/2 flow control in both directions.
+///
+/// The connection is closed gracefully (letting open streams finish) once it has had no open
+/// streams for `idle_timeout` seconds (0 = no limit), or when balancebeam shuts down.
+/// `preface_read` says whether read_preface() already took the preface off the connection.
+pub async fn serve<F, Fut>(
+    client_conn: ClientStream,
+    preface_read: bool,
+    idle_timeout: usize,
+    mut shutdown: ShutdownSignal,
+    handle_stream: F,
+) where
+    F: Fn(ClientStream) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let peer_addr = client_conn.peer_addr().unwrap();
+    let is_tls = client_conn.is_tls();
+    // Put the preface back in front of the rest of the connection for the handshake to read
+    let preface: &[u8] = if preface_read { PREFACE } else { &[] };
+    let (reader, writer) = tokio::io::split(client_conn);
+    let handshake = h2::server::Builder::new()
+        .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+        .handshake::<_, Bytes>(tokio::io::join(preface.chain(reader), writer));
+    let mut connection = match handshake.await {
+        Ok(connection) => connection,
+        Err(error) => {
+            log::info!("HTTP/2 handshake with {} failed: {}", peer_addr.ip(), error);
+            return;
+        }
+    };
+    log::debug!("Serving HTTP/2 to {}", peer_addr.ip());
+
+    let mut streams = JoinSet::new();
+    let mut closing = false;
+    loop {
+        let idle = streams.is_empty() && !closing && idle_timeout > 0;
+        tokio::select! {
+            accepted = connection.accept() => match accepted {
+                Some(Ok((request, respond))) => {
+                    let (proxy_side, bridge_side) = tokio::io::duplex(BRIDGE_BUFFER_SIZE);
+                    streams.spawn(handle_stream(ClientStream::Http2 {
+                        stream: proxy_side,
+                        peer_addr,
+                        is_tls,
+                    }));
+                    streams.spawn(relay_stream(request, respond, bridge_side, shutdown.clone()));
+                }
+                Some(Err(error)) => {
+                    log::info!("HTTP/2 connection from {} failed: {}", peer_addr.ip(), error);
+                    break;
+                }
+                None => break,
+            },
+            Some(_) = streams.join_next(), if !streams.is_empty() => {}
+            _ = shutdown.wait(), if !closing => {
+                log::debug!("Shutting down. Closing HTTP/2 connection from {}", peer_addr.ip());
+                closing = true;
+                connection.graceful_shutdown();
+            }
+            _ = tokio::time::sleep(Duration::from_secs(idle_timeout as u64)), if idle => {
+                log::debug!("HTTP/2 connection from {} went idle. Closing it", peer_addr.ip());
+                closing = true;
+                connection.graceful_shutdown();
+            }
+        }
+    }
+    log::debug!("HTTP/2 connection from {} closed", peer_addr.ip());
+}
+
+/// Relays one stream: the client's request is written to `bridge` as HTTP/1.1 while the response
+/// read back from it is sent to the client. Both happen at once, so that a response sent before
+/// the whole request body has arrived (e.g. an error) isn't held up.
+async fn relay_stream(
+    request: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    bridge: tokio::io::DuplexStream,
+    shutdown: ShutdownSignal,
+) {
+    let (parts, request_body) = request.into_parts();
+    let request = to_http1_request(parts, !request_body.is_end_stream());
+    let method = request.method().clone();
+    let (mut reader, writer) = tokio::io::split(bridge);
+    let (sent, _) = tokio::join!(
+        send_request(&request, request_body, writer),
+        forward_response(&mut reader, &mut respond, &method, &shutdown),
+    );
+    if let Err(error) = sent {
+        log::debug!("Could not relay request body: {}", error);
+    }
+}
+
+/// Translates the head of an HTTP/2 request into an HTTP/1.1 request (RFC 9113 section 8.3):
+/// the :authority pseudo-header becomes the Host header, split Cookie headers are joined back
+/// together, and a body of unknown length is sent chunked.
+fn to_http1_request(parts: http::request::Parts, has_body: bool) -> http::Request<Vec<u8>> {
+    let target = match parts.uri.path_and_query() {
+        Some(path_and_query) => path_and_query.as_str(),
+        // CONNECT requests name just the authority
+        None => parts
+            .uri
+            .authority()
+            .map_or("/", |authority| authority.as_str()),
+    };
+    let mut request = http::Request::new(Vec::new());
+    *request.method_mut() = parts.method;
+    *request.uri_mut() = target
+        .parse()
+        .unwrap_or_else(|_| http::Uri::from_static("/"));
+    *request.version_mut() = http::Version::HTTP_11;
+    let mut headers = parts.headers;
+    if let Some(authority) = parts.uri.authority() {
+        if let Ok(host) = http::HeaderValue::from_str(authority.as_str()) {
+            headers.insert(http::header::HOST, host);
+        }
+    }
+    let cookies: Vec<&[u8]> = headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .map(|cookie| cookie.as_bytes())
+        .collect();
+    if cookies.len() > 1 {
+        let joined = http::HeaderValue::from_bytes(&cookies.join(&b"; "[..])).unwrap();
+        headers.insert(http::header::COOKIE, joined);
+    }
+    if has_body && !headers.contains_key(http::header::CONTENT_LENGTH) {
+        headers.insert(
+            http::header::TRANSFER_ENCODING,
+            http::HeaderValue::from_static("chunked"),
+        );
+    }
+    *request.headers_mut() = headers;
+    request
+}
+
+/// Writes `request` to the bridge, streaming its body from the client as it arrives. The bridge
+/// is shut down for writing afterwards (even if the body broke off), which tells the request
+/// handling that there are no more requests coming.
+async fn send_request<W: AsyncWrite + Unpin>(
+    request: &http::Request<Vec<u8>>,
+    mut body: RecvStream,
+    mut writer: W,
+) -> Result<(), Error> {
+    let sent = async {
+        let proxy_error = |error: std::io::Error| Error::Proxy(error.to_string());
+        request::write_head(request, &mut writer)
+            .await
+            .map_err(proxy_error)?;
+        let chunked = chunked::is_chunked(request.headers());
+        while let Some(data) = body.data().await {
+            let data = data?;
+            let written = if chunked {
+                chunked::write_chunk(&mut writer, &data).await
+            } else {
+                writer.write_all(&data).await
+            };
+            written.map_err(proxy_error)?;
+            // Let the client send more now that this has been passed on
+            let _ = body.flow_control().release_capacity(data.len());
+        }
+        if chunked {
+            let trailers = body.trailers().await?.map(chunked::Trailers);
+            chunked::write_last_chunk(&mut writer, trailers.as_ref())
+                .await
+                .map_err(proxy_error)?;
+        }
+        writer.flush().await.map_err(proxy_error)
+    }
+    .await;
+    let _ = writer.shutdown().await;
+    sent
+}
+
+/// Reads the HTTP/1.1 response from the bridge and sends it on the stream. Failures are reported
+/// to the client by resetting the stream: REFUSED_STREAM if the request was turned away because
+/// balancebeam is shutting down (so the client knows it is safe to retry), or INTERNAL_ERROR
+/// otherwise.
+async fn forward_response<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    respond: &mut SendResponse<Bytes>,
+    method: &http::Method,
+    shutdown: &ShutdownSignal,
+) {
+    let head = tokio::select! {
+        head = read_response_head(reader, method) => head,
+        reset = poll_fn(|cx| respond.poll_reset(cx)) => {
+            log::debug!("Client reset HTTP/2 stream: {:?}", reset);
+            return;
+        }
+    };
+    let (response, framing) = match head {
+        Ok(head) => head,
+        Err(error) => {
+            log::debug!("No response to send on HTTP/2 stream: {}", error);
+            let reason = if shutdown.is_shutting_down() {
+                Reason::REFUSED_STREAM
+            } else {
+                Reason::INTERNAL_ERROR
+            };
+            respond.send_reset(reason);
+            return;
+        }
+    };
+    let (mut parts, body_start) = response.into_parts();
+    // Connection-specific headers aren't allowed in HTTP/2 (RFC 9113 section 8.2.2)
+    hop_by_hop::remove_hop_by_hop_headers(&mut parts.headers);
+    parts.headers.remove(http::header::TRANSFER_ENCODING);
+    parts.version = http::Version::HTTP_2;
+    let head = http::Response::from_parts(parts, ());
+    let mut send = match respond.send_response(head, framing == body::Framing::Empty) {
+        Ok(send) => send,
+        Err(error) => {
+            log::debug!("Could not send response on HTTP/2 stream: {}", error);
+            return;
+        }
+    };
+    if let Err(error) = forward_body(reader, body_start, framing, &mut send).await {
+        log::debug!("Could not relay response body: {}", error);
+        send.send_reset(Reason::INTERNAL_ERROR);
+    }
+}
+
+/// Reads the head of the final response from the bridge, skipping interim (1xx) responses.
+async fn read_response_head<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    method: &http::Method,
+) -> Result<(http::Response<Vec<u8>>, body::Framing), Error> {
+    loop {
+        let mut response = response::read_headers(reader)
+            .await
+            .map_err(|error| Error::Proxy(error.to_string()))?;
+        if response.status().is_informational() {
+            continue;
+        }
+        let framing = response::body_framing(&mut response, method)
+            .map_err(|error| Error::Proxy(error.to_string()))?;
+        return Ok((response, framing));
+    }
+}
+
+/// Sends a response body read from the bridge on the stream, ending the stream afterwards (with
+/// the trailers of a chunked body, if there are any).
+async fn forward_body<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    already_read: Vec<u8>,
+    framing: body::Framing,
+    send: &mut SendStream<Bytes>,
+) -> Result<(), Error> {
+    match framing {
+        body::Framing::Empty => return Ok(()),
+        body::Framing::Length(length) => {
+            forward_raw(reader, already_read, Some(length), send).await?
+        }
+        body::Framing::UntilClose => forward_raw(reader, already_read, None, send).await?,
+        body::Framing::Chunked => {
+            let mut chunked_reader = chunked::ChunkedReader::new(reader, already_read);
+            while let Some(data) = chunked_reader
+                .next()
+                .await
+                .map_err(|error| Error::Proxy(format!("bad chunked body: {:?}", error)))?
+            {
+                send_data(send, data.into()).await?;
+            }
+            let trailers = chunked_reader.into_trailers();
+            if !trailers.is_empty() {
+                send.send_trailers(trailers)?;
+                return Ok(());
+            }
+        }
+    }
+    send.send_data(Bytes::new(), true)?;
+    Ok(())
+}
+
+/// Sends body bytes read from the bridge on the stream until `length` bytes have been sent (or, if
+/// there is no length, until the bridge is closed).
+async fn forward_raw<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    mut already_read: Vec<u8>,
+    length: Option<u64>,
+    send: &mut SendStream<Bytes>,
+) -> Result<(), Error> {
+    let mut remaining = length.unwrap_or(u64::MAX);
+    already_read.truncate(remaining.min(already_read.len() as u64) as usize);
+    remaining -= already_read.len() as u64;
+    send_data(send, already_read.into()).await?;
+    let mut buffer = vec![0_u8; READ_SIZE];
+    while remaining > 0 {
+        let to_read = remaining.min(READ_SIZE as u64) as usize;
+        let bytes_read = reader
+            .read(&mut buffer[..to_read])
+            .await
+            .map_err(|error| Error::Proxy(error.to_string()))?;
+        if bytes_read == 0 {
+            if length.is_some() {
+                return Err(Error::Proxy("response body was cut short".to_string()));
+            }
+            break;
+        }
+        remaining -= bytes_read as u64;
+        send_data(send, Bytes::copy_from_slice(&buffer[..bytes_read])).await?;
+    }
+    Ok(())
+}
+
+/// Sends `data` on the stream, waiting for the client's flow control window to make room for it.
+async fn send_data(send: &mut SendStream<Bytes>, mut data: Bytes) -> Result<(), Error> {
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let capacity = match poll_fn(|cx| send.poll_capacity(cx)).await {
+            Some(capacity) => capacity?,
+            None => return Err(Error::StreamClosed),
+        };
+        if capacity > 0 {
+            send.send_data(data.split_to(capacity.min(data.len())), false)?;
+        }
+    }
+    Ok(())
+}
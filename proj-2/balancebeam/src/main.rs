@@ -1,60 +1,394 @@
+mod access_log;
+mod admin;
+mod body;
+mod cache;
+mod chunked;
+mod circuit_breaker;
+mod compression;
+mod config;
+mod forwarded;
+mod header_rules;
+mod health;
+mod hop_by_hop;
+mod http2;
+mod metrics;
+mod pool;
+mod rate_limit;
 mod request;
 mod response;
+mod rewrite;
+mod routing;
+mod shutdown;
+mod strategy;
+mod stream;
+mod timeout;
+mod tls;
+mod tunnel;
 
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use access_log::{AccessLog, AccessLogFormat};
+use cache::ResponseCache;
+use circuit_breaker::CircuitBreakers;
 use clap::Parser;
-use rand::{Rng, SeedableRng};
+use compression::{Compression, Compressor};
+use config::Config;
+use forwarded::Forwarding;
+use header_rules::HeaderRewriter;
+use ipnet::IpNet;
+use metrics::Metrics;
+use pool::ConnectionPool;
+use routing::{Route, UpstreamPool};
+use shutdown::ShutdownSignal;
+use strategy::{RequestGuard, StrategyKind};
+use stream::{ClientStream, UpstreamStream};
+use timeout::{TimeoutReader, Timeouts};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::RwLock;
-use std::time::Instant;
-
+use tokio::task::JoinSet;
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(about = "Fun with load balancing")]
 struct CmdOptions {
+    /// "TOML or YAML configuration file (overrides the other options; reloaded on SIGHUP)"
+    #[arg(short, long)]
+    config: Option<PathBuf>,
     /// "IP/port to bind to"
     #[arg(short, long, default_value = "0.0.0.0:1100")]
     bind: String,
-    /// "Upstream host to forward requests to"
+    /// "How long to let open connections finish their requests after SIGTERM/SIGINT (in seconds)"
+    #[arg(long, default_value = "30")]
+    shutdown_grace_period: usize,
+    /// "IP/port to serve admin endpoints such as /metrics on (disabled if not given)"
+    #[arg(long)]
+    admin_bind: Option<String>,
+    /// "IP/port to accept HTTPS connections on (disabled if not given)"
+    #[arg(long)]
+    tls_bind: Option<String>,
+    /// "PEM file with the certificate chain to present to HTTPS clients"
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+    /// "PEM file with the private key for --tls-cert"
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+    /// "Certificate for clients asking for a particular host through SNI, as HOST=CERT_PATH,KEY_PATH"
+    #[arg(long, value_parser = tls::parse_sni_certificate)]
+    tls_sni_cert: Vec<tls::SniCertificate>,
+    /// "Upstream host to forward requests to (prefix with https:// to connect over TLS)"
     #[arg(short, long)]
     upstream: Vec<String>,
+    /// "PEM file with the CA certificates to check https:// upstreams against (default: system's)"
+    #[arg(long)]
+    upstream_ca: Option<PathBuf>,
+    /// "Don't check the certificates of https:// upstreams (for test environments only!)"
+    #[arg(long)]
+    upstream_insecure_skip_verify: bool,
+    /// "PEM file with a certificate chain to present to https:// upstreams that ask for one"
+    #[arg(long)]
+    upstream_client_cert: Option<PathBuf>,
+    /// "PEM file with the private key for --upstream-client-cert"
+    #[arg(long)]
+    upstream_client_key: Option<PathBuf>,
     /// "Perform active health checks on this interval (in seconds)"
     #[arg(long, default_value = "10")]
     active_health_check_interval: usize,
     /// "Path to send request to for active health checks"
     #[arg(long, default_value = "/")]
     active_health_check_path: String,
+    /// "How long to wait for an upstream to answer an active health check (in seconds)"
+    #[arg(long, default_value = "5")]
+    active_health_check_timeout: usize,
     /// "Maximum number of requests to accept per IP per minute (0 = unlimited)"
     #[arg(long, default_value = "0")]
     max_requests_per_minute: usize,
+    /// "Load-balancing strategy used to pick an upstream for each request"
+    #[arg(long, value_enum, default_value = "random")]
+    strategy: StrategyKind,
+    /// "Weight of an upstream for the weighted strategy, as ADDRESS=WEIGHT (default weight 1)"
+    #[arg(long, value_parser = parse_upstream_weight)]
+    upstream_weight: Vec<(String, u32)>,
+    /// "How many times an idempotent request is retried on another upstream if one fails"
+    #[arg(long, default_value = "2")]
+    max_retries: usize,
+    /// "Maximum number of idle keep-alive connections to keep open to each upstream (0 = none)"
+    #[arg(long, default_value = "8")]
+    pool_max_idle: usize,
+    /// "How long an idle upstream connection is kept open for reuse (in seconds)"
+    #[arg(long, default_value = "60")]
+    pool_idle_timeout: usize,
+    /// "Consecutive failed requests after which an upstream is taken out of rotation (0 = never)"
+    #[arg(long, default_value = "5")]
+    circuit_breaker_threshold: usize,
+    /// "How long a failing upstream stays out of rotation before it is tried again (in seconds)"
+    #[arg(long, default_value = "30")]
+    circuit_breaker_cool_down: usize,
+    /// "How long a client has to send a request's headers (in seconds, 0 = no limit)"
+    #[arg(long, default_value = "10")]
+    client_header_timeout: usize,
+    /// "Longest a client may pause while sending a request body (in seconds, 0 = no limit)"
+    #[arg(long, default_value = "30")]
+    client_body_timeout: usize,
+    /// "How long an upstream has to accept a connection (in seconds, 0 = no limit)"
+    #[arg(long, default_value = "5")]
+    upstream_connect_timeout: usize,
+    /// "How long an upstream may take to start or continue a response (in seconds, 0 = no limit)"
+    #[arg(long, default_value = "60")]
+    upstream_response_timeout: usize,
+    /// "How long a kept-alive client connection may sit idle (in seconds, 0 = no limit)"
+    #[arg(long, default_value = "60")]
+    keep_alive_timeout: usize,
+    /// "File to write a line about every request to (disabled if not given)"
+    #[arg(long)]
+    access_log: Option<PathBuf>,
+    /// "Format of the access log's lines"
+    #[arg(long, value_enum, default_value = "combined")]
+    access_log_format: AccessLogFormat,
+    /// "Size (in bytes) past which the access log is rotated (0 = never)"
+    #[arg(long, default_value = "104857600")]
+    access_log_max_size: u64,
+    /// "How many rotated access log files to keep"
+    #[arg(long, default_value = "5")]
+    access_log_max_files: usize,
+    /// "Address or CIDR range of a proxy whose X-Forwarded-For/Forwarded headers are believed"
+    #[arg(long, value_parser = forwarded::parse_trusted_proxy)]
+    trusted_proxy: Vec<IpNet>,
+    /// "Drop X-Forwarded-*/Forwarded headers sent by anyone but a --trusted-proxy"
+    #[arg(long)]
+    strip_untrusted_forwarded_headers: bool,
+    /// "Memory (in bytes) the response cache may take up (0 = no caching)"
+    #[arg(long, default_value = "0")]
+    cache_max_size: usize,
+    /// "Size (in bytes) of the largest response body that is cached"
+    #[arg(long, default_value = "1048576")]
+    cache_max_entry_size: usize,
+    /// "Compress responses with gzip or brotli for clients that accept it"
+    #[arg(long)]
+    compress: bool,
+    /// "Size (in bytes) of the smallest response body that is compressed"
+    #[arg(long, default_value = "1024")]
+    compress_min_size: u64,
+}
+
+/// Parses an --upstream-weight value of the form ADDRESS=WEIGHT.
+fn parse_upstream_weight(value: &str) -> Result<(String, u32), String> {
+    let (address, weight) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected ADDRESS=WEIGHT, got {:?}", value))?;
+    let weight = weight
+        .parse()
+        .map_err(|err| format!("invalid weight {:?}: {}", weight, err))?;
+    Ok((address.to_string(), weight))
 }
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
 /// to, what servers have failed, rate limiting counts, etc.)
-///
-/// You should add fields to this struct in later milestones.
 #[derive(Debug, Clone)]
 struct ProxyState {
+    /// IP/port we are listening on (changing it requires a restart)
+    bind: String,
+    /// IP/port we are listening for HTTPS connections on, if any (changing it requires a restart)
+    tls_bind: Option<String>,
+    /// Performs TLS handshakes on the HTTPS listener with the currently configured certificates
+    tls_acceptor: Option<tls::Acceptor>,
     /// How frequently we check whether upstream servers are alive (Milestone 4)
-    #[allow(dead_code)]
     active_health_check_interval: usize,
-    /// Where we should send requests when doing active health checks (Milestone 4)
-    #[allow(dead_code)]
-    active_health_check_path: String,
-    /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
-    #[allow(dead_code)]
-    max_requests_per_minute: usize,
-    /// Addresses of servers that we are proxying to
-    upstream_addresses: Vec<String>,
-    /// previous time request 
-    /// previouse_request: usize,
-    /// Right time request
-    right_time_request: usize,
-    /// Windows start time
-    window_start_time: usize,
+    /// How long an upstream has to answer an active health check before it is considered dead
+    active_health_check_timeout: usize,
+    /// The groups of upstreams we are proxying to, starting with the default pool. Each has its
+    /// own load balancing, health check path and rate limit.
+    pools: Vec<UpstreamPool>,
+    /// Rules deciding which pool each request goes to, tried in order
+    routes: Vec<Route>,
+    /// Opens connections to the upstreams (over TLS for `https://` ones)
+    upstream_connector: tls::Connector,
+    /// How many other upstreams an idempotent request is retried on when an upstream fails
+    max_retries: usize,
+    /// How long connections get to finish their requests once we have been asked to shut down
+    shutdown_grace_period: usize,
+    /// How long each phase of a request may take
+    timeouts: Timeouts,
+    /// Which proxies in front of us are believed about where requests came from
+    forwarding: Arc<Forwarding>,
+    /// Idle keep-alive connections to the upstreams, shared by all client connections
+    connection_pool: Arc<ConnectionPool>,
+    /// Tracks failed requests to each upstream, taking upstreams that keep failing out of rotation
+    circuit_breakers: Arc<CircuitBreakers>,
+    /// Responses to GET and HEAD requests that can be sent again without asking an upstream
+    response_cache: Arc<ResponseCache>,
+    /// When responses are compressed for clients that accept it
+    compression: Compression,
+    /// Counters exposed on the admin listener's /metrics endpoint
+    metrics: Arc<Metrics>,
+    /// Where a line about every request is written, if anywhere (changing the file requires a
+    /// restart)
+    access_log: Option<Arc<AccessLog>>,
+}
+
+impl ProxyState {
+    fn new(config: &Config, access_log: Option<Arc<AccessLog>>) -> ProxyState {
+        ProxyState {
+            bind: config.bind.clone(),
+            tls_bind: config.tls_bind.clone(),
+            tls_acceptor: config.tls.clone().map(tls::Acceptor::new),
+            pools: config
+                .pool_configs()
+                .iter()
+                .map(UpstreamPool::new)
+                .collect(),
+            routes: config.routes.clone(),
+            upstream_connector: upstream_connector(config),
+            max_retries: config.max_retries,
+            shutdown_grace_period: config.shutdown_grace_period,
+            timeouts: config.timeouts,
+            forwarding: Arc::new(config.forwarding.clone()),
+            active_health_check_interval: config.active_health_check_interval,
+            active_health_check_timeout: config.active_health_check_timeout,
+            connection_pool: Arc::new(ConnectionPool::new(
+                config.pool_max_idle,
+                config.pool_idle_timeout,
+            )),
+            circuit_breakers: Arc::new(CircuitBreakers::new(
+                config.circuit_breaker_threshold,
+                config.circuit_breaker_cool_down,
+            )),
+            response_cache: Arc::new(ResponseCache::new(
+                config.cache_max_size,
+                config.cache_max_entry_size,
+            )),
+            compression: config.compression,
+            metrics: Arc::new(Metrics::default()),
+            access_log,
+        }
+    }
+
+    /// Switches over to a reloaded configuration. Pools that are new start out with all of their
+    /// upstreams live, while existing pools keep their upstreams' health (see
+    /// UpstreamPool::apply_config). Rate limiting counts, pooled connections, circuit breaker
+    /// states and cached responses carry over. New TLS certificates are used for connections
+    /// accepted from now on.
+    fn apply_config(&mut self, config: &Config) {
+        let previously_configured = self.configured_upstreams();
+        let mut previous_pools = std::mem::take(&mut self.pools);
+        for pool_config in config.pool_configs() {
+            let pool = match previous_pools
+                .iter()
+                .position(|p| p.name == pool_config.name)
+            {
+                Some(index) => {
+                    let mut pool = previous_pools.swap_remove(index);
+                    pool.apply_config(&pool_config);
+                    pool
+                }
+                None => UpstreamPool::new(&pool_config),
+            };
+            self.pools.push(pool);
+        }
+        self.routes = config.routes.clone();
+        self.upstream_connector = upstream_connector(config);
+        if self.tls_bind.is_some() {
+            self.tls_acceptor = config.tls.clone().map(tls::Acceptor::new);
+        }
+        self.max_retries = config.max_retries;
+        self.shutdown_grace_period = config.shutdown_grace_period;
+        self.timeouts = config.timeouts;
+        self.forwarding = Arc::new(config.forwarding.clone());
+        self.active_health_check_interval = config.active_health_check_interval;
+        self.active_health_check_timeout = config.active_health_check_timeout;
+        self.connection_pool
+            .set_limits(config.pool_max_idle, config.pool_idle_timeout);
+        self.circuit_breakers.set_limits(
+            config.circuit_breaker_threshold,
+            config.circuit_breaker_cool_down,
+        );
+        self.response_cache
+            .set_limits(config.cache_max_size, config.cache_max_entry_size);
+        self.compression = config.compression;
+        if let Some(access_log) = &self.access_log {
+            access_log.set_options(
+                config.access_log_format,
+                config.access_log_max_size,
+                config.access_log_max_files,
+            );
+        }
+        self.forget_removed_upstreams(&previously_configured);
+    }
+
+    fn pool(&self, name: &str) -> Option<&UpstreamPool> {
+        self.pools.iter().find(|pool| pool.name == name)
+    }
+
+    /// Returns every upstream of every pool (once, even if it is in several pools).
+    fn configured_upstreams(&self) -> Vec<String> {
+        let mut upstreams: Vec<String> = Vec::new();
+        for pool in &self.pools {
+            for upstream in &pool.configured_upstreams {
+                if !upstreams.contains(upstream) {
+                    upstreams.push(upstream.clone());
+                }
+            }
+        }
+        upstreams
+    }
+
+    /// Closes pooled connections to, and forgets the failures of, the upstreams out of
+    /// `previously_configured` that are no longer in any pool.
+    fn forget_removed_upstreams(&self, previously_configured: &[String]) {
+        let configured = self.configured_upstreams();
+        for upstream in previously_configured {
+            if !configured.contains(upstream) {
+                self.connection_pool.clear(upstream);
+                self.circuit_breakers.reset(upstream);
+            }
+        }
+    }
+
+    /// Starts sending requests from pool `pool` to `upstream` (until the next configuration
+    /// reload). Returns None if there is no such pool, or Some(false) if `upstream` is already in
+    /// it.
+    fn add_upstream(&mut self, pool: &str, upstream: &str) -> Option<bool> {
+        let pool = self.pools.iter_mut().find(|p| p.name == pool)?;
+        Some(pool.add_upstream(upstream))
+    }
+
+    /// Stops sending requests to `upstream` from every pool it is in (until the next
+    /// configuration reload). Requests that are in flight are left to finish. Returns false if it
+    /// isn't an upstream.
+    fn remove_upstream(&mut self, upstream: &str) -> bool {
+        let mut removed = false;
+        for pool in &mut self.pools {
+            removed |= pool.remove_upstream(upstream);
+        }
+        self.forget_removed_upstreams(&[upstream.to_string()]);
+        removed
+    }
+
+    /// Marks `upstream` as draining (no new requests, though in-flight ones finish) or not, in
+    /// every pool it is in. Returns false if it isn't an upstream.
+    fn set_draining(&mut self, upstream: &str, draining: bool) -> bool {
+        let mut found = false;
+        for pool in &mut self.pools {
+            found |= pool.set_draining(upstream, draining);
+        }
+        if found && draining {
+            self.connection_pool.clear(upstream);
+        }
+        found
+    }
+}
+
+fn upstream_connector(config: &Config) -> tls::Connector {
+    tls::Connector::new(
+        config
+            .upstream_client_config
+            .clone()
+            .expect("Config::load sets up upstream TLS"),
+    )
 }
 
 #[tokio::main]
@@ -62,273 +396,1245 @@ async fn main() {
     // Initialize the logging library. You can print log messages using the `log` macros:
     // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
     // just looks a little prettier.
-    if let Err(_) = std::env::var("RUST_LOG") {
+    if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "debug");
     }
     pretty_env_logger::init();
 
     // Parse the command line arguments passed to this program
     let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
-        std::process::exit(1);
-    }
+    let config = match Config::load(&options) {
+        Ok(config) => config,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
 
     // Start listening for connections
-    let listener = match TcpListener::bind(&options.bind).await {
+    let listener = match TcpListener::bind(&config.bind).await {
         Ok(listener) => listener,
         Err(err) => {
-            log::error!("Could not bind to {}: {}", options.bind, err);
+            log::error!("Could not bind to {}: {}", config.bind, err);
             std::process::exit(1);
         }
     };
-    log::info!("Listening for requests on {}", options.bind);
+    log::info!("Listening for requests on {}", config.bind);
+    let tls_listener = match &config.tls_bind {
+        Some(tls_bind) => match TcpListener::bind(tls_bind).await {
+            Ok(listener) => {
+                log::info!("Listening for HTTPS requests on {}", tls_bind);
+                Some(listener)
+            }
+            Err(err) => {
+                log::error!("Could not bind to {}: {}", tls_bind, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
 
-    // Handle incoming connections
-    let state = ProxyState {
-        upstream_addresses: options.upstream,
-        active_health_check_interval: options.active_health_check_interval,
-        active_health_check_path: options.active_health_check_path,
-        max_requests_per_minute: options.max_requests_per_minute,
-        // previouse_request: 0,
-        right_time_request: 0,
-        window_start_time: Instant::now().elapsed().as_secs() as usize,
+    let access_log = match &config.access_log {
+        Some(path) => match AccessLog::open(
+            path,
+            config.access_log_format,
+            config.access_log_max_size,
+            config.access_log_max_files,
+        ) {
+            Ok(access_log) => Some(Arc::new(access_log)),
+            Err(err) => {
+                log::error!("Could not open access log {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
     };
-    
-    let mut start_time = Instant::now(); // Get the start time
-    let active_health_check_interval = state.active_health_check_interval;
-    let upstreams = state.upstream_addresses.clone();
-    let state = Arc::new(RwLock::new(state));
-    loop {
-        let duration = start_time.elapsed();
-        if duration.as_secs() >= active_health_check_interval as u64 {
-            health_check(&state, &upstreams).await;
-            start_time = Instant::now();
-        }
 
-        let (stream, _) = listener.accept().await.unwrap();
-        let state_clone = state.clone();
-        tokio::spawn(async move {
-            handle_connection(stream, &state_clone).await;
-        });
-    }
-}
+    // Handle incoming connections
+    let state = ProxyState::new(&config, access_log);
+    pool::spawn_eviction_task(state.connection_pool.clone());
+    let state = Arc::new(RwLock::new(state));
+    health::spawn_active_health_checks(state.clone());
+    config::spawn_reload_on_sighup(options, state.clone());
 
-async fn health_check(state: &RwLock<ProxyState>, upstreams: &Vec<String>) {
-    let path;
-    {
-        let state_read = state.read().await;
-        path = state_read.active_health_check_path.clone();
+    if let Some(admin_bind) = &config.admin_bind {
+        let admin_listener = match TcpListener::bind(admin_bind).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                log::error!("Could not bind admin listener to {}: {}", admin_bind, err);
+                std::process::exit(1);
+            }
+        };
+        log::info!("Serving admin endpoints on {}", admin_bind);
+        tokio::spawn(admin::serve(admin_listener, state.clone()));
     }
 
-    for upstream in upstreams.iter() {
-        log::info!("Performing active health check on {}", upstream);
-
-        let request = http::Request::builder()
-        .method(http::Method::GET)
-        .uri(&path)
-        .header("Host", upstream)
-        .body(Vec::new())
-        .unwrap();
-
-        let mut upstream_stream = match TcpStream::connect(upstream).await {
-            Ok(stream) => {stream},
+    let (start_shutdown, shutdown) = ShutdownSignal::new();
+    let mut connections = JoinSet::new();
+    let mut num_connections = 0_usize;
+    let shutdown_requested = shutdown::wait_for_signal();
+    tokio::pin!(shutdown_requested);
+    let signal_name = loop {
+        let (accepted, is_tls) = tokio::select! {
+            accepted = listener.accept() => (accepted, false),
+            accepted = accept_optional(tls_listener.as_ref()) => (accepted, true),
+            // Reap finished connection tasks so that they don't pile up
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            signal_name = &mut shutdown_requested => break signal_name,
+        };
+        let stream = match accepted {
+            Ok((stream, _)) => stream,
             Err(err) => {
-                {
-                    let mut state_write = state.write().await;
-                    state_write.upstream_addresses.retain(|addr| addr != upstream);
-                    log::info!("Tcp connect failed, Drop Upstream addresses: {:?} for {}", upstream, err);
-                }
+                log::warn!("Could not accept connection: {}", err);
                 continue;
             }
         };
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_stream).await {
-            log::warn!("Failed to write request to upstream: {}", error);
-            continue;
-        }else {
-            log::info!("Successfully connected to upstream: {}", upstream);
-            {
-                if let Ok(response) = response::read_from_stream(&mut upstream_stream, &http::Method::GET).await {
-                    if response.status().is_success() {
-                        log::info!("Upstream {} returned 200 OK", upstream);
-                        let mut state_write = state.write().await;
-                        if !state_write.upstream_addresses.contains(&upstream.to_string()) {
-                            state_write.upstream_addresses.push(upstream.to_string());
-                            log::info!("Restored upstream: {}", upstream);
-                            continue;
-                        }
-                    } else {
-                        log::warn!("Upstream {} returned non-200 status: {}", upstream, response.status());
-                        let mut state_write = state.write().await;
-                        state_write.upstream_addresses.retain(|addr| addr != upstream);
-                        log::info!("Removed upstream {} from active addresses due to non-200 response", upstream);
-                        continue;
-                    }
-                }else {
-                    let mut state_write = state.write().await;
-                    state_write.upstream_addresses.retain(|addr| addr != upstream);
-                    log::info!("Removed upstream {} from active addresses due to can't get response", upstream);
-                    continue;
+        num_connections += 1;
+        let state_clone = state.clone();
+        let shutdown = shutdown.clone();
+        connections.spawn(async move {
+            let client_conn = if is_tls {
+                match tls_handshake(stream, &state_clone).await {
+                    Some(client_conn) => client_conn,
+                    None => return,
                 }
-            }
-        }
+            } else {
+                ClientStream::Plain(stream)
+            };
+            serve_client(client_conn, state_clone, shutdown).await;
+        });
+    };
+
+    // Stop accepting connections, and let the open ones finish what they are doing
+    drop(listener);
+    drop(tls_listener);
+    let grace_period = Duration::from_secs(state.read().await.shutdown_grace_period as u64);
+    log::info!(
+        "Received {}, shutting down. Waiting up to {:?} for {} open connections",
+        signal_name,
+        grace_period,
+        connections.len()
+    );
+    let _ = start_shutdown.send(true);
+    let num_open = connections.len();
+    let drained = tokio::time::timeout(grace_period, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    let num_cut_off = if drained.is_ok() {
+        0
+    } else {
+        let num_cut_off = connections.len();
+        connections.shutdown().await;
+        num_cut_off
+    };
+    log::info!(
+        "Shutdown complete. Served {} requests over {} connections; {} of the {} connections open \
+        at shutdown finished cleanly and {} were cut off after the grace period",
+        state.read().await.metrics.total_requests(),
+        num_connections,
+        num_open - num_cut_off,
+        num_open,
+        num_cut_off
+    );
+}
 
+/// Accepts a connection on `listener`, or never returns if there is no listener.
+async fn accept_optional(
+    listener: Option<&TcpListener>,
+) -> std::io::Result<(TcpStream, std::net::SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
     }
 }
-async fn connect_to_upstream(state: &RwLock<ProxyState>) -> Result<TcpStream, std::io::Error> {
-    let mut rng = rand::rngs::StdRng::from_entropy();
+
+/// Performs the TLS handshake with a client that connected to the HTTPS listener. Returns None
+/// (after logging why) if the handshake fails.
+async fn tls_handshake(stream: TcpStream, state: &RwLock<ProxyState>) -> Option<ClientStream> {
+    let (acceptor, handshake_timeout) = {
+        let state_read = state.read().await;
+        (
+            state_read.tls_acceptor.clone()?,
+            state_read.timeouts.client_header,
+        )
+    };
+    let client_ip = stream.peer_addr().ok()?.ip();
+    match acceptor.accept(stream, handshake_timeout).await {
+        Ok(client_conn) => Some(client_conn),
+        Err(err) => {
+            log::info!("TLS handshake with {} failed: {}", client_ip, err);
+            None
+        }
+    }
+}
+
+/// Picks an upstream out of `pool` for a request from `client_ip` using the pool's strategy and
+/// connects to it (reusing an idle pooled connection if there is one), moving on to another
+/// upstream if the connection is refused. Upstreams in `exclude` (ones this request has already
+/// failed on) and upstreams whose circuit is open are passed over. Returns the connection along
+/// with the address of the upstream it goes to. If no upstream could be connected to, the error
+/// from the last one that was tried is returned.
+async fn connect_to_upstream(
+    state: &RwLock<ProxyState>,
+    pool: &str,
+    client_ip: IpAddr,
+    exclude: &[String],
+) -> Result<(UpstreamStream, String), std::io::Error> {
+    let mut exclude = exclude.to_vec();
+    let mut last_error = None;
     loop {
         let upstream_ip;
+        let connection_pool;
+        let connector;
+        let circuit_breakers;
+        let metrics;
+        let connect_timeout;
         {
             let state_read = state.read().await;
-            log::info!("{:?}", state_read); // Printing the state for debugging
-
-            if state_read.upstream_addresses.is_empty() {
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, "No upstream addresses available"));
-            }
-
-            let upstream_idx = rng.gen_range(0..state_read.upstream_addresses.len());
-            upstream_ip = state_read.upstream_addresses[upstream_idx].clone();
+            // The pool may have been removed by a configuration reload since the request was routed
+            let selected = state_read.pool(pool).and_then(|pool| {
+                let candidates: Vec<String> = pool
+                    .upstream_addresses
+                    .iter()
+                    .filter(|upstream| {
+                        !exclude.contains(upstream)
+                            && !pool.draining_upstreams.contains(upstream)
+                            && state_read.circuit_breakers.is_available(upstream)
+                    })
+                    .cloned()
+                    .collect();
+                pool.strategy.select(&candidates, client_ip)
+            });
+            upstream_ip = match selected {
+                Some(upstream_ip) => upstream_ip,
+                None => {
+                    return Err(last_error.unwrap_or_else(|| {
+                        std::io::Error::other("No upstream addresses available")
+                    }))
+                }
+            };
+            log::debug!("Pool {} chose upstream {}", pool, upstream_ip);
+            connection_pool = state_read.connection_pool.clone();
+            connector = state_read.upstream_connector.clone();
+            circuit_breakers = state_read.circuit_breakers.clone();
+            metrics = state_read.metrics.clone();
+            connect_timeout = state_read.timeouts.upstream_connect;
         }
-        // let mut state_write = state.write().await;
-        let connection_result = TcpStream::connect(&upstream_ip).await;
-
-        if let Err(err) = connection_result {
-            log::warn!("Failed to connect to upstream {}: {}", upstream_ip, err);
-            // If connection failed, write lock the state and remove the failed upstream IP
-            let mut state_write = state.write().await;
-            state_write.upstream_addresses.retain(|ip| ip != &upstream_ip);
-            log::warn!("Removed failed upstream: {}", upstream_ip);
-            log::info!("{:?}", state_write);
-            // Return the original error
+        // Another request may have claimed the trial request of a half-open circuit in the meantime
+        if !circuit_breakers.try_acquire(&upstream_ip) {
+            exclude.push(upstream_ip);
             continue;
-        }else {
-            return connection_result;
+        }
+        let connection = open_upstream_connection(
+            &connection_pool,
+            &connector,
+            &metrics,
+            &upstream_ip,
+            connect_timeout,
+        );
+        match connection.await {
+            Ok(stream) => return Ok((stream, upstream_ip)),
+            Err(err) => {
+                log::warn!("Failed to connect to upstream {}: {}", upstream_ip, err);
+                // Pooled connections to it are likely dead too. Try the next upstream.
+                circuit_breakers.record_failure(&upstream_ip);
+                connection_pool.clear(&upstream_ip);
+                exclude.push(upstream_ip);
+                last_error = Some(err);
+            }
         }
     }
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
+/// Borrows an idle connection to `upstream` from the pool, or opens a new one if there is none.
+/// Connecting fails with ErrorKind::TimedOut if the upstream doesn't accept the connection (and
+/// finish the TLS handshake, for `https://` upstreams) within `connect_timeout` seconds.
+async fn open_upstream_connection(
+    connection_pool: &ConnectionPool,
+    connector: &tls::Connector,
+    metrics: &Metrics,
+    upstream: &str,
+    connect_timeout: usize,
+) -> Result<UpstreamStream, std::io::Error> {
+    if let Some(stream) = connection_pool.take(upstream) {
+        log::debug!("Reusing pooled connection to {}", upstream);
+        metrics.record_connection(upstream, true);
+        return Ok(stream);
+    }
+    let stream = timeout::timeout(connect_timeout, connector.connect(upstream))
+        .await
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("no connection within {}s", connect_timeout),
+            )
+        })??;
+    metrics.record_connection(upstream, false);
+    Ok(stream)
+}
+
+async fn send_response(client_conn: &mut ClientStream, response: &http::Response<Vec<u8>>) {
     let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("{} <- {}", client_ip, response::format_response_line(&response));
-    if let Err(error) = response::write_to_stream(&response, client_conn).await {
+    log::info!(
+        "{} <- {}",
+        client_ip,
+        response::format_response_line(response)
+    );
+    if let Err(error) = response::write_to_stream(response, client_conn).await {
         log::warn!("Failed to send response to client: {}", error);
-        return;
     }
 }
 
-async fn handle_connection(mut client_conn: TcpStream, state: &RwLock<ProxyState>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("Connection received from {}", client_ip);
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(&state).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+/// The body of a request that is being proxied
+enum RequestBody {
+    /// Still to be read from the client. It is streamed to the upstream as it arrives, so it can
+    /// only be sent once.
+    Unread {
+        already_read: Vec<u8>,
+        framing: body::Framing,
+    },
+    /// Has been streamed to an upstream. `pipelined` holds whatever the client sent after it,
+    /// which is the start of its next request.
+    Sent { pipelined: Vec<u8> },
+    /// Was read into memory up front so that the request can be sent again if an upstream fails
+    Buffered(Vec<u8>),
+}
+
+impl RequestBody {
+    /// Returns true if the body has been entirely read from the client, so that the client
+    /// connection is ready for the next request whatever happens to this one.
+    fn is_consumed(&self) -> bool {
+        !matches!(self, RequestBody::Unread { .. })
+    }
+}
+
+/// Why proxying a request to an upstream failed
+enum ProxyError {
+    /// The upstream failed before we started sending its response to the client, so the request
+    /// may be retried on another upstream (if it is safe to send again). If it isn't, the client is
+    /// sent the status (502 Bad Gateway, or 504 Gateway Timeout if the upstream was too slow).
+    Upstream(http::StatusCode, String),
+    /// The exchange can't be salvaged. If there is a status, it is sent to the client before the
+    /// connection is closed.
+    Fatal(Option<http::StatusCode>),
+}
+
+/// Serves a client over HTTP/2 if it asked for that (through ALPN, or by opening a plaintext
+/// connection with the HTTP/2 preface), or over HTTP/1.x otherwise.
+async fn serve_client(
+    mut client_conn: ClientStream,
+    state: Arc<RwLock<ProxyState>>,
+    shutdown: ShutdownSignal,
+) {
+    // The header timeout covers looking for the HTTP/2 preface and reading the first request
+    let connected = tokio::time::Instant::now();
+    let timeouts = state.read().await.timeouts;
+    let mut already_read = Vec::new();
+    let is_http2 = if client_conn.is_tls() {
+        client_conn.alpn_protocol() == Some(http2::ALPN_PROTOCOL)
+    } else {
+        let preface = http2::read_preface(&mut client_conn, &mut already_read);
+        match timeout::timeout_from(connected, timeouts.client_header, preface).await {
+            Some(Ok(is_http2)) => is_http2,
+            Some(Err(error)) => {
+                log::info!("Error reading from client stream: {}", error);
+                return;
+            }
+            None => {
+                log::info!("Client sent nothing within {}s", timeouts.client_header);
+                let response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+                send_response(&mut client_conn, &response).await;
+                return;
+            }
         }
     };
-    let upstream_ip = client_conn.peer_addr().unwrap().ip().to_string();
+    if !is_http2 {
+        handle_connection(client_conn, &state, shutdown, connected, already_read).await;
+        return;
+    }
+    let preface_read = !already_read.is_empty();
+    let metrics = state.read().await.metrics.clone();
+    let _active_connection = metrics.connection_opened();
+    let handle_stream = |stream| {
+        let state = state.clone();
+        let shutdown = shutdown.clone();
+        async move {
+            let started = tokio::time::Instant::now();
+            handle_connection(stream, &state, shutdown, started, Vec::new()).await
+        }
+    };
+    http2::serve(
+        client_conn,
+        preface_read,
+        timeouts.keep_alive_idle,
+        shutdown.clone(),
+        handle_stream,
+    )
+    .await;
+}
+
+/// Serves HTTP/1.x requests from a client until it hangs up. The time the client has to send the
+/// headers of its first request counts from `connected`, and `already_read` holds what was read
+/// of the connection before it was handed over.
+async fn handle_connection(
+    mut client_conn: ClientStream,
+    state: &RwLock<ProxyState>,
+    mut shutdown: ShutdownSignal,
+    connected: tokio::time::Instant,
+    already_read: Vec<u8>,
+) {
+    let peer_addr = client_conn.peer_addr().unwrap().ip();
+    let client_ip = peer_addr.to_string();
+    log::info!("Connection received from {}", client_ip);
+    let metrics = state.read().await.metrics.clone();
+    // The HTTP/2 connection a stream belongs to is counted instead of the stream
+    let _active_connection = (!client_conn.is_http2_stream()).then(|| metrics.connection_opened());
 
     // The client may now send us one or more requests. Keep trying to read requests until the
     // client hangs up or we get an error.
+    let mut first_request = Some(connected);
+    // Bytes of the next request that were read along with the previous one
+    let mut pipelined = already_read;
     loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
-            Ok(request) => {
-                let is_rate_limit = rate_limit(&state).await;
-                if is_rate_limit {
-                    let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-                    send_response(&mut client_conn, &response).await;
-                    continue;
-                }else { // update the request count
-                    let current_time = Instant::now().elapsed().as_secs() as usize;
-                    let window_start_time;
-                    {
-                        window_start_time = state.read().await.window_start_time;
-                    }
-                    if current_time - window_start_time >= 60 {
-                        if current_time - window_start_time >= 60 {
-                            state.write().await.window_start_time = current_time;
-                            state.write().await.right_time_request = 0;
-                        }
-                    }
-                }
-                    state.write().await.right_time_request += 1;
-                    request
-            },
+        // Read a request from the client. Only the request line and headers are read here; the body
+        // is streamed to the upstream below. If we are shutting down, close the connection instead
+        // of waiting for another request.
+        if shutdown.is_shutting_down() {
+            log::debug!("Shutting down. Closing connection from {}", client_ip);
+            return;
+        }
+        let timeouts = state.read().await.timeouts;
+        let read_result = tokio::select! {
+            read_result = read_next_request(
+                &mut client_conn,
+                &timeouts,
+                first_request.take(),
+                std::mem::take(&mut pipelined),
+            ) => {
+                read_result
+            }
+            _ = shutdown.wait() => {
+                log::debug!("Shutting down. Closing idle connection from {}", client_ip);
+                let _ = client_conn.shutdown().await;
+                return;
+            }
+        };
+        let mut request = match read_result {
+            Some(Ok(request)) => request,
+            None => {
+                log::debug!("Connection from {} went idle. Shutting it down", client_ip);
+                let _ = client_conn.shutdown().await;
+                return;
+            }
             // Handle case where client closed connection and is no longer sending requests
-            Err(request::Error::IncompleteRequest(0)) => {
+            Some(Err(request::Error::IncompleteRequest(0))) => {
                 log::debug!("Client finished sending requests. Shutting down connection");
+                // Lets TLS clients know the connection was closed on purpose (close_notify)
+                let _ = client_conn.shutdown().await;
+                return;
+            }
+            Some(Err(request::Error::ConnectionError(io_err)))
+                if io_err.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                log::info!(
+                    "Client {} was too slow sending a request: {}",
+                    client_ip,
+                    io_err
+                );
+                let response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+                send_response(&mut client_conn, &response).await;
                 return;
             }
             // Handle I/O error in reading from the client
-            Err(request::Error::ConnectionError(io_err)) => {
+            Some(Err(request::Error::ConnectionError(io_err))) => {
                 log::info!("Error reading request from client stream: {}", io_err);
                 return;
             }
+            Some(Err(error)) => {
+                log::debug!("Error parsing request: {}", error);
+                let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+                send_response(&mut client_conn, &response).await;
+                // We can't tell where the next request would start, so give up on the connection
+                return;
+            }
+        };
+        let (forwarding, access_log) = {
+            let state_read = state.read().await;
+            (state_read.forwarding.clone(), state_read.access_log.clone())
+        };
+        // Where the request really came from, if a trusted proxy passed it on to us
+        let client_addr = forwarding.client_ip(peer_addr, &request);
+        let keep_alive = hop_by_hop::client_wants_keep_alive(&request);
+        // Written to the access log once we are done with the request, however that happens
+        let mut log_entry = access_log::Entry::new(access_log, client_addr, &request);
+        let request_framing = match request::body_framing(&mut request) {
+            Ok(framing) => framing,
             Err(error) => {
-                log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
-                    request::Error::IncompleteRequest(_)
-                    | request::Error::MalformedRequest(_)
-                    | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
-                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
-                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
-                });
+                log::debug!("Error parsing request: {}", error);
+                let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+                log_entry.record_response(&response);
                 send_response(&mut client_conn, &response).await;
-                continue;
+                return;
+            }
+        };
+        // Body bytes that arrived along with the headers
+        let mut request_body_start = std::mem::take(request.body_mut());
+        if request_framing == body::Framing::Chunked
+            && chunked::has_malformed_start(&request_body_start)
+        {
+            log::debug!("Client sent a malformed chunked body");
+            let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+            log_entry.record_response(&response);
+            send_response(&mut client_conn, &response).await;
+            return;
+        }
+
+        // Rate limits are per pool, so the request has to be routed first
+        let route = routing::select_route(&state.read().await.routes, &request).cloned();
+        let pool = route
+            .as_ref()
+            .map_or(routing::DEFAULT_POOL, |route| route.pool.as_str())
+            .to_string();
+        let header_rewriter = HeaderRewriter::new(route.as_ref(), client_addr, &request);
+        log::debug!(
+            "Routing {} to pool {}",
+            request::format_request_line(&request),
+            pool
+        );
+
+        if let Err(retry_after) = rate_limit(state, &pool, client_addr).await {
+            // Read and throw away the body so that we're ready for the client's next request
+            let mut discard = tokio::io::sink();
+            let mut body_reader = TimeoutReader::new(&mut client_conn, timeouts.client_body);
+            let discarded = body::copy_body(
+                &mut body_reader,
+                &mut request_body_start,
+                request_framing,
+                &mut discard,
+            )
+            .await;
+            match discarded {
+                Ok(bytes_in) => log_entry.bytes_in = bytes_in,
+                Err(_) => return,
+            }
+            pipelined = request_body_start;
+            let mut response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
+            // Round up so that clients never retry before the window has moved on
+            let retry_after_secs = retry_after.as_secs() + 1;
+            response
+                .headers_mut()
+                .insert("Retry-After", http::HeaderValue::from(retry_after_secs));
+            if !keep_alive {
+                response.headers_mut().insert(
+                    http::header::CONNECTION,
+                    http::HeaderValue::from_static("close"),
+                );
+            }
+            log_entry.record_response(&response);
+            send_response(&mut client_conn, &response).await;
+            if !keep_alive {
+                let _ = client_conn.shutdown().await;
+                return;
+            }
+            continue;
+        }
+
+        // Let the upstream know who the client is and what it asked for. (We're the ones
+        // connecting directly to the upstream server, so without these headers, the upstream server
+        // would only know our IP, not the client's.)
+        forwarding.add_headers(&mut request, peer_addr, client_conn.is_tls());
+        hop_by_hop::prepare_request(&mut request);
+
+        // Idempotent requests with small enough bodies are read into memory up front, so that
+        // they can be sent again if an upstream fails. Everything else is streamed.
+        let replayable = request::is_idempotent(&request)
+            && match request_framing {
+                body::Framing::Empty => true,
+                body::Framing::Length(length) => length <= request::MAX_REPLAYABLE_BODY_SIZE,
+                _ => false,
+            };
+        let mut request_body = if replayable {
+            let mut buffered = Vec::new();
+            let mut body_reader = TimeoutReader::new(&mut client_conn, timeouts.client_body);
+            match body::copy_body(
+                &mut body_reader,
+                &mut request_body_start,
+                request_framing,
+                &mut buffered,
+            )
+            .await
+            {
+                Ok(bytes_in) => log_entry.bytes_in = bytes_in,
+                Err(body::Error::Read(error)) if error.kind() == std::io::ErrorKind::TimedOut => {
+                    log::info!("Client {} was too slow sending a request body", client_ip);
+                    let response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+                    log_entry.record_response(&response);
+                    send_response(&mut client_conn, &response).await;
+                    return;
+                }
+                Err(error) => {
+                    log::info!("Error reading request body from client: {}", error);
+                    return;
+                }
+            }
+            pipelined = request_body_start;
+            RequestBody::Buffered(buffered)
+        } else {
+            RequestBody::Unread {
+                already_read: request_body_start,
+                framing: request_framing,
+            }
+        };
+
+        // Fresh responses to GET and HEAD requests are sent straight out of the cache. (A request
+        // whose body hasn't been read yet has to go upstream, since its body has to go somewhere.)
+        let (response_cache, compression) = {
+            let state_read = state.read().await;
+            (state_read.response_cache.clone(), state_read.compression)
+        };
+        let client_reusable = match response_cache.lookup(&mut request, &pool) {
+            Some(cache::Lookup::Hit(response)) if request_body.is_consumed() => {
+                log::debug!(
+                    "Answering {} from the cache",
+                    request::format_request_line(&request)
+                );
+                send_cached_response(
+                    &mut client_conn,
+                    &request,
+                    response,
+                    &compression,
+                    &header_rewriter,
+                    keep_alive,
+                    &mut log_entry,
+                    &shutdown,
+                )
+                .await
+            }
+            lookup => {
+                let pending = match lookup {
+                    Some(cache::Lookup::Miss(pending)) => Some(pending),
+                    _ => None,
+                };
+                // The cache goes by what the client asked for, so the path the upstream expects
+                // is only worked out now
+                if let Some(route) = &route {
+                    rewrite::rewrite_uri(route, &mut request);
+                }
+                proxy_request(
+                    &mut client_conn,
+                    state,
+                    &pool,
+                    client_addr,
+                    &request,
+                    &mut request_body,
+                    pending.as_deref(),
+                    &header_rewriter,
+                    keep_alive,
+                    &mut log_entry,
+                    &timeouts,
+                    &shutdown,
+                )
+                .await
             }
         };
+        if let RequestBody::Sent { pipelined: rest } = &mut request_body {
+            pipelined = std::mem::take(rest);
+        }
+        if !client_reusable {
+            return;
+        }
+        if !keep_alive {
+            log::debug!("Client {} asked for its connection to be closed", client_ip);
+            let _ = client_conn.shutdown().await;
+            return;
+        }
+    }
+}
+
+/// Reads the headers of the client's next request. For the first request, `connected` is when the
+/// client connected. Before any other request, waits up to the keep-alive timeout for the client
+/// to start sending one, returning None if it doesn't. Taking longer than the header timeout
+/// (counted from `connected`, or from when the request starts arriving) to send the headers is
+/// reported as a TimedOut ConnectionError. `pipelined` holds the start of the request if it
+/// arrived along with the previous one, in which case there is no waiting for the client to start.
+async fn read_next_request(
+    client_conn: &mut ClientStream,
+    timeouts: &Timeouts,
+    connected: Option<tokio::time::Instant>,
+    mut pipelined: Vec<u8>,
+) -> Option<Result<http::Request<Vec<u8>>, request::Error>> {
+    // A stream of an HTTP/2 connection carries a single request, so there is nothing to wait for
+    if connected.is_none() && pipelined.is_empty() && !client_conn.is_http2_stream() {
+        // The first byte is read rather than peeked at on the socket, since a TLS connection may
+        // already have the request decrypted and buffered with nothing left on the socket. If the
+        // client hung up, read_headers() runs into that as well.
+        let mut first_byte = [0_u8; 1];
+        let read = client_conn.read(&mut first_byte);
+        match timeout::timeout(timeouts.keep_alive_idle, read).await? {
+            Ok(n) => pipelined.extend_from_slice(&first_byte[..n]),
+            Err(error) => return Some(Err(request::Error::ConnectionError(error))),
+        }
+    }
+    let started = connected.unwrap_or_else(tokio::time::Instant::now);
+    let headers = request::read_headers(client_conn, pipelined);
+    let read_result = timeout::timeout_from(started, timeouts.client_header, headers)
+        .await
+        .unwrap_or_else(|| {
+            Err(request::Error::ConnectionError(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("no complete headers within {}s", timeouts.client_header),
+            )))
+        });
+    Some(read_result)
+}
+
+/// Forwards `request` to an upstream of `pool` chosen by the pool's load-balancing strategy and
+/// passes the response back to the client. If an upstream fails before responding and the request
+/// body is buffered (i.e. the request is idempotent), the request is retried on other upstreams,
+/// up to max_retries times. If the request is cacheable (`cache` is given), the response is stored
+/// in the cache as it is passed back. The header rules of the request's route are applied on the
+/// way to the upstream and back. Returns false if the client connection can't be used for any more
+/// requests.
+#[allow(clippy::too_many_arguments)]
+async fn proxy_request(
+    client_conn: &mut ClientStream,
+    state: &RwLock<ProxyState>,
+    pool: &str,
+    client_addr: IpAddr,
+    request: &http::Request<Vec<u8>>,
+    request_body: &mut RequestBody,
+    cache: Option<&cache::Pending>,
+    header_rewriter: &HeaderRewriter,
+    keep_alive: bool,
+    log_entry: &mut access_log::Entry,
+    timeouts: &Timeouts,
+    shutdown: &ShutdownSignal,
+) -> bool {
+    let client_ip = client_addr.to_string();
+    let (strategy, connection_pool, circuit_breakers, metrics, max_retries, compression) = {
+        let state_read = state.read().await;
+        (
+            state_read.pool(pool).map(|pool| pool.strategy.clone()),
+            state_read.connection_pool.clone(),
+            state_read.circuit_breakers.clone(),
+            state_read.metrics.clone(),
+            state_read.max_retries,
+            state_read.compression,
+        )
+    };
+    let mut failed_upstreams = Vec::new();
+    // What to tell the client if there turns out to be no upstream left to retry on
+    let mut last_failure = http::StatusCode::BAD_GATEWAY;
+    loop {
+        // Open a connection to a destination server chosen by the load-balancing strategy
+        let (mut upstream_conn, upstream_ip) =
+            match connect_to_upstream(state, pool, client_addr, &failed_upstreams).await {
+                Ok(connection) => connection,
+                Err(error) => {
+                    log::error!("Could not connect to an upstream: {}", error);
+                    let status = if error.kind() == std::io::ErrorKind::TimedOut {
+                        http::StatusCode::GATEWAY_TIMEOUT
+                    } else {
+                        last_failure
+                    };
+                    let response = response::make_http_error(status);
+                    log_entry.record_response(&response);
+                    send_response(client_conn, &response).await;
+                    return request_body.is_consumed();
+                }
+            };
+        let _request_guard = strategy
+            .clone()
+            .map(|strategy| RequestGuard::new(strategy, &upstream_ip));
         log::info!(
             "{} -> {}: {}",
             client_ip,
             upstream_ip,
-            request::format_request_line(&request)
+            request::format_request_line(request)
         );
 
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+        metrics.request_started(&upstream_ip);
+        log_entry.upstream = Some(upstream_ip.clone());
+        let started = Instant::now();
+        let result = exchange(
+            client_conn,
+            &mut upstream_conn,
+            &upstream_ip,
+            request,
+            request_body,
+            cache,
+            &compression,
+            header_rewriter,
+            keep_alive,
+            log_entry,
+            timeouts,
+            shutdown,
+        )
+        .await;
+        let status = result.as_ref().ok().map(|exchanged| exchanged.status);
+        metrics.request_finished(&upstream_ip, status, started.elapsed());
+        log_entry.upstream_latency = Some(started.elapsed());
+        match result {
+            Ok(Exchange { status, .. }) if status.is_server_error() => {
+                circuit_breakers.record_failure(&upstream_ip)
+            }
+            Ok(_) => circuit_breakers.record_success(&upstream_ip),
+            Err(ProxyError::Upstream(..)) => circuit_breakers.record_failure(&upstream_ip),
+            // Most likely the client's doing, so it says nothing about the upstream
+            Err(ProxyError::Fatal(_)) => {}
+        }
+        match result {
+            Ok(exchanged) => {
+                // Hand the upstream connection back for the next request (from any client) to use
+                if exchanged.upstream_reusable {
+                    connection_pool.put(&upstream_ip, upstream_conn);
+                }
+                return exchanged.client_reusable;
+            }
+            Err(ProxyError::Upstream(status, error)) => {
+                log::error!("Upstream {} failed: {}", upstream_ip, error);
+                failed_upstreams.push(upstream_ip);
+                last_failure = status;
+                if matches!(*request_body, RequestBody::Buffered(_))
+                    && failed_upstreams.len() <= max_retries
+                {
+                    log::info!(
+                        "Retrying request on another upstream ({}/{})",
+                        failed_upstreams.len(),
+                        max_retries
+                    );
+                    metrics.record_retry();
+                    continue;
+                }
+                let response = response::make_http_error(status);
+                log_entry.record_response(&response);
+                send_response(client_conn, &response).await;
+                return request_body.is_consumed();
+            }
+            Err(ProxyError::Fatal(status)) => {
+                if let Some(status) = status {
+                    let response = response::make_http_error(status);
+                    log_entry.record_response(&response);
+                    send_response(client_conn, &response).await;
+                }
+                return false;
+            }
+        }
+    }
+}
+
+/// How a request that got a response from an upstream went
+struct Exchange {
+    status: http::StatusCode,
+    /// Whether the upstream connection can carry another request
+    upstream_reusable: bool,
+    /// Whether the client connection can carry another request
+    client_reusable: bool,
+}
 
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
-            log::error!("Failed to send request to upstream {}: {}", upstream_ip, error);
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+/// Sends `request` over `upstream_conn` and streams the response back to the client. If
+/// `keep_alive` is false, the client is told that its connection will be closed after the
+/// response. If the upstream confirms that the response in the cache that `cache` is revalidating
+/// is still good, that response is sent instead. Responses are compressed on the way if
+/// `compression` says so, and `header_rewriter` gets to change the headers going both ways.
+#[allow(clippy::too_many_arguments)]
+async fn exchange(
+    client_conn: &mut ClientStream,
+    upstream_conn: &mut UpstreamStream,
+    upstream_ip: &str,
+    request: &http::Request<Vec<u8>>,
+    request_body: &mut RequestBody,
+    cache: Option<&cache::Pending>,
+    compression: &Compression,
+    header_rewriter: &HeaderRewriter,
+    keep_alive: bool,
+    log_entry: &mut access_log::Entry,
+    timeouts: &Timeouts,
+    shutdown: &ShutdownSignal,
+) -> Result<Exchange, ProxyError> {
+    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+
+    // Forward the request to the server, streaming the body through as it arrives
+    let rewritten = header_rewriter.rewrite_request(request, upstream_ip);
+    request::write_head(rewritten.as_ref().unwrap_or(request), upstream_conn)
+        .await
+        .map_err(|error| {
+            ProxyError::Upstream(
+                http::StatusCode::BAD_GATEWAY,
+                format!("could not send request: {}", error),
+            )
+        })?;
+    let sent = RequestBody::Sent {
+        pipelined: Vec::new(),
+    };
+    match std::mem::replace(request_body, sent) {
+        RequestBody::Unread {
+            mut already_read,
+            framing,
+        } => {
+            let mut body_reader = TimeoutReader::new(&mut *client_conn, timeouts.client_body);
+            match body::copy_body(&mut body_reader, &mut already_read, framing, upstream_conn).await
+            {
+                Ok(bytes_in) => {
+                    log::debug!("Forwarded request to server");
+                    log_entry.bytes_in = bytes_in;
+                    *request_body = RequestBody::Sent {
+                        pipelined: already_read,
+                    };
+                }
+                Err(body::Error::Write(error)) => {
+                    // Part of the body has been read from the client, so it can't be sent anywhere
+                    // else
+                    log::error!(
+                        "Failed to send request to upstream {}: {}",
+                        upstream_ip,
+                        error
+                    );
+                    return Err(ProxyError::Fatal(Some(http::StatusCode::BAD_GATEWAY)));
+                }
+                Err(body::Error::MalformedChunkedBody) => {
+                    log::debug!("Client sent a malformed chunked body");
+                    return Err(ProxyError::Fatal(Some(http::StatusCode::BAD_REQUEST)));
+                }
+                Err(body::Error::Read(error)) if error.kind() == std::io::ErrorKind::TimedOut => {
+                    log::info!("Client {} was too slow sending a request body", client_ip);
+                    return Err(ProxyError::Fatal(Some(http::StatusCode::REQUEST_TIMEOUT)));
+                }
+                Err(error) => {
+                    log::info!("Error reading request body from client: {}", error);
+                    return Err(ProxyError::Fatal(None));
+                }
+            }
+        }
+        RequestBody::Buffered(buffered) => {
+            let sent = async {
+                upstream_conn.write_all(&buffered).await?;
+                upstream_conn.flush().await
+            }
+            .await;
+            *request_body = RequestBody::Buffered(buffered);
+            sent.map_err(|error| {
+                ProxyError::Upstream(
+                    http::StatusCode::BAD_GATEWAY,
+                    format!("could not send request body: {}", error),
+                )
+            })?;
+            log::debug!("Forwarded request to server");
         }
-        log::debug!("Forwarded request to server");
+        RequestBody::Sent { .. } => unreachable!("request body sent twice"),
+    }
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await {
-            Ok(response) => response,
+    // Read the server's response headers, passing along any interim (1xx) responses
+    let mut sent_interim_response = false;
+    let (mut response, response_framing) = loop {
+        let head = match timeout::timeout(
+            timeouts.upstream_response,
+            response::read_headers(upstream_conn),
+        )
+        .await
+        {
+            Some(Ok(mut response)) => response::body_framing(&mut response, request.method())
+                .map(|framing| (response, framing)),
+            Some(Err(error)) => Err(error),
+            None if sent_interim_response => {
+                log::error!("Upstream {} stopped responding", upstream_ip);
+                return Err(ProxyError::Fatal(Some(http::StatusCode::GATEWAY_TIMEOUT)));
+            }
+            None => {
+                return Err(ProxyError::Upstream(
+                    http::StatusCode::GATEWAY_TIMEOUT,
+                    format!("no response within {}s", timeouts.upstream_response),
+                ))
+            }
+        };
+        let (response, framing) = match head {
+            Ok(head) => head,
+            // Once an interim response has gone out, the client won't expect to start over
+            Err(error) if sent_interim_response => {
+                log::error!("Error reading response from server: {}", error);
+                return Err(ProxyError::Fatal(Some(http::StatusCode::BAD_GATEWAY)));
+            }
             Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
-                return;
+                return Err(ProxyError::Upstream(
+                    http::StatusCode::BAD_GATEWAY,
+                    format!("could not read response: {}", error),
+                ))
             }
         };
-        // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
+        if response.status().is_informational()
+            && response.status() != http::StatusCode::SWITCHING_PROTOCOLS
+        {
+            // HTTP/1.0 clients don't know about interim responses
+            if request.version() != http::Version::HTTP_10 {
+                send_response(client_conn, &response).await;
+                sent_interim_response = true;
+            }
+            continue;
+        }
+        break (response, framing);
+    };
+    if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+        return switch_protocols(
+            client_conn,
+            upstream_conn,
+            upstream_ip,
+            request,
+            response,
+            log_entry,
+            shutdown,
+        )
+        .await;
+    }
+    let mut body_start = std::mem::take(response.body_mut());
+    // The connection can carry another request unless the server has closed it or is about to
+    let upstream_reusable =
+        response_framing != body::Framing::UntilClose && pool::can_reuse(request, &response);
+    if let Some(revalidated) = cache.and_then(|pending| pending.revalidated(&response)) {
+        log::debug!(
+            "Upstream {} says the cached response is still good",
+            upstream_ip
+        );
+        let client_reusable = send_cached_response(
+            client_conn,
+            request,
+            revalidated,
+            compression,
+            header_rewriter,
+            keep_alive,
+            log_entry,
+            shutdown,
+        )
+        .await;
+        return Ok(Exchange {
+            status: response.status(),
+            upstream_reusable,
+            client_reusable,
+        });
     }
+    header_rewriter.rewrite_location(&mut response, upstream_ip);
+    let mut recording =
+        cache.and_then(|pending| pending.start_recording(&response, response_framing));
+    if cache.is_some() {
+        response
+            .headers_mut()
+            .insert(cache::STATUS_HEADER, http::HeaderValue::from_static("MISS"));
+    }
+    // The cache keeps the response as the upstream sent it, so compression comes after recording
+    let encoding = compression.choose(request, &response, response_framing);
+    if let Some(encoding) = encoding {
+        compression::prepare_headers(response.headers_mut(), encoding);
+    }
+    // HTTP/1.0 clients don't understand the chunked coding, so a body of unknown length is sent to
+    // them as is, with the end of the body marked by closing the connection
+    let unchunked = request.version() == http::Version::HTTP_10
+        && (encoding.is_some()
+            || matches!(
+                response_framing,
+                body::Framing::Chunked | body::Framing::UntilClose
+            ));
+    if unchunked {
+        response
+            .headers_mut()
+            .remove(http::header::TRANSFER_ENCODING);
+    } else if encoding.is_some() {
+        // The compressed length isn't known until the whole body has gone through
+        response.headers_mut().insert(
+            http::header::TRANSFER_ENCODING,
+            http::HeaderValue::from_static("chunked"),
+        );
+    }
+    header_rewriter.rewrite_response(&mut response, Some(upstream_ip));
+    // If we are shutting down, let the client know not to send anything more on this connection
+    let client_reusable = keep_alive && !unchunked && !shutdown.is_shutting_down();
+    hop_by_hop::prepare_response(&mut response, request.version(), client_reusable);
+
+    // Forward the response to the client, streaming the body through as it arrives
+    log::info!(
+        "{} <- {}",
+        client_ip,
+        response::format_response_line(&response)
+    );
+    if let Err(error) = response::write_head(&response, client_conn).await {
+        log::warn!("Failed to send response to client: {}", error);
+        return Err(ProxyError::Fatal(None));
+    }
+    log_entry.status = Some(response.status());
+    let mut body_reader = TimeoutReader::new(&mut *upstream_conn, timeouts.upstream_response);
+    let copied = if let Some(encoding) = encoding {
+        let mut compressor = Compressor::new(&mut *client_conn, encoding, !unchunked);
+        let mut client_writer = cache::Tee::new(&mut compressor, recording.as_mut());
+        match body::copy_body_unchunked(
+            &mut body_reader,
+            &mut body_start,
+            response_framing,
+            &mut client_writer,
+        )
+        .await
+        {
+            Ok(_) => compressor.finish().await.map_err(body::Error::Write),
+            Err(error) => Err(error),
+        }
+    } else {
+        let mut client_writer = cache::Tee::new(&mut *client_conn, recording.as_mut());
+        if unchunked {
+            body::copy_body_unchunked(
+                &mut body_reader,
+                &mut body_start,
+                response_framing,
+                &mut client_writer,
+            )
+            .await
+        } else {
+            body::copy_body(
+                &mut body_reader,
+                &mut body_start,
+                response_framing,
+                &mut client_writer,
+            )
+            .await
+        }
+    };
+    match copied {
+        Ok(bytes_out) => {
+            log::debug!("Forwarded response to client");
+            log_entry.bytes_out = bytes_out;
+            if let (Some(pending), Some(recording)) = (cache, recording) {
+                pending.finish(recording);
+            }
+        }
+        Err(body::Error::Write(error)) => {
+            log::warn!("Failed to send response to client: {}", error);
+            return Err(ProxyError::Fatal(None));
+        }
+        Err(error) => {
+            // The status line has already gone out, so all we can do is hang up on the client to
+            // let it know the response is incomplete
+            log::error!("Error reading response body from server: {}", error);
+            return Err(ProxyError::Fatal(None));
+        }
+    }
+    if unchunked {
+        // Closing the connection is what tells the client the body is complete
+        let _ = client_conn.shutdown().await;
+    }
+    Ok(Exchange {
+        status: response.status(),
+        // Anything the upstream sent past the end of the body would be taken for the start of the
+        // next response
+        upstream_reusable: upstream_reusable && body_start.is_empty(),
+        client_reusable,
+    })
 }
 
-async fn rate_limit(state: &RwLock<ProxyState>) -> bool{
-    let state_read = state.read().await;
-    if state_read.max_requests_per_minute == 0 {
-        return false;
+/// Sends a response out of the cache to the client that sent `request` (compressing it if
+/// `compression` says so and applying the response header rules), telling the client whether its
+/// connection stays open. Returns false if the client connection can't be used for any more
+/// requests.
+#[allow(clippy::too_many_arguments)]
+async fn send_cached_response(
+    client_conn: &mut ClientStream,
+    request: &http::Request<Vec<u8>>,
+    mut response: http::Response<Vec<u8>>,
+    compression: &Compression,
+    header_rewriter: &HeaderRewriter,
+    keep_alive: bool,
+    log_entry: &mut access_log::Entry,
+    shutdown: &ShutdownSignal,
+) -> bool {
+    let framing = match response.body().len() {
+        0 => body::Framing::Empty,
+        length => body::Framing::Length(length as u64),
+    };
+    if let Some(encoding) = compression.choose(request, &response, framing) {
+        compression::compress_response(&mut response, encoding);
     }
-    let request_count = state_read.right_time_request;
-    let max_requests = state_read.max_requests_per_minute;
-    if request_count >= max_requests {
-        log::warn!("Rate limit exceeded: {} requests per second", request_count);
-        return true;
+    header_rewriter.rewrite_response(&mut response, log_entry.upstream.as_deref());
+    let client_reusable = keep_alive && !shutdown.is_shutting_down();
+    hop_by_hop::prepare_response(&mut response, request.version(), client_reusable);
+    log_entry.record_response(&response);
+    send_response(client_conn, &response).await;
+    client_reusable
+}
+
+/// Forwards a 101 (Switching Protocols) response to the client, then relays bytes between the
+/// client and `upstream_conn` until the upgraded connection (e.g. a WebSocket) is closed. Neither
+/// connection can be used for HTTP again afterwards.
+async fn switch_protocols(
+    client_conn: &mut ClientStream,
+    upstream_conn: &mut UpstreamStream,
+    upstream_ip: &str,
+    request: &http::Request<Vec<u8>>,
+    mut response: http::Response<Vec<u8>>,
+    log_entry: &mut access_log::Entry,
+    shutdown: &ShutdownSignal,
+) -> Result<Exchange, ProxyError> {
+    if hop_by_hop::upgrade_protocols(request.headers()).is_none() {
+        return Err(ProxyError::Upstream(
+            http::StatusCode::BAD_GATEWAY,
+            "switched protocols without being asked to".to_string(),
+        ));
+    }
+    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+    // Anything the upstream sent after the response head already belongs to the new protocol
+    let upstream_start = std::mem::take(response.body_mut());
+    hop_by_hop::prepare_upgrade_response(&mut response);
+    log::info!(
+        "{} <- {}",
+        client_ip,
+        response::format_response_line(&response)
+    );
+    if let Err(error) = response::write_head(&response, client_conn).await {
+        log::warn!("Failed to send response to client: {}", error);
+        return Err(ProxyError::Fatal(None));
+    }
+    log_entry.status = Some(response.status());
+
+    match tunnel::relay(client_conn, upstream_conn, &upstream_start, shutdown).await {
+        Ok((bytes_in, bytes_out)) => {
+            log::debug!("Closed tunnel between {} and {}", client_ip, upstream_ip);
+            log_entry.bytes_in += bytes_in;
+            log_entry.bytes_out = bytes_out;
+        }
+        Err(error) => {
+            log::info!(
+                "Tunnel between {} and {} broke off: {}",
+                client_ip,
+                upstream_ip,
+                error
+            )
+        }
     }
-    false
-}
\ No newline at end of file
+    let _ = client_conn.shutdown().await;
+    Ok(Exchange {
+        status: response.status(),
+        upstream_reusable: false,
+        client_reusable: false,
+    })
+}
+
+/// Counts a request from `client_ip` against the rate limit of `pool`. Returns Err with how long
+/// the client should wait before retrying if it has already sent too many requests there.
+async fn rate_limit(
+    state: &RwLock<ProxyState>,
+    pool: &str,
+    client_ip: IpAddr,
+) -> Result<(), Duration> {
+    let (rate_limiter, metrics) = {
+        let state_read = state.read().await;
+        let rate_limiter = match state_read.pool(pool) {
+            Some(pool) => pool.rate_limiter.clone(),
+            // Removed by a configuration reload; connecting to an upstream will fail anyway
+            None => return Ok(()),
+        };
+        (rate_limiter, state_read.metrics.clone())
+    };
+    rate_limiter.check(client_ip).inspect_err(|retry_after| {
+        metrics.record_rate_limited();
+        log::warn!(
+            "Rate limit exceeded by {}, retry after {:?}",
+            client_ip,
+            retry_after
+        );
+    })
+}
@@ -0,0 +1,379 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+use crate::circuit_breaker::CircuitState;
+use crate::routing::UpstreamPool;
+use crate::ProxyState;
+
+/// Upper bounds (in seconds) of the buckets of the request latency histograms
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counters describing what balancebeam has been up to, exposed in the Prometheus text format on
+/// the admin listener's /metrics endpoint.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Number of client connections currently open
+    active_connections: AtomicU64,
+    /// Number of requests rejected with 429 Too Many Requests
+    rate_limited: AtomicU64,
+    /// Number of times a request was retried on another upstream
+    retries: AtomicU64,
+    upstreams: Mutex<HashMap<String, UpstreamMetrics>>,
+}
+
+#[derive(Debug, Default)]
+struct UpstreamMetrics {
+    /// Requests sent to the upstream
+    requests: u64,
+    /// Requests that are currently being handled by the upstream
+    active_requests: u64,
+    /// Requests to the upstream that failed or were cut off
+    errors: u64,
+    /// Number of responses with each status code
+    responses: BTreeMap<u16, u64>,
+    /// Time from sending a request until the whole response had been forwarded
+    latency: Histogram,
+    health_checks_passed: u64,
+    health_checks_failed: u64,
+    /// Connections to the upstream that were newly opened, or borrowed from the pool
+    connections_opened: u64,
+    connections_reused: u64,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Number of observations that fell into each of LATENCY_BUCKETS (not cumulative)
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(idx) = LATENCY_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[idx] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Decrements the active connection count when the client connection it was created for ends.
+pub struct ActiveConnection<'a> {
+    metrics: &'a Metrics,
+}
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.metrics
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    /// Counts a client connection as open until the returned guard is dropped.
+    pub fn connection_opened(&self) -> ActiveConnection<'_> {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection { metrics: self }
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_connection(&self, upstream: &str, reused: bool) {
+        let mut upstreams = self.upstreams.lock();
+        let upstream = upstreams.entry(upstream.to_string()).or_default();
+        if reused {
+            upstream.connections_reused += 1;
+        } else {
+            upstream.connections_opened += 1;
+        }
+    }
+
+    pub fn request_started(&self, upstream: &str) {
+        let mut upstreams = self.upstreams.lock();
+        let upstream = upstreams.entry(upstream.to_string()).or_default();
+        upstream.requests += 1;
+        upstream.active_requests += 1;
+    }
+
+    /// Records the end of a request started with request_started(). `status` is the status of the
+    /// upstream's response, or None if the request failed or was cut off.
+    pub fn request_finished(
+        &self,
+        upstream: &str,
+        status: Option<http::StatusCode>,
+        latency: Duration,
+    ) {
+        let mut upstreams = self.upstreams.lock();
+        let upstream = upstreams.entry(upstream.to_string()).or_default();
+        upstream.active_requests = upstream.active_requests.saturating_sub(1);
+        match status {
+            Some(status) => {
+                *upstream.responses.entry(status.as_u16()).or_insert(0) += 1;
+                upstream.latency.observe(latency.as_secs_f64());
+            }
+            None => upstream.errors += 1,
+        }
+    }
+
+    /// Returns the number of requests that have been sent to any upstream.
+    pub fn total_requests(&self) -> u64 {
+        self.upstreams
+            .lock()
+            .values()
+            .map(|upstream| upstream.requests)
+            .sum()
+    }
+
+    /// Returns the number of requests `upstream` is currently handling.
+    pub fn active_requests(&self, upstream: &str) -> u64 {
+        self.upstreams
+            .lock()
+            .get(upstream)
+            .map_or(0, |upstream| upstream.active_requests)
+    }
+
+    pub fn record_health_check(&self, upstream: &str, healthy: bool) {
+        let mut upstreams = self.upstreams.lock();
+        let upstream = upstreams.entry(upstream.to_string()).or_default();
+        if healthy {
+            upstream.health_checks_passed += 1;
+        } else {
+            upstream.health_checks_failed += 1;
+        }
+    }
+}
+
+/// Renders the metrics of `state` in the Prometheus text exposition format.
+pub fn render(state: &ProxyState) -> String {
+    let metrics = &state.metrics;
+    let mut out = String::new();
+
+    write_scalar(
+        &mut out,
+        ("balancebeam_active_connections", "gauge"),
+        "Open client connections",
+        metrics.active_connections.load(Ordering::Relaxed),
+    );
+    write_scalar(
+        &mut out,
+        ("balancebeam_rate_limited_total", "counter"),
+        "Requests rejected by the rate limiter",
+        metrics.rate_limited.load(Ordering::Relaxed),
+    );
+    write_scalar(
+        &mut out,
+        ("balancebeam_retries_total", "counter"),
+        "Requests retried on another upstream",
+        metrics.retries.load(Ordering::Relaxed),
+    );
+
+    // Report every configured upstream (even ones that haven't been sent anything yet), along with
+    // any that were removed from the configuration but still have counts
+    let upstreams = metrics.upstreams.lock();
+    let configured = state.configured_upstreams();
+    let mut addresses: Vec<&String> = configured.iter().collect();
+    let mut removed: Vec<&String> = upstreams
+        .keys()
+        .filter(|upstream| !configured.contains(upstream))
+        .collect();
+    removed.sort();
+    addresses.extend(removed);
+    let empty = UpstreamMetrics::default();
+    let per_upstream: Vec<(&String, &UpstreamMetrics)> = addresses
+        .into_iter()
+        .map(|address| (address, upstreams.get(address).unwrap_or(&empty)))
+        .collect();
+    let idle_connections = state.connection_pool.idle_counts();
+
+    write_per_upstream(
+        &mut out,
+        &per_upstream,
+        ("balancebeam_upstream_healthy", "gauge"),
+        "Whether the upstream is currently receiving requests in any pool (1) or not (0)",
+        |upstream, _| {
+            let live = |pool: &UpstreamPool| pool.upstream_addresses.iter().any(|u| u == upstream);
+            state.pools.iter().any(live) as u64
+        },
+    );
+    write_per_upstream(
+        &mut out,
+        &per_upstream,
+        ("balancebeam_upstream_draining", "gauge"),
+        "Whether the upstream is being drained of requests through the admin API",
+        |upstream, _| {
+            let draining =
+                |pool: &UpstreamPool| pool.draining_upstreams.iter().any(|u| u == upstream);
+            state.pools.iter().any(draining) as u64
+        },
+    );
+    write_per_upstream(
+        &mut out,
+        &per_upstream,
+        ("balancebeam_upstream_circuit_state", "gauge"),
+        "State of the upstream's circuit breaker (0 = closed, 1 = open, 2 = half-open)",
+        |upstream, _| match state.circuit_breakers.state(upstream) {
+            CircuitState::Closed => 0,
+            CircuitState::Open => 1,
+            CircuitState::HalfOpen => 2,
+        },
+    );
+    write_per_upstream(
+        &mut out,
+        &per_upstream,
+        ("balancebeam_upstream_requests_total", "counter"),
+        "Requests sent to the upstream",
+        |_, m| m.requests,
+    );
+    write_per_upstream(
+        &mut out,
+        &per_upstream,
+        ("balancebeam_upstream_active_requests", "gauge"),
+        "Requests currently being handled by the upstream",
+        |_, m| m.active_requests,
+    );
+    write_per_upstream(
+        &mut out,
+        &per_upstream,
+        ("balancebeam_upstream_errors_total", "counter"),
+        "Requests to the upstream that failed or were cut off",
+        |_, m| m.errors,
+    );
+    write_header(
+        &mut out,
+        "balancebeam_upstream_responses_total",
+        "counter",
+        "Responses from the upstream by status code",
+    );
+    for (upstream, m) in &per_upstream {
+        for (status, count) in &m.responses {
+            writeln!(
+                out,
+                "balancebeam_upstream_responses_total{{upstream=\"{}\",status=\"{}\"}} {}",
+                upstream, status, count
+            )
+            .unwrap();
+        }
+    }
+    write_header(
+        &mut out,
+        "balancebeam_upstream_request_duration_seconds",
+        "histogram",
+        "Time from sending a request to the upstream until its response was forwarded",
+    );
+    for (upstream, m) in &per_upstream {
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(m.latency.buckets) {
+            cumulative += count;
+            writeln!(
+                out,
+                "balancebeam_upstream_request_duration_seconds_bucket{{upstream=\"{}\",le=\"{}\"}} {}",
+                upstream, bound, cumulative
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "balancebeam_upstream_request_duration_seconds_bucket{{upstream=\"{}\",le=\"+Inf\"}} {}",
+            upstream, m.latency.count
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "balancebeam_upstream_request_duration_seconds_sum{{upstream=\"{}\"}} {}",
+            upstream, m.latency.sum
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "balancebeam_upstream_request_duration_seconds_count{{upstream=\"{}\"}} {}",
+            upstream, m.latency.count
+        )
+        .unwrap();
+    }
+    write_header(
+        &mut out,
+        "balancebeam_upstream_health_checks_total",
+        "counter",
+        "Active health checks of the upstream by result",
+    );
+    for (upstream, m) in &per_upstream {
+        for (result, count) in [
+            ("passed", m.health_checks_passed),
+            ("failed", m.health_checks_failed),
+        ] {
+            writeln!(
+                out,
+                "balancebeam_upstream_health_checks_total{{upstream=\"{}\",result=\"{}\"}} {}",
+                upstream, result, count
+            )
+            .unwrap();
+        }
+    }
+    write_per_upstream(
+        &mut out,
+        &per_upstream,
+        ("balancebeam_upstream_connections_opened_total", "counter"),
+        "New connections opened to the upstream",
+        |_, m| m.connections_opened,
+    );
+    write_per_upstream(
+        &mut out,
+        &per_upstream,
+        ("balancebeam_upstream_connections_reused_total", "counter"),
+        "Requests that reused a pooled connection to the upstream",
+        |_, m| m.connections_reused,
+    );
+    write_per_upstream(
+        &mut out,
+        &per_upstream,
+        ("balancebeam_pool_idle_connections", "gauge"),
+        "Idle connections to the upstream waiting in the pool",
+        |upstream, _| idle_connections.get(upstream).copied().unwrap_or(0) as u64,
+    );
+    out
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn write_scalar(out: &mut String, (name, kind): (&str, &str), help: &str, value: u64) {
+    write_header(out, name, kind, help);
+    writeln!(out, "{} {}", name, value).unwrap();
+}
+
+/// Writes a metric with one value per upstream, taking the value for each upstream from `value`.
+fn write_per_upstream(
+    out: &mut String,
+    per_upstream: &[(&String, &UpstreamMetrics)],
+    (name, kind): (&str, &str),
+    help: &str,
+    value: impl Fn(&str, &UpstreamMetrics) -> u64,
+) {
+    write_header(out, name, kind, help);
+    for (upstream, m) in per_upstream {
+        writeln!(
+            out,
+            "{}{{upstream=\"{}\"}} {}",
+            name,
+            upstream,
+            value(upstream, m)
+        )
+        .unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::hop_by_hop::has_connection_option;
+use crate::stream::UpstreamStream;
+
+/// How often idle connections are checked for having timed out or been closed by the upstream
+const EVICTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A connection to an upstream that is waiting to be reused
+#[derive(Debug)]
+struct IdleConnection {
+    stream: UpstreamStream,
+    /// When the connection was returned to the pool
+    idle_since: Instant,
+}
+
+/// Pool of idle keep-alive connections to the upstreams. A request borrows a connection with
+/// take() (opening a new one if there is none) and hands it back with put() once the response has
+/// been forwarded, so that later requests to the same upstream can skip the TCP handshake.
+#[derive(Debug)]
+pub struct ConnectionPool {
+    /// Maximum number of idle connections kept per upstream (0 = no pooling). This can be changed
+    /// by a configuration reload, so it is atomic.
+    max_idle: AtomicUsize,
+    /// Seconds a connection may sit idle in the pool before it is closed
+    idle_timeout: AtomicU64,
+    /// Idle connections to each upstream, least recently used first
+    idle: Mutex<HashMap<String, Vec<IdleConnection>>>,
+}
+
+impl ConnectionPool {
+    pub fn new(max_idle: usize, idle_timeout: usize) -> ConnectionPool {
+        ConnectionPool {
+            max_idle: AtomicUsize::new(max_idle),
+            idle_timeout: AtomicU64::new(idle_timeout as u64),
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_limits(&self, max_idle: usize, idle_timeout: usize) {
+        self.max_idle.store(max_idle, Ordering::Relaxed);
+        self.idle_timeout
+            .store(idle_timeout as u64, Ordering::Relaxed);
+    }
+
+    /// Takes the most recently used idle connection to `upstream` out of the pool, skipping over
+    /// any that have expired or that the upstream has closed in the meantime.
+    pub fn take(&self, upstream: &str) -> Option<UpstreamStream> {
+        let idle_timeout = self.idle_timeout();
+        let mut idle = self.idle.lock();
+        let connections = idle.get_mut(upstream)?;
+        while let Some(connection) = connections.pop() {
+            if connection.idle_since.elapsed() < idle_timeout && is_open(&connection.stream) {
+                return Some(connection.stream);
+            }
+        }
+        None
+    }
+
+    /// Returns a connection to `upstream` to the pool once it has finished carrying a request. If
+    /// the pool for that upstream is full, the least recently used connection is closed to make
+    /// room.
+    pub fn put(&self, upstream: &str, stream: UpstreamStream) {
+        let max_idle = self.max_idle.load(Ordering::Relaxed);
+        if max_idle == 0 {
+            return;
+        }
+        let mut idle = self.idle.lock();
+        let connections = idle.entry(upstream.to_string()).or_default();
+        while connections.len() >= max_idle {
+            connections.remove(0);
+        }
+        connections.push(IdleConnection {
+            stream,
+            idle_since: Instant::now(),
+        });
+    }
+
+    /// Returns the number of idle connections to each upstream.
+    pub fn idle_counts(&self) -> HashMap<String, usize> {
+        self.idle
+            .lock()
+            .iter()
+            .map(|(upstream, connections)| (upstream.clone(), connections.len()))
+            .collect()
+    }
+
+    /// Closes all idle connections to `upstream`, e.g. because it has failed.
+    pub fn clear(&self, upstream: &str) {
+        self.idle.lock().remove(upstream);
+    }
+
+    /// Closes idle connections that have timed out or been closed by their upstream, as well as
+    /// any beyond max_idle (which may have been lowered by a configuration reload).
+    pub fn evict_stale(&self) {
+        let idle_timeout = self.idle_timeout();
+        let max_idle = self.max_idle.load(Ordering::Relaxed);
+        let mut idle = self.idle.lock();
+        idle.retain(|_, connections| {
+            connections.retain(|connection| {
+                connection.idle_since.elapsed() < idle_timeout && is_open(&connection.stream)
+            });
+            let excess = connections.len().saturating_sub(max_idle);
+            connections.drain(..excess);
+            !connections.is_empty()
+        });
+    }
+
+    fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout.load(Ordering::Relaxed))
+    }
+}
+
+/// Returns true if an idle connection can still be used: the upstream hasn't closed it, and hasn't
+/// sent anything unsolicited (which would be mistaken for the response to our next request). On a
+/// TLS connection, anything arriving (even a close_notify) means the connection is done for.
+fn is_open(stream: &UpstreamStream) -> bool {
+    let mut buffer = [0_u8; 1];
+    matches!(
+        stream.tcp_stream().try_read(&mut buffer),
+        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock
+    )
+}
+
+/// Returns true if the upstream connection that carried `request` and `response` may be used for
+/// another request afterwards.
+pub fn can_reuse(request: &http::Request<Vec<u8>>, response: &http::Response<Vec<u8>>) -> bool {
+    if response.status() == http::StatusCode::SWITCHING_PROTOCOLS
+        || has_connection_option(request.headers(), "close")
+        || has_connection_option(response.headers(), "close")
+    {
+        return false;
+    }
+    // HTTP/1.0 connections are closed after every response unless both sides ask otherwise
+    (request.version() != http::Version::HTTP_10
+        || has_connection_option(request.headers(), "keep-alive"))
+        && (response.version() != http::Version::HTTP_10
+            || has_connection_option(response.headers(), "keep-alive"))
+}
+
+/// Spawns a background task that periodically closes idle connections that are no longer usable,
+/// so that we don't hold on to sockets the upstreams have given up on.
+pub fn spawn_eviction_task(pool: Arc<ConnectionPool>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EVICTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            pool.evict_stale();
+        }
+    });
+}
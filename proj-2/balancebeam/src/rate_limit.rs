@@ -0,0 +1,93 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Weak;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Length of the window that max_requests_per_minute applies to
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Per-client-IP rate limiter using a sliding window log: for every client we remember when each of
+/// its requests in the last WINDOW arrived, and a new request is only allowed if there are fewer
+/// than `max_requests` of them.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Maximum number of requests per client IP per WINDOW (0 = unlimited). This can be changed
+    /// by a configuration reload, so it is atomic.
+    max_requests: AtomicUsize,
+    /// Arrival times of the requests each client made within the last WINDOW, oldest first
+    clients: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new(max_requests: usize) -> RateLimiter {
+        RateLimiter {
+            max_requests: AtomicUsize::new(max_requests),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a request from `client_ip`. Returns Ok(()) if the request may go through, or
+    /// Err(retry_after) with how long the client has to wait before it will be allowed another
+    /// request.
+    pub fn check(&self, client_ip: IpAddr) -> Result<(), Duration> {
+        let max_requests = self.max_requests.load(Ordering::Relaxed);
+        if max_requests == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut clients = self.clients.lock();
+        let requests = clients.entry(client_ip).or_default();
+        prune(requests, now);
+        if requests.len() >= max_requests {
+            // The client may send another request once enough of its requests have slid out of
+            // the window
+            let oldest = requests[requests.len() - max_requests];
+            return Err(WINDOW.saturating_sub(now.duration_since(oldest)));
+        }
+        requests.push_back(now);
+        Ok(())
+    }
+
+    pub fn set_max_requests(&self, max_requests: usize) {
+        self.max_requests.store(max_requests, Ordering::Relaxed);
+    }
+
+    /// Forgets about clients that have not sent any requests within the last WINDOW.
+    pub fn evict_stale(&self) {
+        let now = Instant::now();
+        let mut clients = self.clients.lock();
+        clients.retain(|_, requests| {
+            prune(requests, now);
+            !requests.is_empty()
+        });
+    }
+}
+
+/// Drops the requests that are older than WINDOW from the front of `requests`.
+fn prune(requests: &mut VecDeque<Instant>, now: Instant) {
+    while let Some(oldest) = requests.front() {
+        if now.duration_since(*oldest) < WINDOW {
+            break;
+        }
+        requests.pop_front();
+    }
+}
+
+/// Spawns a background task that periodically evicts stale clients from `rate_limiter` so that its
+/// memory use doesn't grow with every IP that has ever connected. The task stops once the rate
+/// limiter is no longer used (e.g. because its pool was removed by a configuration reload).
+pub fn spawn_eviction_task(rate_limiter: Weak<RateLimiter>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(WINDOW);
+        loop {
+            interval.tick().await;
+            match rate_limiter.upgrade() {
+                Some(rate_limiter) => rate_limiter.evict_stale(),
+                None => return,
+            }
+        }
+    });
+}
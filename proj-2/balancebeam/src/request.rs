@@ -1,12 +1,17 @@
-use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{body, chunked};
 
 const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
+/// Largest request body we hold in memory so that the request can be retried on another upstream
+pub const MAX_REPLAYABLE_BODY_SIZE: u64 = 1024 * 1024;
+
+/// A parsed request along with the number of bytes its request line and headers took up
+type ParsedRequest = (http::Request<Vec<u8>>, usize);
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request. IncompleteRequest contains the number of
     /// bytes that were successfully read before the client hung up
@@ -15,14 +20,27 @@ pub enum Error {
     MalformedRequest(httparse::Error),
     /// The Content-Length header is present, but does not contain a valid numeric value
     InvalidContentLength,
-    /// The Content-Length header does not match the size of the request body that was sent
-    ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
-    RequestBodyTooLarge,
+    /// The Transfer-Encoding header is present, but chunked is not the final transfer coding (so
+    /// there is no way to tell where the body ends)
+    UnsupportedTransferEncoding,
     /// Encountered an I/O error when reading/writing a TcpStream
     ConnectionError(std::io::Error),
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IncompleteRequest(n) => {
+                write!(f, "client hung up after sending {} bytes of a request", n)
+            }
+            Error::MalformedRequest(err) => write!(f, "malformed request: {}", err),
+            Error::InvalidContentLength => write!(f, "invalid Content-Length header"),
+            Error::UnsupportedTransferEncoding => write!(f, "unsupported Transfer-Encoding"),
+            Error::ConnectionError(err) => write!(f, "connection error: {}", err),
+        }
+    }
+}
+
 /// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
@@ -48,22 +66,15 @@ fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>,
 /// This function appends to a header value (adding a new header if the header is not already
 /// present). This is used to add the client's IP address to the end of the X-Forwarded-For list,
 /// or to add a new X-Forwarded-For header if one is not already present.
-///
-/// You won't need to touch this function.
-pub fn extend_header_value(
-    request: &mut http::Request<Vec<u8>>,
-    name: &'static str,
-    extend_value: &str,
-) {
-    let new_value = match request.headers().get(name) {
-        Some(existing_value) => {
-            [existing_value.as_bytes(), b", ", extend_value.as_bytes()].concat()
-        }
-        None => extend_value.as_bytes().to_owned(),
-    };
-    request
-        .headers_mut()
-        .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
+pub fn extend_header_value(headers: &mut http::HeaderMap, name: &'static str, extend_value: &str) {
+    // Fold any existing headers (there may be several) into one list
+    let mut new_value = Vec::new();
+    for existing_value in headers.get_all(name) {
+        new_value.extend_from_slice(existing_value.as_bytes());
+        new_value.extend_from_slice(b", ");
+    }
+    new_value.extend_from_slice(extend_value.as_bytes());
+    headers.insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
 }
 
 /// Attempts to parse the data in the supplied buffer as an HTTP request. Returns one of the
@@ -73,17 +84,17 @@ pub fn extend_header_value(
 /// * If there is an incomplete but valid-so-far request in the buffer, returns Ok(None)
 /// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
 ///
-/// You won't need to touch this function.
-fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
+/// The request keeps the HTTP version the client used (1.0 or 1.1).
+fn parse_request(buffer: &[u8]) -> Result<Option<ParsedRequest>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req.parse(buffer).or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(Error::MalformedRequest)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut request = http::Request::builder()
             .method(req.method.unwrap())
             .uri(req.path.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(parse_version(req.version.unwrap()));
         for header in req.headers {
             request = request.header(header.name, header.value);
         }
@@ -94,121 +105,128 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
     }
 }
 
+/// Converts the minor version httparse reports for an HTTP/1.x message.
+pub fn parse_version(minor_version: u8) -> http::Version {
+    match minor_version {
+        0 => http::Version::HTTP_10,
+        _ => http::Version::HTTP_11,
+    }
+}
+
 /// Reads an HTTP request from the provided stream, waiting until a complete set of headers is sent.
-/// This function only reads the request line and headers; the read_body function can subsequently
-/// be called in order to read the request body (for a POST request).
+/// This function only reads the request line and headers; any bytes of the body that arrived along
+/// with the headers are left in the request body, and body::copy_body can subsequently be used to
+/// forward the rest of the body (for a POST request).
 ///
-/// Returns Ok(http::Request) if a valid request is received, or Error if not.
+/// `already_read` holds bytes of the request that were read from the stream along with the previous
+/// request on the connection (when the client pipelines its requests).
 ///
-/// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+/// Returns Ok(http::Request) if a valid request is received, or Error if not.
+pub async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    already_read: Vec<u8>,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    let mut request_buffer = already_read;
+    let mut read_buffer = [0_u8; MAX_HEADERS_SIZE];
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
+        // See if we've read a valid request so far
+        if !request_buffer.is_empty() {
+            if let Some((mut request, headers_len)) = parse_request(&request_buffer)? {
+                // We've read a complete set of headers. However, if this was a POST request, a
+                // request body might have been included as well, and we might have read part of the
+                // body out of the stream into request_buffer. We need to add those bytes to the
+                // Request body so that we don't lose them
+                request
+                    .body_mut()
+                    .extend_from_slice(&request_buffer[headers_len..]);
+                return Ok(request);
+            }
+        }
+
+        // Read more bytes from the connection, up to MAX_HEADERS_SIZE in all
+        let space = MAX_HEADERS_SIZE.saturating_sub(request_buffer.len());
         let new_bytes = stream
-            .read(&mut request_buffer[bytes_read..]).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .read(&mut read_buffer[..space])
+            .await
+            .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
-            return Err(Error::IncompleteRequest(bytes_read));
-        }
-        bytes_read += new_bytes;
-
-        // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
-            // We've read a complete set of headers. However, if this was a POST request, a request
-            // body might have been included as well, and we might have read part of the body out of
-            // the stream into header_buffer. We need to add those bytes to the Request body so that
-            // we don't lose them
-            request
-                .body_mut()
-                .extend_from_slice(&request_buffer[headers_len..bytes_read]);
-            return Ok(request);
+            return Err(Error::IncompleteRequest(request_buffer.len()));
         }
+        request_buffer.extend_from_slice(&read_buffer[..new_bytes]);
     }
 }
 
-/// This function reads the body for a request from the stream. The client only sends a body if the
-/// Content-Length header is present; this function reads that number of bytes from the stream. It
-/// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
-///
-/// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
-    request: &mut http::Request<Vec<u8>>,
-    content_length: usize,
-) -> Result<(), Error> {
-    // Keep reading data until we read the full body length, or until we hit an error.
-    while request.body().len() < content_length {
-        // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
-        // space to read that body.)
-        let mut buffer = vec![0_u8; min(512, content_length)];
-        let bytes_read = stream.read(&mut buffer).await.or_else(|err| Err(Error::ConnectionError(err)))?;
-
-        // Make sure the client is still sending us bytes
-        if bytes_read == 0 {
-            log::debug!(
-                "Client hung up after sending a body of length {}, even though it said the content \
-                length is {}",
-                request.body().len(),
-                content_length
-            );
-            return Err(Error::ContentLengthMismatch);
-        }
-
-        // Make sure the client didn't send us *too many* bytes
-        if request.body().len() + bytes_read > content_length {
-            log::debug!(
-                "Client sent more bytes than we expected based on the given content length!"
-            );
-            return Err(Error::ContentLengthMismatch);
+/// Works out how the body of a request is framed. Transfer-Encoding overrides Content-Length, so
+/// any Content-Length is removed from chunked requests; otherwise the upstream could be tricked into
+/// framing the body differently than we did.
+pub fn body_framing(request: &mut http::Request<Vec<u8>>) -> Result<body::Framing, Error> {
+    if request
+        .headers()
+        .contains_key(http::header::TRANSFER_ENCODING)
+    {
+        if !chunked::is_chunked(request.headers()) {
+            return Err(Error::UnsupportedTransferEncoding);
         }
-
-        // Store the received bytes in the request body
-        request.body_mut().extend_from_slice(&buffer[..bytes_read]);
+        request.headers_mut().remove(http::header::CONTENT_LENGTH);
+        return Ok(body::Framing::Chunked);
+    }
+    // The client only sends a body if it supplies the Content-Length header (which it does for
+    // POST requests)
+    match get_content_length(request)? {
+        None | Some(0) => Ok(body::Framing::Empty),
+        Some(content_length) => Ok(body::Framing::Length(content_length as u64)),
     }
-    Ok(())
 }
 
-/// This function reads and returns an HTTP request from a stream, returning an Error if the client
-/// closes the connection prematurely or sends an invalid request.
-///
-/// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
-    // Read headers
-    let mut request = read_headers(stream).await?;
-    // Read body if the client supplied the Content-Length header (which it does for POST requests)
-    if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
-            return Err(Error::RequestBodyTooLarge);
-        } else {
-            read_body(stream, &mut request, content_length).await?;
-        }
-    }
-    Ok(request)
+/// Returns true if sending the request more than once has the same effect as sending it once, so
+/// that it is safe to retry it on another upstream.
+pub fn is_idempotent(request: &http::Request<Vec<u8>>) -> bool {
+    [
+        http::Method::GET,
+        http::Method::HEAD,
+        http::Method::PUT,
+        http::Method::DELETE,
+    ]
+    .contains(request.method())
 }
 
-/// This function serializes a request to bytes and writes those bytes to the provided stream.
-///
-/// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+/// This function writes the request line and headers of a request to the provided stream. The body
+/// is sent separately (see body::copy_body).
+pub async fn write_head<S: AsyncWrite + Unpin>(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_request_line(request).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream
+        .write_all(&format_request_line(request).into_bytes())
+        .await?;
+    stream.write_all(b"\r\n").await?; // \r\n
     for (header_name, header_value) in request.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream
+            .write_all(format!("{}: ", header_name).as_bytes())
+            .await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?; // \r\n
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+    stream.write_all(b"\r\n").await?;
+    Ok(())
+}
+
+/// This function serializes a request to bytes and writes those bytes to the provided stream.
+/// Requests with a chunked body are re-encoded as chunked, including their trailers.
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
+    request: &http::Request<Vec<u8>>,
+    stream: &mut S,
+) -> Result<(), std::io::Error> {
+    write_head(request, stream).await?;
+    if chunked::is_chunked(request.headers()) {
+        chunked::write_chunk(stream, request.body()).await?;
+        chunked::write_last_chunk(stream, request.extensions().get()).await?;
+    } else if !request.body().is_empty() {
+        stream.write_all(request.body()).await?;
     }
     Ok(())
 }
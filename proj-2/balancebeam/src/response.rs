@@ -1,11 +1,16 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{body, chunked, request};
 
 const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
+/// A parsed response along with the number of bytes its status line and headers took up
+type ParsedResponse = (http::Response<Vec<u8>>, usize);
+
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request
     IncompleteResponse,
@@ -17,10 +22,30 @@ pub enum Error {
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     ResponseBodyTooLarge,
+    /// The chunked response body could not be decoded, or the server hung up partway through it
+    MalformedChunkedBody,
     /// Encountered an I/O error when reading/writing a TcpStream
     ConnectionError(std::io::Error),
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IncompleteResponse => {
+                write!(f, "server hung up before sending a complete response")
+            }
+            Error::MalformedResponse(err) => write!(f, "malformed response: {}", err),
+            Error::InvalidContentLength => write!(f, "invalid Content-Length header"),
+            Error::ContentLengthMismatch => {
+                write!(f, "response body does not match Content-Length")
+            }
+            Error::ResponseBodyTooLarge => write!(f, "response body is too large"),
+            Error::MalformedChunkedBody => write!(f, "malformed chunked response body"),
+            Error::ConnectionError(err) => write!(f, "connection error: {}", err),
+        }
+    }
+}
+
 /// Extracts the Content-Length header value from the provided response. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
@@ -51,18 +76,16 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 /// * If there is data in the buffer that is definitely not a valid HTTP response, returns
 ///   Err(Error)
 ///
-/// You won't need to touch this function.
-fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
+/// The response keeps the HTTP version the server used (1.0 or 1.1).
+fn parse_response(buffer: &[u8]) -> Result<Option<ParsedResponse>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut resp = httparse::Response::new(&mut headers);
-    let res = resp
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+    let res = resp.parse(buffer).map_err(Error::MalformedResponse)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut response = http::Response::builder()
             .status(resp.code.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(request::parse_version(resp.version.unwrap()));
         for header in resp.headers {
             response = response.header(header.name, header.value);
         }
@@ -75,12 +98,14 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 
 /// Reads an HTTP response from the provided stream, waiting until a complete set of headers is
 /// sent. This function only reads the response line and headers; the read_body function can
-/// subsequently be called in order to read the response body.
+/// subsequently be called in order to read the response body, or body::copy_body can be used to
+/// forward it. Any bytes of the body that arrived along with the headers are left in the response
+/// body.
 ///
 /// Returns Ok(http::Response) if a valid response is received, or Error if not.
-///
-/// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+pub async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
@@ -90,7 +115,7 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
         // Read bytes from the connection into the buffer, starting at position bytes_read
         let new_bytes = stream
             .read(&mut response_buffer[bytes_read..]).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete response
             return Err(Error::IncompleteResponse);
@@ -110,21 +135,31 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
     }
 }
 
-/// This function reads the body for a response from the stream. If the Content-Length header is
-/// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
-///
-/// You will need to modify this function in Milestone 2.
-async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>>) -> Result<(), Error> {
+/// This function reads the body for a response from the stream, unless the body is chunked (see
+/// read_chunked_body). If the Content-Length header is present and no Transfer-Encoding is set, it
+/// reads that many bytes; otherwise, it reads bytes until the connection is closed.
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    response: &mut http::Response<Vec<u8>>,
+) -> Result<(), Error> {
     // The response may or may not supply a Content-Length header. If it provides the header, then
     // we want to read that number of bytes; if it does not, we want to keep reading bytes until
-    // the connection is closed.
-    let content_length = get_content_length(response)?;
+    // the connection is closed. (A Transfer-Encoding other than chunked also means reading until
+    // the connection is closed, whatever Content-Length says.)
+    let content_length = if response
+        .headers()
+        .contains_key(http::header::TRANSFER_ENCODING)
+    {
+        None
+    } else {
+        get_content_length(response)?
+    };
 
     while content_length.is_none() || response.body().len() < content_length.unwrap() {
         let mut buffer = [0_u8; 512];
         let bytes_read = stream
             .read(&mut buffer).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if bytes_read == 0 {
             // The server has hung up!
             if content_length.is_none() {
@@ -151,47 +186,153 @@ async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>
         // Append received bytes to the response body
         response.body_mut().extend_from_slice(&buffer[..bytes_read]);
     }
+    if content_length.is_none()
+        && !response
+            .headers()
+            .contains_key(http::header::TRANSFER_ENCODING)
+    {
+        // The body was delimited by the server closing the connection. Give it a Content-Length so
+        // that the client connection can be kept alive after it is forwarded.
+        let body_len = response.body().len();
+        response.headers_mut().insert(
+            http::header::CONTENT_LENGTH,
+            http::HeaderValue::from(body_len),
+        );
+    }
     Ok(())
 }
 
+/// This function reads a chunked response body from the stream, decoding it into the response
+/// body. Any trailer fields are stored in the response's extensions as chunked::Trailers.
+async fn read_chunked_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    response: &mut http::Response<Vec<u8>>,
+) -> Result<(), Error> {
+    // read_headers may have read the beginning of the chunked body along with the headers
+    let already_read = std::mem::take(response.body_mut());
+    let mut reader = chunked::ChunkedReader::new(stream, already_read);
+    loop {
+        match reader.next().await {
+            Ok(Some(data)) => {
+                if response.body().len() + data.len() > MAX_BODY_SIZE {
+                    return Err(Error::ResponseBodyTooLarge);
+                }
+                response.body_mut().extend_from_slice(&data);
+            }
+            Ok(None) => break,
+            Err(chunked::Error::Io(err)) => return Err(Error::ConnectionError(err)),
+            Err(err) => {
+                log::debug!("Error decoding chunked response body: {:?}", err);
+                return Err(Error::MalformedChunkedBody);
+            }
+        }
+    }
+    let trailers = reader.into_trailers();
+    response
+        .extensions_mut()
+        .insert(chunked::Trailers(trailers));
+    Ok(())
+}
+
+/// Returns true if a response with this status to a request with this method may have a body. A
+/// response may have a body as long as it is not responding to a HEAD request and as long as the
+/// response status code is not 1xx, 204 (no content), or 304 (not modified).
+fn has_body(response: &http::Response<Vec<u8>>, request_method: &http::Method) -> bool {
+    !(request_method == http::Method::HEAD
+        || response.status().as_u16() < 200
+        || response.status() == http::StatusCode::NO_CONTENT
+        || response.status() == http::StatusCode::NOT_MODIFIED)
+}
+
+/// Works out how the body of a response is framed, for forwarding it with body::copy_body.
+///
+/// Since copy_body sends bodies that are delimited by the server closing the connection as
+/// chunked, this also updates the headers of such responses to match.
+pub fn body_framing(
+    response: &mut http::Response<Vec<u8>>,
+    request_method: &http::Method,
+) -> Result<body::Framing, Error> {
+    if !has_body(response, request_method) {
+        return Ok(body::Framing::Empty);
+    }
+    if chunked::is_chunked(response.headers()) {
+        response.headers_mut().remove(http::header::CONTENT_LENGTH);
+        return Ok(body::Framing::Chunked);
+    }
+    if !response
+        .headers()
+        .contains_key(http::header::TRANSFER_ENCODING)
+    {
+        if let Some(content_length) = get_content_length(response)? {
+            return Ok(body::Framing::Length(content_length as u64));
+        }
+    }
+    // The body runs until the server closes the connection. Add chunked as the final transfer
+    // coding, since that's how copy_body will send it on.
+    let headers = response.headers_mut();
+    headers.remove(http::header::CONTENT_LENGTH);
+    let transfer_encoding = match headers.get(http::header::TRANSFER_ENCODING) {
+        Some(existing) => [existing.as_bytes(), b", chunked"].concat(),
+        None => b"chunked".to_vec(),
+    };
+    headers.insert(
+        http::header::TRANSFER_ENCODING,
+        http::HeaderValue::from_bytes(&transfer_encoding).unwrap(),
+    );
+    Ok(body::Framing::UntilClose)
+}
+
 /// This function reads and returns an HTTP response from a stream, returning an Error if the server
 /// closes the connection prematurely or sends an invalid response.
-///
-/// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(
-    stream: &mut TcpStream,
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
     request_method: &http::Method,
 ) -> Result<http::Response<Vec<u8>>, Error> {
     let mut response = read_headers(stream).await?;
-    // A response may have a body as long as it is not responding to a HEAD request and as long as
-    // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
-        || response.status().as_u16() < 200
-        || response.status() == http::StatusCode::NO_CONTENT
-        || response.status() == http::StatusCode::NOT_MODIFIED)
-    {
-        read_body(stream, &mut response).await?;
+    if has_body(&response, request_method) {
+        if chunked::is_chunked(response.headers()) {
+            response.headers_mut().remove(http::header::CONTENT_LENGTH);
+            read_chunked_body(stream, &mut response).await?;
+        } else {
+            read_body(stream, &mut response).await?;
+        }
     }
     Ok(response)
 }
 
-/// This function serializes a response to bytes and writes those bytes to the provided stream.
-///
-/// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+/// This function writes the status line and headers of a response to the provided stream. The body
+/// is sent separately (see body::copy_body).
+pub async fn write_head<S: AsyncWrite + Unpin>(
     response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_response_line(response).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream
+        .write_all(&format_response_line(response).into_bytes())
+        .await?;
+    stream.write_all(b"\r\n").await?; // \r\n
     for (header_name, header_value) in response.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream
+            .write_all(format!("{}: ", header_name).as_bytes())
+            .await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?; // \r\n
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+    stream.write_all(b"\r\n").await?;
+    Ok(())
+}
+
+/// This function serializes a response to bytes and writes those bytes to the provided stream.
+/// Responses with a chunked body are re-encoded as chunked, including their trailers.
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
+    response: &http::Response<Vec<u8>>,
+    stream: &mut S,
+) -> Result<(), std::io::Error> {
+    write_head(response, stream).await?;
+    if chunked::is_chunked(response.headers()) {
+        chunked::write_chunk(stream, response.body()).await?;
+        chunked::write_last_chunk(stream, response.extensions().get()).await?;
+    } else if !response.body().is_empty() {
+        stream.write_all(response.body()).await?;
     }
     Ok(())
 }
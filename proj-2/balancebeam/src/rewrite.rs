@@ -0,0 +1,147 @@
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::routing::Route;
+use crate::tls;
+
+/// A regex replacement made to the path and query of a request before it is forwarded. Only the
+/// first match is replaced; the replacement can refer to capture groups as `$1` or `$name`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UriRewrite {
+    pub pattern: Pattern,
+    pub replacement: String,
+}
+
+/// A regex that is compiled when the configuration is loaded
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Pattern(Regex);
+
+impl TryFrom<String> for Pattern {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Pattern, regex::Error> {
+        Regex::new(&pattern).map(Pattern)
+    }
+}
+
+/// Changes the request target of a request that took `route` into what the upstream expects:
+/// strips the route's path prefix if it asks for that, then applies its rewrites in order.
+pub fn rewrite_uri(route: &Route, request: &mut http::Request<Vec<u8>>) {
+    if !route.strip_path_prefix && route.rewrites.is_empty() {
+        return;
+    }
+    let original = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    let mut rewritten = original.to_string();
+    if let Some(prefix) = route
+        .path_prefix
+        .as_deref()
+        .filter(|_| route.strip_path_prefix)
+    {
+        // The route matched, so the path starts with the prefix
+        let rest = &original[prefix.trim_end_matches('/').len().min(original.len())..];
+        rewritten = if rest.starts_with('/') {
+            rest.to_string()
+        } else {
+            format!("/{}", rest)
+        };
+    }
+    for rewrite in &route.rewrites {
+        rewritten = rewrite
+            .pattern
+            .0
+            .replace(&rewritten, rewrite.replacement.as_str())
+            .into_owned();
+    }
+    if !rewritten.starts_with('/') {
+        rewritten.insert(0, '/');
+    }
+
+    let mut parts = request.uri().clone().into_parts();
+    parts.path_and_query = match rewritten.parse() {
+        Ok(path_and_query) => Some(path_and_query),
+        Err(error) => {
+            log::warn!(
+                "Not rewriting {} to invalid {:?}: {}",
+                original,
+                rewritten,
+                error
+            );
+            return;
+        }
+    };
+    match http::Uri::from_parts(parts) {
+        Ok(uri) => {
+            log::debug!("Rewrote {} to {}", original, rewritten);
+            *request.uri_mut() = uri;
+        }
+        Err(error) => log::warn!("Not rewriting {} to {:?}: {}", original, rewritten, error),
+    }
+}
+
+/// Makes the Location of a redirect from `upstream` lead back through us. A URL on the upstream
+/// itself becomes a path on whatever host the client reached us through, and if the route
+/// stripped `stripped_prefix` off the request's path, the prefix is put back on paths the
+/// upstream redirects to on the same host (`client_host`, since the Host header is passed on).
+pub fn rewrite_location(
+    response: &mut http::Response<Vec<u8>>,
+    upstream: &str,
+    client_host: Option<&str>,
+    stripped_prefix: Option<&str>,
+) {
+    if !response.status().is_redirection() {
+        return;
+    }
+    let location = match response
+        .headers()
+        .get(http::header::LOCATION)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(location) => location.to_string(),
+        None => return,
+    };
+    let (origin, path) = if location.starts_with('/') && !location.starts_with("//") {
+        ("", location.as_str())
+    } else {
+        let without_scheme = match location
+            .strip_prefix("http://")
+            .or_else(|| location.strip_prefix("https://"))
+        {
+            Some(without_scheme) => without_scheme,
+            // Some other scheme, or a path relative to the current one, which works out the same
+            // with or without a prefix in front
+            None => return,
+        };
+        let authority_end = without_scheme
+            .find(['/', '?', '#'])
+            .unwrap_or(without_scheme.len());
+        let authority = &without_scheme[..authority_end];
+        let origin_end = location.len() - without_scheme.len() + authority_end;
+        if authority.eq_ignore_ascii_case(tls::parse_upstream(upstream).1) {
+            ("", &location[origin_end..])
+        } else if client_host.is_some_and(|host| authority.eq_ignore_ascii_case(host)) {
+            location.split_at(origin_end)
+        } else {
+            // A different site altogether
+            return;
+        }
+    };
+    let prefix = stripped_prefix.map_or("", |prefix| prefix.trim_end_matches('/'));
+    let path = if !path.starts_with('/') {
+        format!("/{}", path)
+    } else {
+        path.to_string()
+    };
+    let rewritten = format!("{}{}{}", origin, prefix, path);
+    if rewritten == location {
+        return;
+    }
+    if let Ok(value) = http::HeaderValue::from_str(&rewritten) {
+        log::debug!("Rewrote redirect to {} as {}", location, rewritten);
+        response.headers_mut().insert(http::header::LOCATION, value);
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::header_rules::HeaderRule;
+use crate::rate_limit::{self, RateLimiter};
+use crate::rewrite::UriRewrite;
+use crate::strategy::{self, Strategy, StrategyKind};
+
+/// Name of the pool made up of the upstreams given with --upstream (or at the top level of the
+/// configuration file). Requests that no route matches are sent there.
+pub const DEFAULT_POOL: &str = "default";
+
+/// The settings of a group of upstreams that requests can be routed to
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub name: String,
+    pub upstreams: Vec<String>,
+    /// Weights for the weighted strategy. Upstreams that aren't listed have a weight of 1.
+    pub weights: HashMap<String, u32>,
+    pub strategy: StrategyKind,
+    pub health_check_path: String,
+    pub max_requests_per_minute: usize,
+}
+
+/// A rule sending matching requests to a pool. Every condition that is given has to hold for a
+/// request to match; a route without conditions matches everything.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// Name of the pool matching requests are sent to
+    pub pool: String,
+    /// Host the request is for (compared case-insensitively, ignoring the port).
+    /// `*.example.com` matches any subdomain of example.com.
+    pub host: Option<String>,
+    /// Path prefix, matched on whole segments: `/api` matches `/api` and `/api/users`, but not
+    /// `/apiary`
+    pub path_prefix: Option<String>,
+    /// Methods the request may use (any if empty). They are upper-cased when the configuration
+    /// is loaded, so `get` means GET.
+    #[serde(default, deserialize_with = "deserialize_methods")]
+    pub methods: Vec<http::Method>,
+    /// Headers the request has to carry with exactly these values (names are case-insensitive)
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Whether to take path_prefix off the path before forwarding, so that `/api/users` reaches
+    /// the upstream as `/users`. Redirects to such paths get the prefix put back.
+    #[serde(default)]
+    pub strip_path_prefix: bool,
+    /// Regex replacements made, in order, to the path and query of matching requests (after
+    /// strip_path_prefix) before they are forwarded
+    #[serde(default)]
+    pub rewrites: Vec<UriRewrite>,
+    /// Changes made, in order, to the headers of matching requests as they are forwarded
+    #[serde(default)]
+    pub request_headers: Vec<HeaderRule>,
+    /// Changes made, in order, to the headers of the responses to matching requests
+    #[serde(default)]
+    pub response_headers: Vec<HeaderRule>,
+}
+
+impl Route {
+    pub fn matches(&self, request: &http::Request<Vec<u8>>) -> bool {
+        if let Some(host) = &self.host {
+            match request_host(request) {
+                Some(request_host) if host_matches(host, &request_host) => {}
+                _ => return false,
+            }
+        }
+        if let Some(prefix) = &self.path_prefix {
+            if !path_matches(prefix, request.uri().path()) {
+                return false;
+            }
+        }
+        if !self.methods.is_empty() && !self.methods.contains(request.method()) {
+            return false;
+        }
+        self.headers.iter().all(|(name, value)| {
+            request
+                .headers()
+                .get_all(name.as_str())
+                .iter()
+                .any(|actual| actual.as_bytes() == value.as_bytes())
+        })
+    }
+}
+
+/// Deserializes the methods of a route, upper-casing them.
+fn deserialize_methods<'de, D>(deserializer: D) -> Result<Vec<http::Method>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let methods: Vec<String> = Deserialize::deserialize(deserializer)?;
+    methods
+        .iter()
+        .map(|method| {
+            http::Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .map_err(|_| format!("invalid method {:?}", method))
+        })
+        .collect::<Result<_, _>>()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Returns the first route that matches `request`, if any. Requests that no route matches go to
+/// the default pool.
+pub fn select_route<'a>(
+    routes: &'a [Route],
+    request: &http::Request<Vec<u8>>,
+) -> Option<&'a Route> {
+    routes.iter().find(|route| route.matches(request))
+}
+
+/// Returns the (lowercase) host a request is for, without the port, taken from an absolute-form
+/// request target or else the Host header.
+fn request_host(request: &http::Request<Vec<u8>>) -> Option<String> {
+    if let Some(host) = request.uri().host() {
+        return Some(host.to_ascii_lowercase());
+    }
+    let host = request.headers().get(http::header::HOST)?.to_str().ok()?;
+    let host = match host.rfind(':') {
+        // Leave the colons of a bracketed IPv6 address alone
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    };
+    Some(host.to_ascii_lowercase())
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(parent) => host
+            .strip_suffix(&parent.to_ascii_lowercase())
+            .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+fn path_matches(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// A group of upstreams that requests can be routed to, with its own load balancing, health
+/// checks and rate limit. An upstream may belong to several pools; its connection pool, circuit
+/// breaker and metrics are shared between them.
+#[derive(Debug, Clone)]
+pub struct UpstreamPool {
+    pub name: String,
+    /// Addresses of the upstreams that requests are currently sent to
+    pub upstream_addresses: Vec<String>,
+    /// Every upstream in the pool, including the ones that are currently failed
+    pub configured_upstreams: Vec<String>,
+    /// Upstreams that are not given any new requests (set through the admin API)
+    pub draining_upstreams: Vec<String>,
+    /// Decides which of the upstream_addresses each request goes to
+    pub strategy: Arc<dyn Strategy>,
+    /// What the strategy was built from, so that a reload only replaces it if these change
+    strategy_kind: StrategyKind,
+    strategy_weights: HashMap<String, u32>,
+    /// Where requests are sent when doing active health checks
+    pub health_check_path: String,
+    /// Limits how many requests to this pool an individual IP can make in a minute
+    pub rate_limiter: Arc<RateLimiter>,
+}
+
+impl UpstreamPool {
+    pub fn new(config: &PoolConfig) -> UpstreamPool {
+        let rate_limiter = Arc::new(RateLimiter::new(config.max_requests_per_minute));
+        rate_limit::spawn_eviction_task(Arc::downgrade(&rate_limiter));
+        UpstreamPool {
+            name: config.name.clone(),
+            upstream_addresses: config.upstreams.clone(),
+            configured_upstreams: config.upstreams.clone(),
+            draining_upstreams: Vec::new(),
+            strategy: strategy::new_strategy(config.strategy, config.weights.clone()),
+            strategy_kind: config.strategy,
+            strategy_weights: config.weights.clone(),
+            health_check_path: config.health_check_path.clone(),
+            rate_limiter,
+        }
+    }
+
+    /// Switches over to reloaded settings. Upstreams that are new in `config` start out live,
+    /// while upstreams that were already failed stay failed until a health check restores them.
+    /// Rate limiting counts carry over, and so does the strategy (with the requests it knows to be
+    /// in flight) unless its kind or weights changed.
+    pub fn apply_config(&mut self, config: &PoolConfig) {
+        let previously_configured = std::mem::take(&mut self.configured_upstreams);
+        self.upstream_addresses = config
+            .upstreams
+            .iter()
+            .filter(|upstream| {
+                self.upstream_addresses.contains(upstream)
+                    || !previously_configured.contains(upstream)
+            })
+            .cloned()
+            .collect();
+        self.configured_upstreams = config.upstreams.clone();
+        self.draining_upstreams
+            .retain(|upstream| config.upstreams.contains(upstream));
+        if config.strategy != self.strategy_kind || config.weights != self.strategy_weights {
+            self.strategy = strategy::new_strategy(config.strategy, config.weights.clone());
+            self.strategy_kind = config.strategy;
+            self.strategy_weights = config.weights.clone();
+        }
+        self.health_check_path = config.health_check_path.clone();
+        self.rate_limiter
+            .set_max_requests(config.max_requests_per_minute);
+    }
+
+    pub fn contains(&self, upstream: &str) -> bool {
+        self.configured_upstreams.iter().any(|u| u == upstream)
+    }
+
+    /// Starts sending requests to `upstream` (until the next configuration reload). Returns false
+    /// if it is already in the pool.
+    pub fn add_upstream(&mut self, upstream: &str) -> bool {
+        if self.contains(upstream) {
+            return false;
+        }
+        self.configured_upstreams.push(upstream.to_string());
+        self.upstream_addresses.push(upstream.to_string());
+        true
+    }
+
+    /// Stops sending requests to `upstream`. Returns false if it isn't in the pool.
+    pub fn remove_upstream(&mut self, upstream: &str) -> bool {
+        if !self.contains(upstream) {
+            return false;
+        }
+        self.configured_upstreams.retain(|u| u != upstream);
+        self.upstream_addresses.retain(|u| u != upstream);
+        self.draining_upstreams.retain(|u| u != upstream);
+        true
+    }
+
+    /// Marks `upstream` as draining or not. Returns false if it isn't in the pool.
+    pub fn set_draining(&mut self, upstream: &str, draining: bool) -> bool {
+        if !self.contains(upstream) {
+            return false;
+        }
+        self.draining_upstreams.retain(|u| u != upstream);
+        if draining {
+            self.draining_upstreams.push(upstream.to_string());
+        }
+        true
+    }
+}
@@ -0,0 +1,35 @@
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+/// Lets connection tasks find out that balancebeam is shutting down, so that they can finish the
+/// request they are working on and then close their connection instead of waiting for another.
+#[derive(Debug, Clone)]
+pub struct ShutdownSignal(watch::Receiver<bool>);
+
+impl ShutdownSignal {
+    /// Returns a sender that starts the shutdown, along with the signal for connection tasks.
+    pub fn new() -> (watch::Sender<bool>, ShutdownSignal) {
+        let (sender, receiver) = watch::channel(false);
+        (sender, ShutdownSignal(receiver))
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Waits until the shutdown has started.
+    pub async fn wait(&mut self) {
+        // An error means the sender is gone, which only happens once main is exiting anyway
+        let _ = self.0.wait_for(|shutting_down| *shutting_down).await;
+    }
+}
+
+/// Waits for SIGTERM or SIGINT (Ctrl-C), returning the name of the signal that was received.
+pub async fn wait_for_signal() -> &'static str {
+    let mut terminate = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Could not listen for SIGINT");
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use rand::Rng;
+
+/// The load-balancing strategies that can be selected with the --strategy option (or the
+/// `strategy` setting of a configuration file).
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StrategyKind {
+    /// Pick a uniformly random upstream
+    Random,
+    /// Cycle through the upstreams in order
+    RoundRobin,
+    /// Pick the upstream with the fewest requests in flight
+    LeastConnections,
+    /// Cycle through the upstreams, giving each a share of requests in proportion to its weight
+    Weighted,
+    /// Always send a given client IP to the same upstream (as long as it stays alive)
+    ConsistentHash,
+}
+
+/// A Strategy decides which upstream server a request should be forwarded to.
+///
+/// Strategies are shared between all connection tasks, so any bookkeeping they do has to use
+/// interior mutability.
+pub trait Strategy: std::fmt::Debug + Send + Sync {
+    /// Chooses one of the live `upstreams` for a request from `client_ip`. Returns None if
+    /// `upstreams` is empty.
+    fn select(&self, upstreams: &[String], client_ip: IpAddr) -> Option<String>;
+
+    /// Called when a request starts being sent to `upstream`.
+    fn request_started(&self, _upstream: &str) {}
+
+    /// Called once `upstream` has finished handling a request (or failed to).
+    fn request_finished(&self, _upstream: &str) {}
+}
+
+/// Builds the strategy corresponding to `kind`. `weights` maps upstream addresses to their weight
+/// and is only used by the weighted strategy; upstreams missing from the map have a weight of 1.
+pub fn new_strategy(kind: StrategyKind, weights: HashMap<String, u32>) -> Arc<dyn Strategy> {
+    match kind {
+        StrategyKind::Random => Arc::new(Random),
+        StrategyKind::RoundRobin => Arc::new(RoundRobin::default()),
+        StrategyKind::LeastConnections => Arc::new(LeastConnections::default()),
+        StrategyKind::Weighted => Arc::new(Weighted {
+            weights,
+            current_weights: Mutex::default(),
+        }),
+        StrategyKind::ConsistentHash => Arc::new(ConsistentHash),
+    }
+}
+
+/// Tells a strategy that a request to an upstream is in flight for as long as the guard is alive.
+pub struct RequestGuard {
+    strategy: Arc<dyn Strategy>,
+    upstream: String,
+}
+
+impl RequestGuard {
+    pub fn new(strategy: Arc<dyn Strategy>, upstream: &str) -> RequestGuard {
+        strategy.request_started(upstream);
+        RequestGuard {
+            strategy,
+            upstream: upstream.to_string(),
+        }
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.strategy.request_finished(&self.upstream);
+    }
+}
+
+#[derive(Debug)]
+struct Random;
+
+impl Strategy for Random {
+    fn select(&self, upstreams: &[String], _client_ip: IpAddr) -> Option<String> {
+        if upstreams.is_empty() {
+            return None;
+        }
+        let idx = rand::thread_rng().gen_range(0..upstreams.len());
+        Some(upstreams[idx].clone())
+    }
+}
+
+#[derive(Debug, Default)]
+struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl Strategy for RoundRobin {
+    fn select(&self, upstreams: &[String], _client_ip: IpAddr) -> Option<String> {
+        if upstreams.is_empty() {
+            return None;
+        }
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % upstreams.len();
+        Some(upstreams[idx].clone())
+    }
+}
+
+#[derive(Debug, Default)]
+struct LeastConnections {
+    /// Number of requests currently in flight to each upstream
+    active_requests: Mutex<HashMap<String, usize>>,
+}
+
+impl Strategy for LeastConnections {
+    fn select(&self, upstreams: &[String], _client_ip: IpAddr) -> Option<String> {
+        let active_requests = self.active_requests.lock();
+        let count = |upstream: &String| active_requests.get(upstream).copied().unwrap_or(0);
+        let fewest = upstreams.iter().map(count).min()?;
+        // Break ties randomly so that idle upstreams share the load evenly
+        let candidates: Vec<&String> = upstreams.iter().filter(|u| count(u) == fewest).collect();
+        let idx = rand::thread_rng().gen_range(0..candidates.len());
+        Some(candidates[idx].clone())
+    }
+
+    fn request_started(&self, upstream: &str) {
+        *self
+            .active_requests
+            .lock()
+            .entry(upstream.to_string())
+            .or_insert(0) += 1;
+    }
+
+    fn request_finished(&self, upstream: &str) {
+        let mut active_requests = self.active_requests.lock();
+        if let Some(count) = active_requests.get_mut(upstream) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                active_requests.remove(upstream);
+            }
+        }
+    }
+}
+
+/// Smooth weighted round-robin (as done by nginx): every pick, each upstream's current weight
+/// grows by its configured weight, the upstream with the highest current weight wins and has the
+/// total weight taken off again. Over a cycle each upstream is picked in proportion to its weight,
+/// with the picks of heavier upstreams spread out instead of sent in bursts.
+#[derive(Debug)]
+struct Weighted {
+    weights: HashMap<String, u32>,
+    current_weights: Mutex<HashMap<String, i64>>,
+}
+
+impl Strategy for Weighted {
+    fn select(&self, upstreams: &[String], client_ip: IpAddr) -> Option<String> {
+        let weight = |upstream: &String| *self.weights.get(upstream).unwrap_or(&1) as i64;
+        let total: i64 = upstreams.iter().map(weight).sum();
+        if total == 0 {
+            // Every live upstream has a weight of zero; fall back to picking uniformly
+            return Random.select(upstreams, client_ip);
+        }
+        let mut current_weights = self.current_weights.lock();
+        let mut best: Option<(&String, i64)> = None;
+        for upstream in upstreams {
+            let current = current_weights.entry(upstream.clone()).or_insert(0);
+            *current += weight(upstream);
+            // Ties go to the upstream listed first
+            if best.is_none_or(|(_, best_current)| *current > best_current) {
+                best = Some((upstream, *current));
+            }
+        }
+        let (chosen, _) = best?;
+        *current_weights.get_mut(chosen).unwrap() -= total;
+        Some(chosen.clone())
+    }
+}
+
+/// Consistent hashing using rendezvous (highest random weight) hashing: every upstream is scored
+/// against the client IP and the highest score wins. Removing an upstream only moves the clients
+/// that were mapped to it, and they move back once it is restored.
+#[derive(Debug)]
+struct ConsistentHash;
+
+impl Strategy for ConsistentHash {
+    fn select(&self, upstreams: &[String], client_ip: IpAddr) -> Option<String> {
+        upstreams
+            .iter()
+            .max_by_key(|upstream| {
+                let mut hasher = DefaultHasher::new();
+                client_ip.hash(&mut hasher);
+                upstream.hash(&mut hasher);
+                hasher.finish()
+            })
+            .cloned()
+    }
+}
@@ -0,0 +1,97 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// How long (in seconds) each phase of proxying a request may take before we give up on it, so
+/// that a client or upstream that goes quiet can't tie up a connection forever. 0 means no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Time a client has to send a request's headers. On a new connection this starts when the
+    /// client connects; on a kept-alive one, when the first byte of the request arrives.
+    pub client_header: usize,
+    /// Longest a client may pause while sending a request body
+    pub client_body: usize,
+    /// Time an upstream has to accept a connection
+    pub upstream_connect: usize,
+    /// Time an upstream has to start responding once it has been sent a request, and the longest it
+    /// may pause while sending the response body
+    pub upstream_response: usize,
+    /// Longest a kept-alive client connection may sit idle between requests
+    pub keep_alive_idle: usize,
+}
+
+/// Runs `future` to completion, or for `secs` seconds (0 = no limit), whichever comes first.
+/// Returns None if the time ran out.
+pub async fn timeout<F: Future>(secs: usize, future: F) -> Option<F::Output> {
+    timeout_from(Instant::now(), secs, future).await
+}
+
+/// Like timeout(), but counts the `secs` seconds from `start` rather than from now, so that
+/// several steps can share one time limit.
+pub async fn timeout_from<F: Future>(start: Instant, secs: usize, future: F) -> Option<F::Output> {
+    if secs == 0 {
+        return Some(future.await);
+    }
+    tokio::time::timeout_at(start + Duration::from_secs(secs as u64), future)
+        .await
+        .ok()
+}
+
+/// Wraps a reader so that a read fails with ErrorKind::TimedOut if no data arrives for a while.
+/// Unlike putting a timeout on a whole body copy, this allows large bodies to take as long as they
+/// need, as long as they keep moving.
+pub struct TimeoutReader<R> {
+    inner: R,
+    /// Longest a read may wait for data, or None for no limit
+    limit: Option<Duration>,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> TimeoutReader<R> {
+    pub fn new(inner: R, secs: usize) -> TimeoutReader<R> {
+        let limit = (secs > 0).then(|| Duration::from_secs(secs as u64));
+        TimeoutReader {
+            inner,
+            limit,
+            deadline: limit.map(|limit| Box::pin(tokio::time::sleep(limit))),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TimeoutReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                // Restart the clock for the next read
+                if let (Some(deadline), Some(limit)) = (this.deadline.as_mut(), this.limit) {
+                    deadline.as_mut().reset(Instant::now() + limit);
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                let timed_out = match this.deadline.as_mut() {
+                    Some(deadline) => deadline.as_mut().poll(cx).is_ready(),
+                    None => false,
+                };
+                if timed_out {
+                    Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "timed out waiting for data",
+                    )))
+                } else {
+                    Poll::Pending
+                }
+            }
+        }
+    }
+}
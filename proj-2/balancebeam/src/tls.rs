@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use serde::Deserialize;
+use tokio::net::TcpStream;
+
+use crate::stream::{ClientStream, UpstreamStream};
+use crate::{http2, timeout};
+
+/// A certificate that is only presented to clients asking for `host` through SNI
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniCertificate {
+    /// Host name the certificate is for. `*.example.com` matches any direct subdomain of
+    /// example.com.
+    pub host: String,
+    /// PEM file with the certificate chain, leaf certificate first
+    pub cert: PathBuf,
+    /// PEM file with the private key
+    pub key: PathBuf,
+}
+
+/// Parses an --tls-sni-cert value of the form HOST=CERT_PATH,KEY_PATH.
+pub fn parse_sni_certificate(value: &str) -> Result<SniCertificate, String> {
+    let (host, paths) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected HOST=CERT_PATH,KEY_PATH, got {:?}", value))?;
+    let (cert, key) = paths
+        .split_once(',')
+        .ok_or_else(|| format!("expected HOST=CERT_PATH,KEY_PATH, got {:?}", value))?;
+    Ok(SniCertificate {
+        host: host.to_string(),
+        cert: cert.into(),
+        key: key.into(),
+    })
+}
+
+/// The crypto implementation used for every TLS connection
+pub fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Performs TLS handshakes with clients on the HTTPS listener
+#[derive(Clone)]
+pub struct Acceptor(tokio_rustls::TlsAcceptor);
+
+impl Acceptor {
+    pub fn new(config: Arc<rustls::ServerConfig>) -> Acceptor {
+        Acceptor(config.into())
+    }
+
+    /// Performs the TLS handshake on a newly accepted connection, giving up if the client takes
+    /// longer than `timeout_secs` seconds (0 = no limit).
+    pub async fn accept(
+        &self,
+        stream: TcpStream,
+        timeout_secs: usize,
+    ) -> std::io::Result<ClientStream> {
+        let stream = timeout::timeout(timeout_secs, self.0.accept(stream))
+            .await
+            .ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::TimedOut, "TLS handshake timed out")
+            })??;
+        Ok(ClientStream::Tls(Box::new(stream)))
+    }
+}
+
+impl std::fmt::Debug for Acceptor {
+    // The rustls configuration is very long, and would drown out the rest of the ProxyState
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Acceptor")
+    }
+}
+
+/// Builds the TLS configuration for the client listener. `default` (if any) is presented to clients
+/// that don't use SNI, or ask for a host none of the `sni` certificates are for. HTTP/2 and
+/// HTTP/1.1 are offered through ALPN, in that order of preference.
+pub fn server_config(
+    default: Option<(&Path, &Path)>,
+    sni: &[SniCertificate],
+) -> Result<Arc<rustls::ServerConfig>, String> {
+    let provider = crypto_provider();
+    let default = default
+        .map(|(cert, key)| load_certified_key(cert, key, &provider))
+        .transpose()?;
+    let mut by_host = HashMap::new();
+    for certificate in sni {
+        let certified_key = load_certified_key(&certificate.cert, &certificate.key, &provider)?;
+        by_host.insert(certificate.host.to_ascii_lowercase(), certified_key);
+    }
+    if default.is_none() && by_host.is_empty() {
+        return Err("TLS needs a certificate (--tls-cert and --tls-key, or --tls-sni-cert)".into());
+    }
+
+    let mut config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|err| format!("Could not set up TLS: {}", err))?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(CertificateResolver { default, by_host }));
+    config.alpn_protocols = vec![http2::ALPN_PROTOCOL.to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// How balancebeam connects to `https://` upstreams
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamTls {
+    /// PEM file with the CA certificates upstream certificates are checked against. The system's
+    /// trusted certificates are used if this isn't given.
+    pub ca: Option<PathBuf>,
+    /// Accept any certificate from upstreams. Only meant for test environments!
+    pub insecure_skip_verify: bool,
+    /// Certificate chain and private key to present to upstreams that ask for one (mutual TLS)
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+/// Builds the TLS configuration for connections to `https://` upstreams. Only HTTP/1.1 is offered
+/// through ALPN.
+pub fn client_config(settings: &UpstreamTls) -> Result<Arc<rustls::ClientConfig>, String> {
+    let provider = crypto_provider();
+    let mut roots = rustls::RootCertStore::empty();
+    match &settings.ca {
+        Some(ca) => {
+            for cert in read_certificates(ca)? {
+                roots.add(cert).map_err(|err| {
+                    format!("Could not use CA certificate in {}: {}", ca.display(), err)
+                })?;
+            }
+        }
+        None => {
+            let native = rustls_native_certs::load_native_certs();
+            for err in &native.errors {
+                log::warn!(
+                    "Could not load some of the system's CA certificates: {}",
+                    err
+                );
+            }
+            let (_, ignored) = roots.add_parsable_certificates(native.certs);
+            if ignored > 0 {
+                log::debug!("Ignored {} unusable system CA certificates", ignored);
+            }
+        }
+    }
+
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| format!("Could not set up TLS: {}", err))?
+        .with_root_certificates(roots);
+    let mut config = match (&settings.client_cert, &settings.client_key) {
+        (Some(cert), Some(key)) => {
+            let certs = read_certificates(cert)?;
+            let key = read_private_key(key)?;
+            builder.with_client_auth_cert(certs, key).map_err(|err| {
+                format!(
+                    "Could not use client certificate {}: {}",
+                    cert.display(),
+                    err
+                )
+            })?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err(
+                "--upstream-client-cert and --upstream-client-key must be given together"
+                    .to_string(),
+            )
+        }
+    };
+    if settings.insecure_skip_verify {
+        log::warn!("Not verifying the certificates of https:// upstreams");
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoVerification(provider)));
+    }
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Splits an upstream address into whether it is reached over TLS (it starts with `https://`) and
+/// the host:port to connect to. Plain addresses and `http://` ones are reached over plain TCP.
+pub fn parse_upstream(upstream: &str) -> (bool, &str) {
+    if let Some(address) = upstream.strip_prefix("https://") {
+        (true, address)
+    } else {
+        (false, upstream.strip_prefix("http://").unwrap_or(upstream))
+    }
+}
+
+/// Returns the name to ask for through SNI and check the certificate against when connecting to
+/// `address` (a host:port).
+fn server_name(address: &str) -> std::io::Result<ServerName<'static>> {
+    let host = match address.rsplit_once(':') {
+        Some((host, _port)) => host,
+        None => address,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_string()).map_err(|err| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid host name {:?}: {}", host, err),
+        )
+    })
+}
+
+/// Opens connections to upstreams, performing a TLS handshake with `https://` ones
+#[derive(Clone)]
+pub struct Connector(tokio_rustls::TlsConnector);
+
+impl Connector {
+    pub fn new(config: Arc<rustls::ClientConfig>) -> Connector {
+        Connector(config.into())
+    }
+
+    pub async fn connect(&self, upstream: &str) -> std::io::Result<UpstreamStream> {
+        let (use_tls, address) = parse_upstream(upstream);
+        let stream = TcpStream::connect(address).await?;
+        if !use_tls {
+            return Ok(UpstreamStream::Plain(stream));
+        }
+        let stream = self.0.connect(server_name(address)?, stream).await?;
+        Ok(UpstreamStream::Tls(Box::new(stream)))
+    }
+}
+
+impl std::fmt::Debug for Connector {
+    // The rustls configuration is very long, and would drown out the rest of the ProxyState
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Connector")
+    }
+}
+
+/// Accepts any certificate an upstream presents (still checking that the upstream has the private
+/// key for it), for --upstream-insecure-skip-verify
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Loads a certificate chain and its private key from PEM files, making sure they belong together.
+fn load_certified_key(
+    cert_path: &Path,
+    key_path: &Path,
+    provider: &CryptoProvider,
+) -> Result<Arc<CertifiedKey>, String> {
+    let certs = read_certificates(cert_path)?;
+    let key = read_private_key(key_path)?;
+    let certified_key = CertifiedKey::from_der(certs, key, provider).map_err(|err| {
+        format!(
+            "Could not use {} with {}: {}",
+            cert_path.display(),
+            key_path.display(),
+            err
+        )
+    })?;
+    Ok(Arc::new(certified_key))
+}
+
+/// Reads every certificate in a PEM file.
+pub fn read_certificates(
+    path: &Path,
+) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>, String> {
+    let file = std::fs::File::open(path)
+        .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("Could not parse {}: {}", path.display(), err))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+/// Reads the first private key in a PEM file.
+fn read_private_key(path: &Path) -> Result<rustls::pki_types::PrivateKeyDer<'static>, String> {
+    let file = std::fs::File::open(path)
+        .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|err| format!("Could not parse {}: {}", path.display(), err))?
+        .ok_or_else(|| format!("No private key found in {}", path.display()))
+}
+
+/// Picks the certificate to present based on the host name the client asked for through SNI
+#[derive(Debug)]
+struct CertificateResolver {
+    default: Option<Arc<CertifiedKey>>,
+    /// Certificates by (lowercase) host name or `*.` wildcard
+    by_host: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        if let Some(server_name) = client_hello.server_name() {
+            let server_name = server_name.to_ascii_lowercase();
+            if let Some(certified_key) = self.by_host.get(&server_name) {
+                return Some(certified_key.clone());
+            }
+            if let Some((_, parent)) = server_name.split_once('.') {
+                if let Some(certified_key) = self.by_host.get(&format!("*.{}", parent)) {
+                    return Some(certified_key.clone());
+                }
+            }
+        }
+        self.default.clone()
+    }
+}
@@ -0,0 +1,39 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use crate::shutdown::ShutdownSignal;
+
+/// Relays bytes in both directions between a client and an upstream once their connection has
+/// switched from HTTP to another protocol (e.g. WebSocket). When one side finishes sending, the
+/// other side's write half is shut down, and the tunnel lasts until both sides are done (or
+/// balancebeam starts shutting down, since an upgraded connection has no natural point at which
+/// to stop).
+///
+/// `upstream_start` holds whatever the upstream sent right after its 101 response, which has to
+/// reach the client first. Returns the number of bytes sent from the client to the upstream and
+/// from the upstream to the client.
+pub async fn relay<C, U>(
+    client: &mut C,
+    upstream: &mut U,
+    upstream_start: &[u8],
+    shutdown: &ShutdownSignal,
+) -> io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    client.write_all(upstream_start).await?;
+    client.flush().await?;
+    let mut shutdown = shutdown.clone();
+    tokio::select! {
+        copied = tokio::io::copy_bidirectional(client, upstream) => {
+            let (to_upstream, to_client) = copied?;
+            Ok((to_upstream, upstream_start.len() as u64 + to_client))
+        }
+        _ = shutdown.wait() => Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "balancebeam is shutting down",
+        )),
+    }
+}
@@ -2,6 +2,9 @@ mod common;
 
 use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
 
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response};
+use std::collections::{HashMap, HashSet};
 use std::time::Duration;
 use tokio::time::sleep;
 
@@ -9,6 +12,23 @@ async fn setup_with_params(
     n_upstreams: usize,
     active_health_check_interval: Option<usize>,
     max_requests_per_minute: Option<usize>,
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    setup_with_args(
+        n_upstreams,
+        active_health_check_interval,
+        max_requests_per_minute,
+        |_| Vec::new(),
+    )
+    .await
+}
+
+/// Starts `n_upstreams` echo servers and a balancebeam in front of them. `extra_args` is given the
+/// upstream addresses and returns any additional command-line arguments for balancebeam.
+async fn setup_with_args(
+    n_upstreams: usize,
+    active_health_check_interval: Option<usize>,
+    max_requests_per_minute: Option<usize>,
+    extra_args: impl FnOnce(&[&str]) -> Vec<String>,
 ) -> (BalanceBeam, Vec<Box<dyn Server>>) {
     init_logging();
     let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
@@ -23,15 +43,44 @@ async fn setup_with_params(
         .iter()
         .map(|addr| addr.as_str())
         .collect();
-    let balancebeam = BalanceBeam::new(
+    let extra_args = extra_args(&upstream_addresses);
+    let extra_args: Vec<&str> = extra_args.iter().map(|arg| arg.as_str()).collect();
+    let balancebeam = BalanceBeam::new_with_args(
         &upstream_addresses,
         active_health_check_interval,
         max_requests_per_minute,
+        &extra_args,
     )
     .await;
     (balancebeam, upstreams)
 }
 
+async fn setup_with_strategy(
+    n_upstreams: usize,
+    strategy: &str,
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    let strategy = strategy.to_string();
+    // Keep active health checks out of the way so that the upstreams only count our requests
+    setup_with_args(n_upstreams, Some(3600), None, |_| {
+        vec!["--strategy".to_string(), strategy]
+    })
+    .await
+}
+
+/// Stops all of the upstreams, returning the number of requests each one received (in the order
+/// the upstreams were started)
+async fn stop_upstreams(mut upstreams: Vec<Box<dyn Server>>) -> Vec<usize> {
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    request_counters
+}
+
 async fn setup(n_upstreams: usize) -> (BalanceBeam, Vec<Box<dyn Server>>) {
     setup_with_params(n_upstreams, None, None).await
 }
@@ -240,6 +289,55 @@ async fn test_active_health_checks_restore_failed_upstream() {
     log::info!("All done :)");
 }
 
+/// Make sure active health checks time out on upstreams that accept connections but never answer:
+///
+/// * Start an echo server and an upstream that accepts connections and then goes silent
+/// * Wait for a few rounds of health checks, without sending any traffic in the meantime
+/// * Ensure all requests now go to the echo server
+#[tokio::test]
+async fn test_active_health_checks_time_out_hung_upstream() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let hung_listener = tokio::net::TcpListener::bind(common::random_address())
+        .await
+        .expect("Could not bind hung upstream");
+    let hung_address = hung_listener.local_addr().unwrap().to_string();
+    let hung_task = tokio::spawn(async move {
+        // Accept connections and hold onto them without ever responding
+        let mut connections = Vec::new();
+        while let Ok((connection, _)) = hung_listener.accept().await {
+            connections.push(connection);
+        }
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address, &hung_address],
+        Some(1),
+        None,
+        &["--active-health-check-timeout", "1"],
+    )
+    .await;
+
+    log::info!("Waiting for health checks to give up on the hung upstream...");
+    sleep(Duration::from_secs(4)).await;
+
+    for i in 0..6 {
+        let path = format!("/request-{}", i);
+        let response_text = tokio::time::timeout(Duration::from_secs(5), balancebeam.get(&path))
+            .await
+            .expect(
+                "Request was sent to the hung upstream. Health check timeouts may not be working",
+            )
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    hung_task.abort();
+    let request_count = Box::new(upstream).stop().await;
+    assert!(request_count >= 6);
+
+    log::info!("All done :)");
+}
+
 /// Enable rate limiting and ensure that requests fail after sending more than the threshold
 #[tokio::test]
 async fn test_rate_limiting() {
@@ -271,7 +369,7 @@ async fn test_rate_limiting() {
     for i in 0..num_extra_requests {
         let client = reqwest::Client::new();
         let response = client
-            .get(&format!("http://{}/overboard-{}", balancebeam.address, i))
+            .get(format!("http://{}/overboard-{}", balancebeam.address, i))
             .header("x-sent-by", "balancebeam-tests")
             .send()
             .await
@@ -283,6 +381,15 @@ async fn test_rate_limiting() {
         log::info!("{:?}", response);
         log::info!("Checking to make sure the server responded with HTTP 429");
         assert_eq!(response.status().as_u16(), 429);
+        let retry_after: u64 = response
+            .headers()
+            .get("retry-after")
+            .expect("HTTP 429 response is missing a Retry-After header")
+            .to_str()
+            .unwrap()
+            .parse()
+            .expect("Retry-After header is not a number of seconds");
+        assert!(retry_after > 0 && retry_after <= 60);
     }
 
     log::info!("Ensuring the extra requests didn't go through to the upstream servers");
@@ -294,3 +401,329 @@ async fn test_rate_limiting() {
 
     log::info!("All done :)");
 }
+
+/// Exhaust the rate limit from one client IP and make sure a different client IP can still get
+/// its requests through
+#[tokio::test]
+async fn test_rate_limiting_is_per_client_ip() {
+    let n_upstreams = 1;
+    let rate_limit_threshold = 3;
+    let (balancebeam, mut upstreams) =
+        setup_with_params(n_upstreams, None, Some(rate_limit_threshold)).await;
+
+    async fn get_from(balancebeam: &BalanceBeam, client_ip: &str, path: &str) -> u16 {
+        let client = reqwest::Client::builder()
+            .local_address(client_ip.parse::<std::net::IpAddr>().unwrap())
+            .build()
+            .unwrap();
+        client
+            .get(format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .status()
+            .as_u16()
+    }
+
+    log::info!("Using up the rate limit for 127.0.0.1");
+    for i in 0..rate_limit_threshold {
+        assert_eq!(
+            get_from(&balancebeam, "127.0.0.1", &format!("/a-{}", i)).await,
+            200
+        );
+    }
+    assert_eq!(
+        get_from(&balancebeam, "127.0.0.1", "/a-overboard").await,
+        429
+    );
+
+    log::info!("Sending requests from 127.0.0.2, which should have its own limit");
+    for i in 0..rate_limit_threshold {
+        assert_eq!(
+            get_from(&balancebeam, "127.0.0.2", &format!("/b-{}", i)).await,
+            200
+        );
+    }
+    assert_eq!(
+        get_from(&balancebeam, "127.0.0.2", "/b-overboard").await,
+        429
+    );
+
+    let mut total_request_count = 0;
+    while let Some(upstream) = upstreams.pop() {
+        total_request_count += upstream.stop().await;
+    }
+    assert_eq!(total_request_count, 2 * rate_limit_threshold);
+
+    log::info!("All done :)");
+}
+
+/// Round-robin should hand out requests to the upstreams in turn, so every upstream gets exactly the
+/// same number of requests
+#[tokio::test]
+async fn test_round_robin_distribution() {
+    let n_upstreams = 3;
+    let n_requests = 30;
+    let (balancebeam, upstreams) = setup_with_strategy(n_upstreams, "round-robin").await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let request_counters = stop_upstreams(upstreams).await;
+    assert_eq!(
+        request_counters,
+        vec![n_requests / n_upstreams; n_upstreams]
+    );
+
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that holds on to every request for a path starting with /held: it reports
+/// `id` on `arrived` as soon as such a request comes in, but only answers it (with `id`) once
+/// `release` is set. Returns the address.
+async fn start_holding_upstream(
+    id: usize,
+    arrived: tokio::sync::mpsc::UnboundedSender<usize>,
+    release: tokio::sync::watch::Receiver<bool>,
+) -> String {
+    let address = common::random_address();
+    let bind_addr = address.parse().unwrap();
+    let service = make_service_fn(move |_| {
+        let arrived = arrived.clone();
+        let release = release.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req: hyper::Request<Body>| {
+                let arrived = arrived.clone();
+                let mut release = release.clone();
+                async move {
+                    if req.uri().path().starts_with("/held") {
+                        arrived.send(id).unwrap();
+                        while !*release.borrow_and_update() {
+                            release.changed().await.unwrap();
+                        }
+                    }
+                    Ok::<_, hyper::Error>(Response::new(Body::from(id.to_string())))
+                }
+            }))
+        }
+    });
+    tokio::spawn(hyper::Server::bind(&bind_addr).serve(service));
+    address
+}
+
+/// Start a number of requests one after another, holding each of them open at its upstream. Each
+/// new request should go to an upstream with the fewest requests in flight, so the requests end up
+/// spread exactly evenly.
+#[tokio::test]
+async fn test_least_connections_distribution() {
+    init_logging();
+    let n_upstreams = 3;
+    let n_requests = 9;
+    let (arrived_tx, mut arrived_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (release_tx, release_rx) = tokio::sync::watch::channel(false);
+    let mut upstreams = Vec::new();
+    for id in 0..n_upstreams {
+        upstreams.push(start_holding_upstream(id, arrived_tx.clone(), release_rx.clone()).await);
+    }
+    let upstream_refs: Vec<&str> = upstreams.iter().map(String::as_str).collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstream_refs,
+        Some(3600),
+        None,
+        &["--strategy", "least-connections"],
+    )
+    .await;
+
+    let mut in_flight = vec![0; n_upstreams];
+    let mut requests = Vec::new();
+    for i in 0..n_requests {
+        let url = format!("http://{}/held-{}", balancebeam.address, i);
+        requests.push(tokio::spawn(async move {
+            let response = reqwest::get(url).await?;
+            response.error_for_status()?.text().await
+        }));
+        // Wait for the request to reach its upstream before starting the next one
+        let id = tokio::time::timeout(Duration::from_secs(5), arrived_rx.recv())
+            .await
+            .expect("Request never reached an upstream")
+            .unwrap();
+        assert_eq!(
+            in_flight[id],
+            *in_flight.iter().min().unwrap(),
+            "Request {} went to upstream {} although it had more requests in flight than \
+            another upstream ({:?})",
+            i,
+            id,
+            in_flight
+        );
+        in_flight[id] += 1;
+    }
+    assert_eq!(in_flight, vec![n_requests / n_upstreams; n_upstreams]);
+
+    release_tx.send(true).unwrap();
+    for request in requests {
+        request
+            .await
+            .unwrap()
+            .expect("Error sending request to balancebeam");
+    }
+
+    log::info!("All done :)");
+}
+
+/// With weights of 1 and 3, every cycle of four requests should send the second one to the first
+/// upstream and the other three to the second
+#[tokio::test]
+async fn test_weighted_distribution() {
+    init_logging();
+    let upstreams = [
+        start_identifying_upstream().await,
+        start_identifying_upstream().await,
+    ];
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0], &upstreams[1]],
+        Some(3600),
+        None,
+        &[
+            "--strategy",
+            "weighted",
+            "--upstream-weight",
+            &format!("{}=1", upstreams[0]),
+            "--upstream-weight",
+            &format!("{}=3", upstreams[1]),
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let mut picked = Vec::new();
+    for _ in 0..8 {
+        picked.push(upstream_for(&balancebeam, &client).await);
+    }
+    let expected: Vec<&String> = [1, 0, 1, 1, 1, 0, 1, 1]
+        .iter()
+        .map(|&i| &upstreams[i])
+        .collect();
+    assert_eq!(picked.iter().collect::<Vec<_>>(), expected);
+
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that answers every request with its own address, so that tests can tell
+/// which upstream a request went to. Returns the address.
+async fn start_identifying_upstream() -> String {
+    let address = common::random_address();
+    let own_address = address.clone();
+    common::start_upstream_at(address, move |_| {
+        Response::new(Body::from(own_address.clone()))
+    })
+    .await
+}
+
+/// Returns a client that sends requests from `client_ip`, each over a new connection
+fn client_from(client_ip: std::net::IpAddr) -> reqwest::Client {
+    reqwest::Client::builder()
+        .local_address(client_ip)
+        .pool_max_idle_per_host(0)
+        .build()
+        .unwrap()
+}
+
+/// Sends a request, returning the address of the upstream that answered it.
+async fn upstream_for(balancebeam: &BalanceBeam, client: &reqwest::Client) -> String {
+    client
+        .get(format!("http://{}/", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response")
+}
+
+/// Send several requests from each of a range of loopback addresses. Every client IP should stick
+/// to a single upstream, and the different IPs should be spread over more than one upstream. Once
+/// balancebeam is restarted without one of the upstreams, only the client IPs that were sent to it
+/// should move.
+#[tokio::test]
+async fn test_consistent_hash_distribution() {
+    init_logging();
+    let n_client_ips = 30;
+    let requests_per_ip = 4;
+    let upstreams = [
+        start_identifying_upstream().await,
+        start_identifying_upstream().await,
+        start_identifying_upstream().await,
+    ];
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0], &upstreams[1], &upstreams[2]],
+        Some(3600),
+        None,
+        &["--strategy", "consistent-hash"],
+    )
+    .await;
+
+    let client_ips: Vec<std::net::IpAddr> = (1..=n_client_ips)
+        .map(|ip_suffix| format!("127.0.0.{}", ip_suffix).parse().unwrap())
+        .collect();
+    let mut assigned = HashMap::new();
+    for &client_ip in &client_ips {
+        let client = client_from(client_ip);
+        let upstream = upstream_for(&balancebeam, &client).await;
+        for _ in 1..requests_per_ip {
+            assert_eq!(
+                upstream_for(&balancebeam, &client).await,
+                upstream,
+                "Requests from {} were sent to different upstreams",
+                client_ip
+            );
+        }
+        assigned.insert(client_ip, upstream);
+    }
+    log::info!("Upstream picked for each client IP: {:?}", assigned);
+    assert!(
+        assigned.values().collect::<HashSet<_>>().len() > 1,
+        "All client IPs were sent to the same upstream"
+    );
+
+    let removed = assigned[&client_ips[0]].clone();
+    log::info!("Restarting balancebeam without upstream {}", removed);
+    drop(balancebeam);
+    let remaining: Vec<&str> = upstreams
+        .iter()
+        .filter(|upstream| **upstream != removed)
+        .map(String::as_str)
+        .collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &remaining,
+        Some(3600),
+        None,
+        &["--strategy", "consistent-hash"],
+    )
+    .await;
+
+    for &client_ip in &client_ips {
+        let upstream = upstream_for(&balancebeam, &client_from(client_ip)).await;
+        if assigned[&client_ip] == removed {
+            assert_ne!(
+                upstream, removed,
+                "{} was sent to a removed upstream",
+                client_ip
+            );
+        } else {
+            assert_eq!(
+                upstream, assigned[&client_ip],
+                "{} moved although its upstream is still there",
+                client_ip
+            );
+        }
+    }
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,193 @@
+mod common;
+
+use common::{init_logging, start_balancebeam, write_config_file, EchoServer, Server};
+
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::sleep;
+
+fn upstreams_toml(upstreams: &[&str]) -> String {
+    upstreams
+        .iter()
+        .map(|address| format!("[[upstreams]]\naddress = \"{}\"\n", address))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Make sure upstreams and settings can come from a TOML config file instead of the command line
+#[tokio::test]
+async fn test_toml_config_file() {
+    init_logging();
+    let upstreams = [EchoServer::new().await, EchoServer::new().await];
+    let config = format!(
+        "strategy = \"round-robin\"\n\n[health_check]\ninterval = 0\n\n{}",
+        upstreams_toml(&[&upstreams[0].address, &upstreams[1].address])
+    );
+    let config_path = write_config_file("balancebeam-config", "toml.toml", &config);
+    let balancebeam = start_balancebeam(&config_path).await;
+
+    for i in 0..10 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let [first, second] = upstreams;
+    assert_eq!(Box::new(first).stop().await, 5);
+    assert_eq!(Box::new(second).stop().await, 5);
+    std::fs::remove_file(config_path).unwrap();
+
+    log::info!("All done :)");
+}
+
+/// Make sure YAML config files are understood, including rate limiting settings
+#[tokio::test]
+async fn test_yaml_config_file() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config = format!(
+        "max_requests_per_minute: 2\nupstreams:\n  - address: \"{}\"\n    weight: 2\n",
+        upstream.address
+    );
+    let config_path = write_config_file("balancebeam-config", "yaml.yaml", &config);
+    let balancebeam = start_balancebeam(&config_path).await;
+
+    let client = reqwest::Client::new();
+    let mut statuses = Vec::new();
+    for i in 0..3 {
+        let response = client
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        statuses.push(response.status().as_u16());
+    }
+    assert_eq!(statuses, vec![200, 200, 429]);
+
+    Box::new(upstream).stop().await;
+    std::fs::remove_file(config_path).unwrap();
+
+    log::info!("All done :)");
+}
+
+/// Swap the upstream in the config file and send SIGHUP. New connections should go to the new
+/// upstream, while a keep-alive connection that was opened before the reload keeps working.
+#[tokio::test]
+async fn test_reload_config_on_sighup() {
+    init_logging();
+    let old_upstream = EchoServer::new().await;
+    let new_upstream = EchoServer::new().await;
+    let config_path = write_config_file(
+        "balancebeam-config",
+        "reload.toml",
+        &upstreams_toml(&[&old_upstream.address]),
+    );
+    let balancebeam = start_balancebeam(&config_path).await;
+
+    log::info!("Opening a keep-alive connection before reloading");
+    let long_lived_client = reqwest::Client::new();
+    let response_text = long_lived_client
+        .get(format!("http://{}/before-reload", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("GET /before-reload HTTP/1.1"));
+
+    log::info!("Pointing the config file at a different upstream and reloading");
+    std::fs::write(&config_path, upstreams_toml(&[&new_upstream.address])).unwrap();
+    balancebeam.reload_config();
+    sleep(Duration::from_millis(500)).await;
+
+    for i in 0..4 {
+        let path = format!("/after-reload-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    log::info!("Making sure the connection opened before the reload now uses the new upstream");
+    let response_text = long_lived_client
+        .get(format!("http://{}/in-flight", balancebeam.address))
+        .send()
+        .await
+        .expect("Connection opened before the reload was dropped")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("GET /in-flight HTTP/1.1"));
+
+    assert_eq!(Box::new(old_upstream).stop().await, 1);
+    assert_eq!(Box::new(new_upstream).stop().await, 5);
+    std::fs::remove_file(config_path).unwrap();
+
+    log::info!("All done :)");
+}
+
+/// Reload while a request is held open with the least-connections strategy. The strategy should
+/// survive the reload and still know about that request, so every request sent afterwards goes to
+/// the other upstream.
+#[tokio::test]
+async fn test_reload_keeps_strategy_state() {
+    init_logging();
+    let upstreams = [EchoServer::new().await, EchoServer::new().await];
+    let config = format!(
+        "strategy = \"least-connections\"\n\n[health_check]\ninterval = 0\n\n{}",
+        upstreams_toml(&[&upstreams[0].address, &upstreams[1].address])
+    );
+    let config_path = write_config_file("balancebeam-config", "reload-strategy.toml", &config);
+    let balancebeam = start_balancebeam(&config_path).await;
+
+    log::info!("Holding a request open by not finishing its chunked body");
+    let mut held = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    held.write_all(
+        b"POST /held HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n",
+    )
+    .await
+    .expect("Could not send request");
+    // Give balancebeam time to pick an upstream
+    sleep(Duration::from_millis(100)).await;
+
+    log::info!("Reloading the unchanged config file");
+    balancebeam.reload_config();
+    sleep(Duration::from_millis(500)).await;
+
+    let n_requests = 10;
+    for i in 0..n_requests {
+        let path = format!("/after-reload-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        // Let balancebeam finish with the upstream before the next request is routed
+        sleep(Duration::from_millis(50)).await;
+    }
+
+    held.write_all(b"0\r\n\r\n")
+        .await
+        .expect("Could not send request");
+    held.shutdown().await.expect("Could not shut down");
+    let mut response = Vec::new();
+    held.read_to_end(&mut response)
+        .await
+        .expect("Could not read response");
+    assert!(String::from_utf8_lossy(&response).contains("POST /held HTTP/1.1"));
+
+    let [first, second] = upstreams;
+    let mut request_counts = vec![Box::new(first).stop().await, Box::new(second).stop().await];
+    request_counts.sort();
+    assert_eq!(request_counts, vec![1, n_requests]);
+    std::fs::remove_file(config_path).unwrap();
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,152 @@
+mod common;
+
+use common::{init_logging, send_raw_request, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+async fn setup() -> (BalanceBeam, EchoServer) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    (balancebeam, upstream)
+}
+
+/// Send a chunked request body (with chunk extensions and a trailer) and make sure the upstream
+/// receives the decoded body
+#[tokio::test]
+async fn test_chunked_request_body() {
+    let (balancebeam, upstream) = setup().await;
+
+    let response_text = send_raw_request(
+        &balancebeam.address,
+        b"POST /chunked HTTP/1.1\r\n\
+        Host: example.com\r\n\
+        Transfer-Encoding: chunked\r\n\
+        \r\n\
+        7\r\nHello, \r\n\
+        8;ext=value\r\nchunked \r\n\
+        6\r\nworld!\r\n\
+        0\r\n\
+        x-trailer: done\r\n\
+        \r\n",
+    )
+    .await;
+    log::info!("Response: {}", response_text);
+    assert!(response_text.starts_with("HTTP/1.1 200 OK"));
+    assert!(response_text.contains("POST /chunked HTTP/1.1"));
+    assert!(response_text.contains("\n\nHello, chunked world!"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
+
+/// A request whose chunked body can't be decoded should be rejected with a 400 rather than
+/// forwarded
+#[tokio::test]
+async fn test_malformed_chunked_request_body() {
+    let (balancebeam, upstream) = setup().await;
+
+    let response_text = send_raw_request(
+        &balancebeam.address,
+        b"POST /bad-chunks HTTP/1.1\r\n\
+        Host: example.com\r\n\
+        Transfer-Encoding: chunked\r\n\
+        \r\n\
+        zz\r\nnot hex\r\n\
+        0\r\n\r\n",
+    )
+    .await;
+    assert!(response_text.starts_with("HTTP/1.1 400 Bad Request"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 0);
+
+    log::info!("All done :)");
+}
+
+/// Ask the echo server for chunked responses several times over one keep-alive connection, and
+/// make sure every response comes back intact
+#[tokio::test]
+async fn test_chunked_response_body() {
+    let (balancebeam, upstream) = setup().await;
+
+    let client = reqwest::Client::new();
+    for i in 0..3 {
+        let path = format!("/chunked-response-{}", i);
+        let response = client
+            .post(format!("http://{}{}", balancebeam.address, path))
+            .header("x-echo-chunked", "yes")
+            .body("a body long enough to be split over several chunks")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        let response_text = response
+            .text()
+            .await
+            .expect("Could not read chunked response");
+        assert!(response_text.contains(&format!("POST {} HTTP/1.1", path)));
+        assert!(response_text.ends_with("\n\na body long enough to be split over several chunks"));
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+
+    log::info!("All done :)");
+}
+
+/// Make sure trailers sent by an upstream after a chunked response body are passed on to the
+/// client
+#[tokio::test]
+async fn test_chunked_response_trailers() {
+    init_logging();
+    let upstream = tokio::net::TcpListener::bind(common::random_address())
+        .await
+        .expect("Could not bind upstream");
+    let upstream_address = upstream.local_addr().unwrap().to_string();
+    let upstream_task = tokio::spawn(async move {
+        let (mut connection, _) = upstream.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            let mut buffer = [0_u8; 1024];
+            let bytes_read = connection.read(&mut buffer).await.unwrap();
+            assert!(
+                bytes_read > 0,
+                "balancebeam hung up before sending a request"
+            );
+            request.extend_from_slice(&buffer[..bytes_read]);
+        }
+        connection
+            .write_all(
+                b"HTTP/1.1 200 OK\r\n\
+                Transfer-Encoding: chunked\r\n\
+                Trailer: x-checksum\r\n\
+                \r\n\
+                5\r\nhello\r\n\
+                6\r\n world\r\n\
+                0\r\n\
+                x-checksum: abc123\r\n\
+                \r\n",
+            )
+            .await
+            .unwrap();
+        // Hold the connection open until balancebeam is done with it
+        let _ = connection.read(&mut [0_u8; 1]).await;
+    });
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let response_text = send_raw_request(
+        &balancebeam.address,
+        b"GET /trailers HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    )
+    .await;
+    log::info!("Response: {}", response_text);
+    assert!(response_text.starts_with("HTTP/1.1 200 OK"));
+    assert!(response_text.contains("\r\n0\r\nx-checksum: abc123\r\n\r\n"));
+    assert!(response_text.contains("\r\n5\r\nhello\r\n6\r\n world\r\n"));
+
+    drop(balancebeam);
+    upstream_task.await.expect("Upstream task panicked");
+
+    log::info!("All done :)");
+}
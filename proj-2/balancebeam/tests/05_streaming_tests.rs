@@ -0,0 +1,126 @@
+mod common;
+
+use common::{init_logging, send_raw_request, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Send a request body much larger than balancebeam used to be willing to buffer, and make sure it
+/// arrives intact
+#[tokio::test]
+async fn test_large_request_body() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let body: String = "0123456789abcdef".repeat(2 * 1024 * 1024);
+    let response_text = balancebeam
+        .post("/large", &body)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.starts_with("POST /large HTTP/1.1"));
+    assert!(response_text.ends_with(&format!("\n\n{}", body)));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
+
+/// Make sure a response body that is delimited by the upstream closing the connection is passed on
+/// to the client with chunked encoding
+#[tokio::test]
+async fn test_close_delimited_response_body() {
+    init_logging();
+    let upstream = tokio::net::TcpListener::bind(common::random_address())
+        .await
+        .expect("Could not bind upstream");
+    let upstream_address = upstream.local_addr().unwrap().to_string();
+    let upstream_task = tokio::spawn(async move {
+        let (mut connection, _) = upstream.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            let mut buffer = [0_u8; 1024];
+            let bytes_read = connection.read(&mut buffer).await.unwrap();
+            assert!(
+                bytes_read > 0,
+                "balancebeam hung up before sending a request"
+            );
+            request.extend_from_slice(&buffer[..bytes_read]);
+        }
+        connection
+            .write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nuntil the end")
+            .await
+            .unwrap();
+        // Dropping the connection ends the body
+    });
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let response_text = send_raw_request(
+        &balancebeam.address,
+        b"GET /close HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    )
+    .await;
+    log::info!("Response: {}", response_text);
+    assert!(response_text.starts_with("HTTP/1.1 200 OK"));
+    assert!(response_text
+        .to_lowercase()
+        .contains("transfer-encoding: chunked"));
+    assert!(response_text.ends_with("\r\n\r\nd\r\nuntil the end\r\n0\r\n\r\n"));
+
+    drop(balancebeam);
+    upstream_task.await.expect("Upstream task panicked");
+
+    log::info!("All done :)");
+}
+
+/// Make sure requests that a client pipelines (sends one after the other without waiting for the
+/// responses) are all answered in order, even when they arrive in a single write along with bodies
+/// of each kind
+#[tokio::test]
+async fn test_pipelined_requests() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let response = send_raw_request(
+        &balancebeam.address,
+        b"GET /first HTTP/1.1\r\n\
+          Host: example.com\r\n\
+          \r\n\
+          POST /second HTTP/1.1\r\n\
+          Host: example.com\r\n\
+          Content-Length: 6\r\n\
+          \r\n\
+          lengthPOST /third HTTP/1.1\r\n\
+          Host: example.com\r\n\
+          Transfer-Encoding: chunked\r\n\
+          \r\n\
+          7\r\nchunked\r\n0\r\n\r\n\
+          GET /fourth HTTP/1.1\r\n\
+          Host: example.com\r\n\
+          \r\n",
+    )
+    .await;
+    log::info!("Response:\n{}", response);
+    assert_eq!(response.matches("HTTP/1.1 200").count(), 4);
+    let positions: Vec<usize> = [
+        "GET /first ",
+        "POST /second ",
+        "POST /third ",
+        "GET /fourth ",
+    ]
+    .iter()
+    .map(|request_line| {
+        response
+            .find(request_line)
+            .unwrap_or_else(|| panic!("{} was not answered", request_line))
+    })
+    .collect();
+    assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(response.contains("\n\nlength"));
+    assert!(response.contains("\n\nchunked"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 4);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,117 @@
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{init_logging, send_raw_request, BalanceBeam};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Starts an upstream that answers every request on a connection with a small keep-alive response,
+/// and counts how many connections it has accepted.
+async fn start_counting_upstream() -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind(common::random_address())
+        .await
+        .expect("Could not bind upstream");
+    let address = listener.local_addr().unwrap().to_string();
+    let connections_accepted = Arc::new(AtomicUsize::new(0));
+    let counter = connections_accepted.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut connection, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                loop {
+                    let mut buffer = [0_u8; 1024];
+                    let bytes_read = match connection.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(bytes_read) => bytes_read,
+                    };
+                    request.extend_from_slice(&buffer[..bytes_read]);
+                    while let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        request.drain(..end + 4);
+                        if connection
+                            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    (address, connections_accepted)
+}
+
+async fn send_requests(balancebeam: &BalanceBeam, num_requests: usize) {
+    for i in 0..num_requests {
+        // Every request goes over a fresh client connection
+        let response_text = send_raw_request(
+            &balancebeam.address,
+            format!("GET /request-{} HTTP/1.1\r\nHost: example.com\r\n\r\n", i).as_bytes(),
+        )
+        .await;
+        assert!(response_text.starts_with("HTTP/1.1 200 OK"));
+        assert!(response_text.ends_with("\r\n\r\nok"));
+    }
+}
+
+/// Make sure short-lived client connections share pooled upstream connections instead of each
+/// opening their own
+#[tokio::test]
+async fn test_upstream_connections_are_reused() {
+    init_logging();
+    let (upstream_address, connections_accepted) = start_counting_upstream().await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], Some(3600), None).await;
+
+    send_requests(&balancebeam, 10).await;
+    assert_eq!(connections_accepted.load(Ordering::SeqCst), 1);
+
+    log::info!("All done :)");
+}
+
+/// Make sure pooled connections are closed once they have been idle for longer than the idle
+/// timeout
+#[tokio::test]
+async fn test_idle_upstream_connections_time_out() {
+    init_logging();
+    let (upstream_address, connections_accepted) = start_counting_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        Some(3600),
+        None,
+        &["--pool-idle-timeout", "1"],
+    )
+    .await;
+
+    send_requests(&balancebeam, 3).await;
+    assert_eq!(connections_accepted.load(Ordering::SeqCst), 1);
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    send_requests(&balancebeam, 3).await;
+    assert_eq!(connections_accepted.load(Ordering::SeqCst), 2);
+
+    log::info!("All done :)");
+}
+
+/// Make sure that --pool-max-idle 0 turns pooling off
+#[tokio::test]
+async fn test_pooling_disabled() {
+    init_logging();
+    let (upstream_address, connections_accepted) = start_counting_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        Some(3600),
+        None,
+        &["--pool-max-idle", "0"],
+    )
+    .await;
+
+    send_requests(&balancebeam, 5).await;
+    assert_eq!(connections_accepted.load(Ordering::SeqCst), 5);
+
+    log::info!("All done :)");
+}
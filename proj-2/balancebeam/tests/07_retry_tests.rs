@@ -0,0 +1,165 @@
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+
+/// Starts an upstream that reads each request's headers and then hangs up without responding.
+/// Returns its address and a count of the requests it has swallowed.
+async fn start_broken_upstream() -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind(common::random_address())
+        .await
+        .expect("Could not bind upstream");
+    let address = listener.local_addr().unwrap().to_string();
+    let requests_received = Arc::new(AtomicUsize::new(0));
+    let counter = requests_received.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut connection, _) = listener.accept().await.unwrap();
+            let counter = counter.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let mut buffer = [0_u8; 1024];
+                    match connection.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(bytes_read) => request.extend_from_slice(&buffer[..bytes_read]),
+                    }
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+    });
+    (address, requests_received)
+}
+
+/// Starts a broken upstream followed by a working one, with balancebeam alternating between them.
+async fn setup(extra_args: &[&str]) -> (BalanceBeam, EchoServer, Arc<AtomicUsize>) {
+    init_logging();
+    let (broken_address, broken_requests) = start_broken_upstream().await;
+    let upstream = EchoServer::new().await;
+    let mut args = vec!["--strategy", "round-robin"];
+    args.extend_from_slice(extra_args);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&broken_address, &upstream.address],
+        Some(3600),
+        None,
+        &args,
+    )
+    .await;
+    (balancebeam, upstream, broken_requests)
+}
+
+/// Make sure each request on a keep-alive connection gets its own upstream
+#[tokio::test]
+async fn test_upstream_selected_per_request() {
+    init_logging();
+    let upstreams = vec![EchoServer::new().await, EchoServer::new().await];
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address, &upstreams[1].address],
+        Some(3600),
+        None,
+        &["--strategy", "round-robin"],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for i in 0..6 {
+        let path = format!("/request-{}", i);
+        let response_text = client
+            .get(format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let mut request_counters = Vec::new();
+    for upstream in upstreams {
+        request_counters.push(Box::new(upstream).stop().await);
+    }
+    assert_eq!(request_counters, vec![3, 3]);
+
+    log::info!("All done :)");
+}
+
+/// Make sure idempotent requests that hit a failing upstream are retried on another one
+#[tokio::test]
+async fn test_idempotent_requests_are_retried() {
+    let (balancebeam, upstream, broken_requests) = setup(&[]).await;
+
+    let client = reqwest::Client::new();
+    for i in 0..6 {
+        let path = format!("/request-{}", i);
+        let response = client
+            .put(format!("http://{}{}", balancebeam.address, path))
+            .body(format!("body {}", i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        let response_text = response.text().await.unwrap();
+        assert!(response_text.contains(&format!("PUT {} HTTP/1.1", path)));
+        assert!(response_text.ends_with(&format!("\n\nbody {}", i)));
+    }
+    assert!(broken_requests.load(Ordering::SeqCst) > 0);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 6);
+
+    log::info!("All done :)");
+}
+
+/// Make sure POST requests are not sent to a second upstream after the first one failed
+#[tokio::test]
+async fn test_non_idempotent_requests_are_not_retried() {
+    let (balancebeam, upstream, broken_requests) = setup(&[]).await;
+
+    let client = reqwest::Client::new();
+    let mut statuses = Vec::new();
+    for i in 0..4 {
+        let response = client
+            .post(format!("http://{}/request-{}", balancebeam.address, i))
+            .body("some data")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        statuses.push(response.status().as_u16());
+    }
+    assert_eq!(statuses, vec![502, 200, 502, 200]);
+    assert_eq!(broken_requests.load(Ordering::SeqCst), 2);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+
+    log::info!("All done :)");
+}
+
+/// Make sure --max-retries 0 turns retrying off
+#[tokio::test]
+async fn test_retry_budget() {
+    let (balancebeam, upstream, _) = setup(&["--max-retries", "0"]).await;
+
+    let client = reqwest::Client::new();
+    let mut statuses = Vec::new();
+    for i in 0..4 {
+        let response = client
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        statuses.push(response.status().as_u16());
+    }
+    assert_eq!(statuses, vec![502, 200, 502, 200]);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,131 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, ErrorServer};
+
+async fn fetch_metrics(admin_address: &str) -> String {
+    let response = reqwest::get(format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error fetching metrics");
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    response.text().await.unwrap()
+}
+
+/// Returns the value of the sample called `name` (including labels) in `metrics`.
+fn sample(metrics: &str, name: &str) -> Option<f64> {
+    metrics.lines().find_map(|line| {
+        let (sample_name, value) = line.rsplit_once(' ')?;
+        if sample_name == name {
+            value.parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// Send requests to two upstreams (one of which always fails) and make sure /metrics reports them
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let error_upstream = ErrorServer::new().await;
+    let admin_address = common::random_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address, &error_upstream.address],
+        Some(3600),
+        Some(5),
+        &["--strategy", "round-robin", "--admin-bind", &admin_address],
+    )
+    .await;
+
+    for i in 0..6 {
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let metrics = fetch_metrics(&admin_address).await;
+    log::info!("Metrics:\n{}", metrics);
+    let ok = &upstream.address;
+    let failing = &error_upstream.address;
+    let label = |upstream: &str| format!("{{upstream=\"{}\"}}", upstream);
+    assert!(metrics.contains("# TYPE balancebeam_upstream_requests_total counter"));
+    assert_eq!(
+        sample(&metrics, "balancebeam_rate_limited_total"),
+        Some(1.0)
+    );
+    assert_eq!(
+        sample(
+            &metrics,
+            &format!("balancebeam_upstream_requests_total{}", label(ok))
+        ),
+        Some(3.0)
+    );
+    assert_eq!(
+        sample(
+            &metrics,
+            &format!("balancebeam_upstream_requests_total{}", label(failing))
+        ),
+        Some(2.0)
+    );
+    assert_eq!(
+        sample(
+            &metrics,
+            &format!(
+                "balancebeam_upstream_responses_total{{upstream=\"{}\",status=\"200\"}}",
+                ok
+            )
+        ),
+        Some(3.0)
+    );
+    assert!(sample(
+        &metrics,
+        &format!(
+            "balancebeam_upstream_responses_total{{upstream=\"{}\",status=\"200\"}}",
+            failing
+        )
+    )
+    .is_none());
+    assert_eq!(
+        sample(
+            &metrics,
+            &format!(
+                "balancebeam_upstream_request_duration_seconds_bucket{{upstream=\"{}\",le=\"+Inf\"}}",
+                ok
+            )
+        ),
+        Some(3.0)
+    );
+    assert_eq!(
+        sample(
+            &metrics,
+            &format!("balancebeam_upstream_healthy{}", label(ok))
+        ),
+        Some(1.0)
+    );
+    assert_eq!(
+        sample(
+            &metrics,
+            &format!("balancebeam_pool_idle_connections{}", label(ok))
+        ),
+        Some(1.0)
+    );
+    assert_eq!(
+        sample(
+            &metrics,
+            &format!("balancebeam_upstream_connections_reused_total{}", label(ok))
+        ),
+        Some(2.0)
+    );
+
+    let response = reqwest::get(format!("http://{}/nope", admin_address))
+        .await
+        .expect("Error sending request to admin listener");
+    assert_eq!(response.status().as_u16(), 404);
+
+    log::info!("All done :)");
+}
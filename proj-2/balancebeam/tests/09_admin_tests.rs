@@ -0,0 +1,205 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+async fn setup(upstreams: &[&str]) -> (BalanceBeam, String) {
+    init_logging();
+    let admin_address = common::random_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        upstreams,
+        Some(3600),
+        None,
+        &["--strategy", "round-robin", "--admin-bind", &admin_address],
+    )
+    .await;
+    (balancebeam, admin_address)
+}
+
+/// Sends a request to the admin API, returning the status and the parsed JSON body.
+async fn admin_request(
+    admin_address: &str,
+    method: reqwest::Method,
+    path: &str,
+    body: Option<&str>,
+) -> (u16, Value) {
+    let mut request =
+        reqwest::Client::new().request(method, format!("http://{}{}", admin_address, path));
+    if let Some(body) = body {
+        request = request
+            .header("Content-Type", "application/json")
+            .body(body.to_string());
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to admin listener");
+    let status = response.status().as_u16();
+    let body = response.text().await.unwrap();
+    log::info!("Admin API replied {}: {}", status, body);
+    (status, serde_json::from_str(&body).unwrap_or(Value::Null))
+}
+
+/// Returns the addresses listed in a GET /upstreams response
+fn addresses(upstreams: &Value) -> Vec<&str> {
+    upstreams
+        .as_array()
+        .expect("Expected a list of upstreams")
+        .iter()
+        .map(|upstream| upstream["address"].as_str().unwrap())
+        .collect()
+}
+
+async fn send_requests(balancebeam: &BalanceBeam, n_requests: usize) {
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+}
+
+/// Add an upstream, make sure it starts receiving requests, then remove the original one and make
+/// sure it stops receiving them
+#[tokio::test]
+async fn test_add_and_remove_upstreams() {
+    let upstream = EchoServer::new().await;
+    let new_upstream = EchoServer::new().await;
+    let (balancebeam, admin_address) = setup(&[&upstream.address]).await;
+
+    let (status, upstreams) =
+        admin_request(&admin_address, reqwest::Method::GET, "/upstreams", None).await;
+    assert_eq!(status, 200);
+    assert_eq!(addresses(&upstreams), vec![upstream.address.as_str()]);
+    assert_eq!(upstreams[0]["healthy"], Value::Bool(true));
+    assert_eq!(upstreams[0]["draining"], Value::Bool(false));
+
+    let new_upstream_json = format!("{{\"address\": \"{}\"}}", new_upstream.address);
+    let (status, upstreams) = admin_request(
+        &admin_address,
+        reqwest::Method::POST,
+        "/upstreams",
+        Some(&new_upstream_json),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        addresses(&upstreams),
+        vec![upstream.address.as_str(), new_upstream.address.as_str()]
+    );
+    let (status, _) = admin_request(
+        &admin_address,
+        reqwest::Method::POST,
+        "/upstreams",
+        Some(&new_upstream_json),
+    )
+    .await;
+    assert_eq!(status, 409);
+    send_requests(&balancebeam, 4).await;
+
+    let (status, upstreams) = admin_request(
+        &admin_address,
+        reqwest::Method::DELETE,
+        &format!("/upstreams/{}", upstream.address),
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(addresses(&upstreams), vec![new_upstream.address.as_str()]);
+    let (status, _) = admin_request(
+        &admin_address,
+        reqwest::Method::DELETE,
+        &format!("/upstreams/{}", upstream.address),
+        None,
+    )
+    .await;
+    assert_eq!(status, 404);
+    send_requests(&balancebeam, 4).await;
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    assert_eq!(Box::new(new_upstream).stop().await, 6);
+
+    log::info!("All done :)");
+}
+
+/// Drain an upstream while it is handling a request. The request should finish, but no new
+/// requests should be sent to the upstream until draining is turned off again.
+#[tokio::test]
+async fn test_drain_upstream() {
+    let drained_upstream = EchoServer::new().await;
+    let other_upstream = EchoServer::new().await;
+    let (balancebeam, admin_address) =
+        setup(&[&drained_upstream.address, &other_upstream.address]).await;
+
+    // Round robin sends this first request to drained_upstream. Leave its chunked body unfinished
+    // so that it stays in flight.
+    let mut in_flight = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .unwrap();
+    in_flight
+        .write_all(
+            b"POST /in-flight HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let drain_path = format!("/upstreams/{}/drain", drained_upstream.address);
+    let (status, upstreams) =
+        admin_request(&admin_address, reqwest::Method::POST, &drain_path, None).await;
+    assert_eq!(status, 200);
+    assert_eq!(upstreams[0]["draining"], Value::Bool(true));
+    assert_eq!(upstreams[0]["active_requests"], Value::from(1));
+
+    send_requests(&balancebeam, 4).await;
+    in_flight
+        .write_all(b"5\r\nhello\r\n0\r\n\r\n")
+        .await
+        .unwrap();
+    in_flight.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    in_flight.read_to_end(&mut response).await.unwrap();
+    let response_text = String::from_utf8_lossy(&response);
+    assert!(response_text.starts_with("HTTP/1.1 200 OK"));
+    assert!(response_text.ends_with("\n\nhello"));
+
+    let (status, upstreams) =
+        admin_request(&admin_address, reqwest::Method::DELETE, &drain_path, None).await;
+    assert_eq!(status, 200);
+    assert_eq!(upstreams[0]["draining"], Value::Bool(false));
+    send_requests(&balancebeam, 4).await;
+
+    assert_eq!(Box::new(drained_upstream).stop().await, 3);
+    assert_eq!(Box::new(other_upstream).stop().await, 6);
+
+    log::info!("All done :)");
+}
+
+/// Make sure a health check can be forced through the admin API
+#[tokio::test]
+async fn test_forced_health_check() {
+    let upstream = EchoServer::new().await;
+    let error_upstream = ErrorServer::new().await;
+    let (_balancebeam, admin_address) = setup(&[&upstream.address, &error_upstream.address]).await;
+
+    let (_, upstreams) =
+        admin_request(&admin_address, reqwest::Method::GET, "/upstreams", None).await;
+    assert_eq!(upstreams[1]["healthy"], Value::Bool(true));
+
+    let (status, upstreams) =
+        admin_request(&admin_address, reqwest::Method::POST, "/health-check", None).await;
+    assert_eq!(status, 200);
+    assert_eq!(upstreams[0]["healthy"], Value::Bool(true));
+    assert_eq!(upstreams[1]["healthy"], Value::Bool(false));
+
+    let (status, _) =
+        admin_request(&admin_address, reqwest::Method::GET, "/health-check", None).await;
+    assert_eq!(status, 405);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,124 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use nix::sys::signal::Signal;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn setup(extra_args: &[&str]) -> (BalanceBeam, EchoServer) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], Some(3600), None, extra_args).await;
+    (balancebeam, upstream)
+}
+
+/// Sends the headers of a chunked POST request without finishing its body, so that the request
+/// stays in flight until the caller sends the rest.
+async fn start_request(balancebeam: &BalanceBeam) -> TcpStream {
+    let mut connection = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    connection
+        .write_all(
+            b"POST /in-flight HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    // Give balancebeam time to start forwarding the request
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    connection
+}
+
+/// A request that is in flight when balancebeam is asked to shut down should still be answered,
+/// after which balancebeam closes the connection and exits
+#[tokio::test]
+async fn test_in_flight_request_finishes() {
+    let (mut balancebeam, upstream) = setup(&[]).await;
+
+    let mut in_flight = start_request(&balancebeam).await;
+    balancebeam.send_signal(Signal::SIGTERM);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(
+        TcpStream::connect(&balancebeam.address).await.is_err(),
+        "balancebeam accepted a connection after it started shutting down"
+    );
+
+    in_flight
+        .write_all(b"5\r\nhello\r\n0\r\n\r\n")
+        .await
+        .unwrap();
+    // Don't close our side of the connection: balancebeam should close it
+    let mut response = Vec::new();
+    in_flight.read_to_end(&mut response).await.unwrap();
+    let response_text = String::from_utf8_lossy(&response);
+    log::info!("Response: {}", response_text);
+    assert!(response_text.starts_with("HTTP/1.1 200 OK"));
+    assert!(response_text.to_lowercase().contains("connection: close"));
+    assert!(response_text.ends_with("\n\nhello"));
+
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(5))
+        .await
+        .expect("balancebeam did not exit");
+    assert!(status.success());
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Idle keep-alive connections should be closed right away on shutdown
+#[tokio::test]
+async fn test_idle_connections_are_closed() {
+    let (mut balancebeam, _upstream) = setup(&["--shutdown-grace-period", "30"]).await;
+
+    let mut idle = TcpStream::connect(&balancebeam.address).await.unwrap();
+    idle.write_all(b"GET /first HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buffer = [0_u8; 4096];
+    let bytes_read = idle.read(&mut buffer).await.unwrap();
+    assert!(String::from_utf8_lossy(&buffer[..bytes_read]).starts_with("HTTP/1.1 200 OK"));
+
+    let start = Instant::now();
+    balancebeam.send_signal(Signal::SIGINT);
+    // Whatever is left of the first response may still be buffered, but after that the connection
+    // should be closed without another request being sent
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), idle.read_to_end(&mut rest))
+        .await
+        .expect("balancebeam did not close the idle connection")
+        .unwrap();
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(5))
+        .await
+        .expect("balancebeam did not exit");
+    assert!(status.success());
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    log::info!("All done :)");
+}
+
+/// Requests that haven't finished by the end of the grace period are cut off
+#[tokio::test]
+async fn test_grace_period() {
+    let (mut balancebeam, _upstream) = setup(&["--shutdown-grace-period", "1"]).await;
+
+    let mut in_flight = start_request(&balancebeam).await;
+    let start = Instant::now();
+    balancebeam.send_signal(Signal::SIGTERM);
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(5))
+        .await
+        .expect("balancebeam did not exit after the grace period");
+    assert!(status.success());
+    assert!(start.elapsed() >= Duration::from_secs(1));
+
+    let mut response = Vec::new();
+    let _ = in_flight.read_to_end(&mut response).await;
+    assert!(response.is_empty());
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,120 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{init_logging, start_upstream, BalanceBeam, EchoServer, Server};
+use hyper::{Body, Request, Response};
+use tokio::time::sleep;
+
+/// Answers requests for paths starting with /fail with a 500 and everything else with a 200
+fn respond(request: Request<Body>) -> Response<Body> {
+    let status = if request.uri().path().starts_with("/fail") {
+        http::StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        http::StatusCode::OK
+    };
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+async fn get_statuses(balancebeam: &BalanceBeam, paths: &[&str]) -> Vec<u16> {
+    let client = reqwest::Client::new();
+    let mut statuses = Vec::new();
+    for path in paths {
+        let response = client
+            .get(format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        statuses.push(response.status().as_u16());
+    }
+    statuses
+}
+
+/// Make sure failures that don't happen back to back don't take an upstream out of rotation, but
+/// failure_threshold consecutive ones do
+#[tokio::test]
+async fn test_circuit_opens_after_consecutive_failures() {
+    init_logging();
+    let upstream = start_upstream(respond).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        Some(3600),
+        None,
+        &["--circuit-breaker-threshold", "2"],
+    )
+    .await;
+
+    let statuses = get_statuses(&balancebeam, &["/fail", "/ok", "/fail", "/ok"]).await;
+    assert_eq!(statuses, vec![500, 200, 500, 200]);
+    // Two failures in a row open the circuit, leaving no upstream to send requests to
+    let statuses = get_statuses(&balancebeam, &["/fail", "/fail", "/ok"]).await;
+    assert_eq!(statuses, vec![500, 500, 502]);
+
+    log::info!("All done :)");
+}
+
+/// Make sure a trial request is let through once the cool-down has passed, and that the circuit
+/// closes if it succeeds and opens again if it fails
+#[tokio::test]
+async fn test_circuit_half_opens_after_cool_down() {
+    init_logging();
+    let upstream = start_upstream(respond).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        Some(3600),
+        None,
+        &[
+            "--circuit-breaker-threshold",
+            "2",
+            "--circuit-breaker-cool-down",
+            "1",
+        ],
+    )
+    .await;
+
+    let statuses = get_statuses(&balancebeam, &["/fail", "/fail", "/ok"]).await;
+    assert_eq!(statuses, vec![500, 500, 502]);
+
+    // A failed trial opens the circuit for another cool-down
+    sleep(Duration::from_millis(1500)).await;
+    let statuses = get_statuses(&balancebeam, &["/fail", "/ok"]).await;
+    assert_eq!(statuses, vec![500, 502]);
+
+    // A successful one closes it
+    sleep(Duration::from_millis(1500)).await;
+    let statuses = get_statuses(&balancebeam, &["/ok", "/fail", "/ok"]).await;
+    assert_eq!(statuses, vec![200, 500, 200]);
+
+    log::info!("All done :)");
+}
+
+/// Make sure requests keep flowing to the healthy upstream while another one's circuit is open
+#[tokio::test]
+async fn test_open_circuit_skips_upstream() {
+    init_logging();
+    let flaky_upstream = start_upstream(respond).await;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&flaky_upstream, &upstream.address],
+        Some(3600),
+        None,
+        &[
+            "--strategy",
+            "round-robin",
+            "--circuit-breaker-threshold",
+            "1",
+        ],
+    )
+    .await;
+
+    // The first request goes to the flaky upstream and opens its circuit, so all of the others go
+    // to the echo server
+    let statuses = get_statuses(&balancebeam, &["/fail-0", "/fail-1", "/fail-2", "/fail-3"]).await;
+    assert_eq!(statuses, vec![500, 200, 200, 200]);
+    assert_eq!(Box::new(upstream).stop().await, 3);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,197 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{init_logging, BalanceBeam, EchoServer, Server, SlowServer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Sends `data` to balancebeam without finishing the request, then returns everything balancebeam
+/// sends back before it hangs up.
+async fn send_stalled_request(balancebeam: &BalanceBeam, data: &[u8]) -> String {
+    let mut connection = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    connection.write_all(data).await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(
+        Duration::from_secs(5),
+        connection.read_to_end(&mut response),
+    )
+    .await
+    .expect("balancebeam did not hang up on a stalled request")
+    .unwrap();
+    String::from_utf8_lossy(&response).to_string()
+}
+
+/// Make sure a client that trickles in its headers (slowloris) is sent a 408 and disconnected
+#[tokio::test]
+async fn test_client_header_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--client-header-timeout", "1"],
+    )
+    .await;
+
+    let start = Instant::now();
+    let response = send_stalled_request(&balancebeam, b"GET / HTTP/1.1\r\nHost: exa").await;
+    assert!(response.starts_with("HTTP/1.1 408"));
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    assert_eq!(Box::new(upstream).stop().await, 0);
+
+    log::info!("All done :)");
+}
+
+/// Make sure the header timeout counts from when the client connects, not from when it starts
+/// sending, so a client that waits before trickling in its headers doesn't get extra time
+#[tokio::test]
+async fn test_client_header_timeout_from_connect() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--client-header-timeout", "2"],
+    )
+    .await;
+
+    let start = Instant::now();
+    let mut connection = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    connection
+        .write_all(b"GET / HTTP/1.1\r\nHost: exa")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    connection.read_to_end(&mut response).await.unwrap();
+    assert!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 408"));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_secs(2));
+    assert!(
+        elapsed < Duration::from_secs(3),
+        "Timed out after {:?}",
+        elapsed
+    );
+    assert_eq!(Box::new(upstream).stop().await, 0);
+
+    log::info!("All done :)");
+}
+
+/// Make sure a client that stops sending its request body partway through is sent a 408, whether
+/// the body is buffered or streamed to the upstream
+#[tokio::test]
+async fn test_client_body_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--client-body-timeout", "1"],
+    )
+    .await;
+
+    for method in ["PUT", "POST"] {
+        let request = format!(
+            "{} / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 10\r\n\r\nabc",
+            method
+        );
+        let response = send_stalled_request(&balancebeam, request.as_bytes()).await;
+        assert!(
+            response.starts_with("HTTP/1.1 408"),
+            "Unexpected response to stalled {}: {}",
+            method,
+            response
+        );
+    }
+
+    log::info!("All done :)");
+}
+
+/// Make sure an upstream that takes too long to respond gets the client a 504
+#[tokio::test]
+async fn test_upstream_response_timeout() {
+    init_logging();
+    let upstream = SlowServer::new(Duration::from_secs(3)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--upstream-response-timeout", "1"],
+    )
+    .await;
+
+    let start = Instant::now();
+    let response = reqwest::get(format!("http://{}/slow", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 504);
+    assert!(start.elapsed() < Duration::from_secs(3));
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Make sure an upstream that is slow but within the timeout still gets its response through
+#[tokio::test]
+async fn test_slow_upstream_within_timeout() {
+    init_logging();
+    let upstream = SlowServer::new(Duration::from_millis(500)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--upstream-response-timeout", "2"],
+    )
+    .await;
+
+    let response = reqwest::get(format!("http://{}/slow", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.text().await.unwrap(), "slow response");
+
+    log::info!("All done :)");
+}
+
+/// Make sure kept-alive connections that sit idle for too long are closed
+#[tokio::test]
+async fn test_keep_alive_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--keep-alive-timeout", "1"],
+    )
+    .await;
+
+    let mut connection = TcpStream::connect(&balancebeam.address).await.unwrap();
+    connection
+        .write_all(b"GET /first HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let start = Instant::now();
+    // The rest of the response may still be on its way, but after that the connection should be
+    // closed without another request being sent
+    let mut response = Vec::new();
+    tokio::time::timeout(
+        Duration::from_secs(5),
+        connection.read_to_end(&mut response),
+    )
+    .await
+    .expect("balancebeam did not close the idle connection")
+    .unwrap();
+    assert!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 200 OK"));
+    assert!(start.elapsed() >= Duration::from_secs(1));
+
+    log::info!("All done :)");
+}
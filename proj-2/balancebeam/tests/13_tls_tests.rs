@@ -0,0 +1,174 @@
+mod common;
+
+use std::sync::Arc;
+
+use common::{init_logging, BalanceBeam, EchoServer, TestCertificate};
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+/// Starts balancebeam in front of an echo server with an HTTPS listener, returning the HTTPS
+/// address
+async fn setup(
+    default: &TestCertificate,
+    extra_args: &[&str],
+) -> (BalanceBeam, EchoServer, String) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let tls_address = common::random_address();
+    let mut args = vec![
+        "--tls-bind",
+        &tls_address,
+        "--tls-cert",
+        default.cert_path.to_str().unwrap(),
+        "--tls-key",
+        default.key_path.to_str().unwrap(),
+    ];
+    args.extend_from_slice(extra_args);
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], None, None, &args).await;
+    (balancebeam, upstream, tls_address)
+}
+
+/// Connects to `address` over TLS asking for `host`, trusting only `trusted`.
+async fn connect(
+    address: &str,
+    host: &str,
+    trusted: &TestCertificate,
+) -> tokio_rustls::client::TlsStream<TcpStream> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(trusted.der.clone()).unwrap();
+    let mut config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let stream = TcpStream::connect(address)
+        .await
+        .expect("Could not connect to balancebeam");
+    TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from(host.to_string()).unwrap(), stream)
+        .await
+        .expect("TLS handshake failed")
+}
+
+/// Sends a GET request for `path`, then closes our side of the connection and returns everything
+/// balancebeam sends back
+async fn get(stream: &mut tokio_rustls::client::TlsStream<TcpStream>, path: &str) -> String {
+    let request = format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    // balancebeam may hang up without a close_notify, which rustls reports as an error
+    let _ = stream.read_to_end(&mut response).await;
+    String::from_utf8_lossy(&response).to_string()
+}
+
+/// Make sure requests are proxied over HTTPS, and that HTTP/1.1 is negotiated through ALPN by
+/// clients that don't offer HTTP/2
+#[tokio::test]
+async fn test_https_request() {
+    let certificate = TestCertificate::new(&["localhost"]);
+    let (_balancebeam, _upstream, tls_address) = setup(&certificate, &[]).await;
+
+    let mut stream = connect(&tls_address, "localhost", &certificate).await;
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+    let response = get(&mut stream, "/over-tls").await;
+    assert!(
+        response.starts_with("HTTP/1.1 200"),
+        "Unexpected response: {}",
+        response
+    );
+    assert!(response.contains("GET /over-tls HTTP/1.1"));
+
+    log::info!("All done :)");
+}
+
+/// Send a request with a body and another request in one write over a kept-alive HTTPS connection.
+/// The second request arrives in the same TLS record as the body of the first, so it is already
+/// decrypted by the time the first is done, and should be answered without waiting for anything
+/// more from the client.
+#[tokio::test]
+async fn test_https_pipelined_requests() {
+    let certificate = TestCertificate::new(&["localhost"]);
+    let (_balancebeam, _upstream, tls_address) = setup(&certificate, &[]).await;
+
+    let mut stream = connect(&tls_address, "localhost", &certificate).await;
+    // Longer than balancebeam reads along with the headers, so that the body is read on its own
+    let body = "x".repeat(8000);
+    let requests = format!(
+        "POST /first HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n{}\
+         GET /second HTTP/1.1\r\nHost: test\r\n\r\n",
+        body.len(),
+        body
+    );
+    stream.write_all(requests.as_bytes()).await.unwrap();
+    // Keep the connection open, so that only the pipelined bytes can get the second request going
+    let mut response = Vec::new();
+    let mut buffer = [0_u8; 4096];
+    let read_both = async {
+        while !String::from_utf8_lossy(&response).contains("GET /second HTTP/1.1") {
+            let n = stream.read(&mut buffer).await.unwrap();
+            assert!(n > 0, "balancebeam hung up before answering both requests");
+            response.extend_from_slice(&buffer[..n]);
+        }
+    };
+    tokio::time::timeout(std::time::Duration::from_secs(5), read_both)
+        .await
+        .expect("The second request was not answered");
+    assert!(String::from_utf8_lossy(&response).contains("POST /first HTTP/1.1"));
+
+    log::info!("All done :)");
+}
+
+/// Make sure the certificate for the host a client asks for through SNI is presented, and that
+/// the default certificate is presented for other hosts
+#[tokio::test]
+async fn test_sni_certificate_selection() {
+    let default = TestCertificate::new(&["other.test"]);
+    let example = TestCertificate::new(&["example.test"]);
+    let wildcard = TestCertificate::new(&["*.wildcard.test"]);
+    let (_balancebeam, _upstream, tls_address) = setup(
+        &default,
+        &[
+            "--tls-sni-cert",
+            &example.sni_arg("example.test"),
+            "--tls-sni-cert",
+            &wildcard.sni_arg("*.wildcard.test"),
+        ],
+    )
+    .await;
+
+    for (host, certificate) in [
+        ("example.test", &example),
+        ("www.wildcard.test", &wildcard),
+        ("other.test", &default),
+    ] {
+        log::info!("Connecting with SNI {}", host);
+        let mut stream = connect(&tls_address, host, certificate).await;
+        let presented = stream.get_ref().1.peer_certificates().unwrap()[0].clone();
+        assert_eq!(presented, certificate.der, "Wrong certificate for {}", host);
+        let response = get(&mut stream, "/").await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+    }
+
+    log::info!("All done :)");
+}
+
+/// Make sure the plaintext listener keeps working alongside the HTTPS one
+#[tokio::test]
+async fn test_plaintext_alongside_https() {
+    let certificate = TestCertificate::new(&["localhost"]);
+    let (balancebeam, _upstream, _) = setup(&certificate, &[]).await;
+
+    let response = balancebeam
+        .get("/plaintext")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response.contains("GET /plaintext HTTP/1.1"));
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,192 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{init_logging, BalanceBeam, TestCertificate};
+use hyper::service::service_fn;
+use hyper::{Body, Response};
+use tokio::net::TcpListener;
+use tokio::time::sleep;
+use tokio_rustls::TlsAcceptor;
+
+/// Starts an HTTPS upstream presenting `certificate`, returning its `https://` address. If
+/// `client_ca` is given, clients must present a certificate it vouches for. Every response says
+/// which path was asked for and whether the client presented a certificate.
+async fn start_https_upstream(
+    certificate: &TestCertificate,
+    client_ca: Option<&TestCertificate>,
+) -> String {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .unwrap();
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = rustls::RootCertStore::empty();
+            roots.add(client_ca.der.clone()).unwrap();
+            let verifier =
+                rustls::server::WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                    .build()
+                    .unwrap();
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let key_pem = std::fs::read(&certificate.key_path).unwrap();
+    let key = rustls_pemfile::private_key(&mut &key_pem[..])
+        .unwrap()
+        .unwrap();
+    let config = builder
+        .with_single_cert(vec![certificate.der.clone()], key)
+        .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let address = common::random_address();
+    let listener = TcpListener::bind(&address).await.unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::info!("HTTPS upstream: TLS handshake failed: {}", err);
+                        return;
+                    }
+                };
+                let client_cert = stream.get_ref().1.peer_certificates().is_some();
+                let service = service_fn(move |request: hyper::Request<Body>| async move {
+                    Ok::<_, hyper::Error>(Response::new(Body::from(format!(
+                        "path: {}, client cert: {}",
+                        request.uri().path(),
+                        client_cert
+                    ))))
+                });
+                let _ = hyper::server::conn::Http::new()
+                    .serve_connection(stream, service)
+                    .await;
+            });
+        }
+    });
+    format!("https://{}", address)
+}
+
+async fn get(balancebeam: &BalanceBeam, path: &str) -> (u16, String) {
+    let response = reqwest::get(format!("http://{}{}", balancebeam.address, path))
+        .await
+        .expect("Error sending request to balancebeam");
+    (response.status().as_u16(), response.text().await.unwrap())
+}
+
+/// Make sure requests are forwarded over TLS to https:// upstreams whose certificate is vouched for
+/// by the --upstream-ca bundle (the second request goes over a pooled TLS connection)
+#[tokio::test]
+async fn test_https_upstream() {
+    init_logging();
+    let certificate = TestCertificate::new(&["127.0.0.1"]);
+    let upstream = start_https_upstream(&certificate, None).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        None,
+        None,
+        &["--upstream-ca", certificate.cert_path.to_str().unwrap()],
+    )
+    .await;
+
+    for path in ["/first", "/second"] {
+        let (status, body) = get(&balancebeam, path).await;
+        assert_eq!(status, 200);
+        assert_eq!(body, format!("path: {}, client cert: false", path));
+    }
+
+    log::info!("All done :)");
+}
+
+/// Make sure an upstream with a certificate we don't trust isn't sent requests, unless certificate
+/// verification has been turned off
+#[tokio::test]
+async fn test_untrusted_upstream_certificate() {
+    init_logging();
+    let certificate = TestCertificate::new(&["127.0.0.1"]);
+    let other_ca = TestCertificate::new(&["127.0.0.1"]);
+    let upstream = start_https_upstream(&certificate, None).await;
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        None,
+        None,
+        &["--upstream-ca", other_ca.cert_path.to_str().unwrap()],
+    )
+    .await;
+    assert_eq!(get(&balancebeam, "/").await.0, 502);
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        None,
+        None,
+        &["--upstream-insecure-skip-verify"],
+    )
+    .await;
+    assert_eq!(get(&balancebeam, "/").await.0, 200);
+
+    log::info!("All done :)");
+}
+
+/// Make sure balancebeam presents its client certificate to upstreams that require one
+#[tokio::test]
+async fn test_upstream_client_certificate() {
+    init_logging();
+    let certificate = TestCertificate::new(&["127.0.0.1"]);
+    let client_certificate = TestCertificate::new(&["balancebeam"]);
+    let upstream = start_https_upstream(&certificate, Some(&client_certificate)).await;
+    let ca = certificate.cert_path.to_str().unwrap();
+
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream], None, None, &["--upstream-ca", ca]).await;
+    assert_eq!(get(&balancebeam, "/").await.0, 502);
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        None,
+        None,
+        &[
+            "--upstream-ca",
+            ca,
+            "--upstream-client-cert",
+            client_certificate.cert_path.to_str().unwrap(),
+            "--upstream-client-key",
+            client_certificate.key_path.to_str().unwrap(),
+        ],
+    )
+    .await;
+    let (status, body) = get(&balancebeam, "/mtls").await;
+    assert_eq!(status, 200);
+    assert_eq!(body, "path: /mtls, client cert: true");
+
+    log::info!("All done :)");
+}
+
+/// Make sure active health checks reach https:// upstreams over TLS (a plaintext probe would fail
+/// and take the upstream out of rotation)
+#[tokio::test]
+async fn test_https_upstream_health_check() {
+    init_logging();
+    let certificate = TestCertificate::new(&["127.0.0.1"]);
+    let upstream = start_https_upstream(&certificate, None).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        Some(1),
+        None,
+        &["--upstream-ca", certificate.cert_path.to_str().unwrap()],
+    )
+    .await;
+
+    sleep(Duration::from_millis(2500)).await;
+    let (status, body) = get(&balancebeam, "/after-health-checks").await;
+    assert_eq!(status, 200);
+    assert_eq!(body, "path: /after-health-checks, client cert: false");
+
+    log::info!("All done :)");
+}
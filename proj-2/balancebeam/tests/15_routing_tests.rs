@@ -0,0 +1,219 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{init_logging, start_balancebeam, start_upstream, write_config_file, BalanceBeam};
+use hyper::{Body, Response};
+use tokio::time::sleep;
+
+/// Starts an upstream that answers every request with its `name`, except for requests for
+/// /unhealthy, which get a 500. Returns its address.
+async fn start_named_upstream(name: &'static str) -> String {
+    start_upstream(move |request| {
+        if request.uri().path() == "/unhealthy" {
+            Response::builder()
+                .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .unwrap()
+        } else {
+            Response::new(Body::from(name))
+        }
+    })
+    .await
+}
+
+/// Sends a request and returns the status along with the body (the name of the upstream that
+/// answered it)
+async fn send(
+    balancebeam: &BalanceBeam,
+    method: reqwest::Method,
+    host: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+) -> (u16, String) {
+    let mut request = reqwest::Client::new()
+        .request(method, format!("http://{}{}", balancebeam.address, path))
+        .header("Host", host);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    (response.status().as_u16(), response.text().await.unwrap())
+}
+
+/// Make sure requests go to the pool of the first route they match, and to the default pool if
+/// they match none
+#[tokio::test]
+async fn test_routes_select_pools() {
+    init_logging();
+    let config = format!(
+        r#"
+[health_check]
+interval = 0
+
+[[upstreams]]
+address = "{}"
+
+[[routes]]
+pool = "admin"
+host = "example.com"
+path_prefix = "/admin"
+# Methods are upper-cased when the configuration is loaded
+methods = ["post"]
+
+[[routes]]
+pool = "api"
+host = "*.example.com"
+path_prefix = "/api"
+
+[[routes]]
+pool = "canary"
+headers = {{ "X-Canary" = "yes" }}
+
+[[pools]]
+name = "api"
+[[pools.upstreams]]
+address = "{}"
+
+[[pools]]
+name = "admin"
+[[pools.upstreams]]
+address = "{}"
+
+[[pools]]
+name = "canary"
+[[pools.upstreams]]
+address = "{}"
+"#,
+        start_named_upstream("default").await,
+        start_named_upstream("api").await,
+        start_named_upstream("admin").await,
+        start_named_upstream("canary").await,
+    );
+    let config_path = write_config_file("balancebeam-routing", "select.toml", &config);
+    let balancebeam = start_balancebeam(&config_path).await;
+
+    // (method, host, path, extra headers, pool the request should go to)
+    type Case<'a> = (&'a str, &'a str, &'a str, &'a [(&'a str, &'a str)], &'a str);
+    let cases: &[Case] = &[
+        ("GET", "www.example.com", "/api", &[], "api"),
+        ("GET", "WWW.Example.com:1100", "/api/users", &[], "api"),
+        ("GET", "www.example.com", "/apiary", &[], "default"),
+        ("GET", "example.com", "/api/users", &[], "default"),
+        ("POST", "example.com", "/admin/users", &[], "admin"),
+        ("GET", "example.com", "/admin/users", &[], "default"),
+        ("GET", "other.com", "/", &[("x-canary", "yes")], "canary"),
+        ("GET", "other.com", "/", &[("x-canary", "no")], "default"),
+        // Earlier routes win
+        (
+            "GET",
+            "www.example.com",
+            "/api",
+            &[("x-canary", "yes")],
+            "api",
+        ),
+    ];
+    for (method, host, path, headers, pool) in cases {
+        log::info!(
+            "{} {}{} {:?} should go to {}",
+            method,
+            host,
+            path,
+            headers,
+            pool
+        );
+        let method = reqwest::Method::from_bytes(method.as_bytes()).unwrap();
+        let (status, body) = send(&balancebeam, method, host, path, headers).await;
+        assert_eq!(status, 200);
+        assert_eq!(&body, pool);
+    }
+
+    std::fs::remove_file(config_path).unwrap();
+    log::info!("All done :)");
+}
+
+/// Make sure each pool has its own rate limit, falling back to the top-level one
+#[tokio::test]
+async fn test_rate_limit_per_pool() {
+    init_logging();
+    let config = format!(
+        r#"
+max_requests_per_minute = 3
+
+[health_check]
+interval = 0
+
+[[upstreams]]
+address = "{}"
+
+[[routes]]
+pool = "api"
+path_prefix = "/api"
+
+[[pools]]
+name = "api"
+max_requests_per_minute = 1
+[[pools.upstreams]]
+address = "{}"
+"#,
+        start_named_upstream("default").await,
+        start_named_upstream("api").await,
+    );
+    let config_path = write_config_file("balancebeam-routing", "rate-limit.toml", &config);
+    let balancebeam = start_balancebeam(&config_path).await;
+
+    let mut statuses = Vec::new();
+    for path in ["/api", "/api", "/", "/", "/", "/"] {
+        statuses.push(
+            send(&balancebeam, reqwest::Method::GET, "test", path, &[])
+                .await
+                .0,
+        );
+    }
+    assert_eq!(statuses, vec![200, 429, 200, 200, 200, 429]);
+
+    std::fs::remove_file(config_path).unwrap();
+    log::info!("All done :)");
+}
+
+/// Make sure each pool is health checked on its own path. The same upstream is in both pools, but
+/// only fails the health check of one of them.
+#[tokio::test]
+async fn test_health_check_path_per_pool() {
+    init_logging();
+    let upstream = start_named_upstream("shared").await;
+    let config = format!(
+        r#"
+[health_check]
+interval = 1
+path = "/healthy"
+
+[[upstreams]]
+address = "{upstream}"
+
+[[routes]]
+pool = "strict"
+path_prefix = "/strict"
+
+[[pools]]
+name = "strict"
+health_check_path = "/unhealthy"
+[[pools.upstreams]]
+address = "{upstream}"
+"#,
+    );
+    let config_path = write_config_file("balancebeam-routing", "health-check.toml", &config);
+    let balancebeam = start_balancebeam(&config_path).await;
+
+    sleep(Duration::from_millis(2500)).await;
+    let (status, body) = send(&balancebeam, reqwest::Method::GET, "test", "/", &[]).await;
+    assert_eq!((status, body.as_str()), (200, "shared"));
+    let (status, _) = send(&balancebeam, reqwest::Method::GET, "test", "/strict", &[]).await;
+    assert_eq!(status, 502);
+
+    std::fs::remove_file(config_path).unwrap();
+    log::info!("All done :)");
+}
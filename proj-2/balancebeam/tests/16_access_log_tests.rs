@@ -0,0 +1,214 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::time::sleep;
+
+/// Returns a fresh access log path in the temp directory, removing files left over from a
+/// previous run.
+fn access_log_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-access-{}-{}.log",
+        name,
+        std::process::id()
+    ));
+    for n in 0..5 {
+        let _ = std::fs::remove_file(rotated(&path, n));
+    }
+    path
+}
+
+/// Returns the path of the `n`th rotated file (or the live file for n = 0).
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    if n == 0 {
+        return path.to_path_buf();
+    }
+    PathBuf::from(format!("{}.{}", path.display(), n))
+}
+
+/// Waits for the access log to hold `count` lines (an entry is written once balancebeam is done
+/// with the request, which may be just after the client has the response), then returns them.
+async fn read_lines(path: &Path, count: usize) -> Vec<String> {
+    for _ in 0..20 {
+        let contents = std::fs::read_to_string(path).unwrap_or_default();
+        let lines: Vec<String> = contents.lines().map(str::to_string).collect();
+        if lines.len() >= count {
+            return lines;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    panic!("The access log never got {} lines", count);
+}
+
+/// Make sure every request gets a line in Apache's combined format, with the request body size,
+/// upstream and latencies tacked on
+#[tokio::test]
+async fn test_combined_format() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = access_log_path("combined");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--access-log", path.to_str().unwrap()],
+    )
+    .await;
+
+    let response_text = reqwest::Client::new()
+        .get(format!("http://{}/first?q=\"x\"", balancebeam.address))
+        .header("User-Agent", "test-agent")
+        .header("Referer", "http://example.com/")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    balancebeam.post("/second", "twelve bytes").await.unwrap();
+
+    let lines = read_lines(&path, 2).await;
+    log::info!("Access log:\n{}", lines.join("\n"));
+    assert_eq!(lines.len(), 2);
+    let fields: Vec<&str> = lines[0].splitn(6, ' ').collect();
+    assert_eq!(&fields[..3], &["127.0.0.1", "-", "-"]);
+    assert!(fields[3].starts_with('[') && fields[4].ends_with(']'));
+    let rest = format!(
+        "\"GET /first?q=%22x%22 HTTP/1.1\" 200 {} \"http://example.com/\" \"test-agent\" 0 \"{}\" ",
+        response_text.len(),
+        upstream.address
+    );
+    assert!(
+        fields[5].starts_with(&rest),
+        "{} should start with {}",
+        fields[5],
+        rest
+    );
+    let latencies: Vec<f64> = fields[5][rest.len()..]
+        .split(' ')
+        .map(|latency| latency.parse().unwrap())
+        .collect();
+    assert_eq!(latencies.len(), 2);
+    assert!(latencies[0] <= latencies[1]);
+
+    assert!(
+        lines[1].contains("\"POST /second HTTP/1.1\" 200 "),
+        "{}",
+        lines[1]
+    );
+    assert!(lines[1].contains(&format!(" 12 \"{}\" ", upstream.address)));
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Make sure JSON lines carry every field, including for requests balancebeam answers itself
+#[tokio::test]
+async fn test_json_format() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = access_log_path("json");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        Some(1),
+        &[
+            "--access-log",
+            path.to_str().unwrap(),
+            "--access-log-format",
+            "json",
+        ],
+    )
+    .await;
+
+    let response_text = balancebeam.get("/allowed").await.unwrap();
+    let status = reqwest::get(format!("http://{}/limited", balancebeam.address))
+        .await
+        .unwrap()
+        .status();
+    assert_eq!(status.as_u16(), 429);
+
+    let lines = read_lines(&path, 2).await;
+    log::info!("Access log:\n{}", lines.join("\n"));
+    let entries: Vec<serde_json::Value> = lines
+        .iter()
+        .map(|line| serde_json::from_str(line).expect("Access log line isn't JSON"))
+        .collect();
+
+    let allowed = &entries[0];
+    assert_eq!(allowed["client_ip"], "127.0.0.1");
+    assert_eq!(allowed["method"], "GET");
+    assert_eq!(allowed["uri"], "/allowed");
+    assert_eq!(allowed["protocol"], "HTTP/1.1");
+    assert_eq!(allowed["status"], 200);
+    assert_eq!(allowed["bytes_in"], 0);
+    assert_eq!(allowed["bytes_out"], response_text.len());
+    assert_eq!(allowed["upstream"], upstream.address.as_str());
+    assert!(allowed["time"].is_string());
+    let upstream_latency = allowed["upstream_latency"].as_f64().unwrap();
+    assert!(upstream_latency <= allowed["total_latency"].as_f64().unwrap());
+
+    let limited = &entries[1];
+    assert_eq!(limited["uri"], "/limited");
+    assert_eq!(limited["status"], 429);
+    assert!(limited["upstream"].is_null());
+    assert!(limited["upstream_latency"].is_null());
+    assert!(limited["total_latency"].is_f64());
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Make sure the log is rotated once it grows past its maximum size, keeping only as many old
+/// files as asked for
+#[tokio::test]
+async fn test_rotation() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = access_log_path("rotation");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--access-log",
+            path.to_str().unwrap(),
+            "--access-log-max-size",
+            "300",
+            "--access-log-max-files",
+            "2",
+        ],
+    )
+    .await;
+
+    for i in 0..10 {
+        balancebeam.get(&format!("/request-{}", i)).await.unwrap();
+    }
+    // Wait for the last request to be logged
+    sleep(Duration::from_millis(200)).await;
+
+    let newest = std::fs::read_to_string(&path).unwrap();
+    assert!(newest.contains("/request-9 "));
+    let mut num_lines = 0;
+    for n in 0..=2 {
+        let contents = std::fs::read_to_string(rotated(&path, n)).unwrap();
+        assert!(
+            contents.len() <= 300,
+            "File {} is {} bytes",
+            n,
+            contents.len()
+        );
+        assert!(!contents.is_empty());
+        num_lines += contents.lines().count();
+    }
+    assert!(!rotated(&path, 3).exists());
+    // The oldest requests have been rotated out of existence
+    assert!(num_lines < 10);
+    let oldest = std::fs::read_to_string(rotated(&path, 2)).unwrap();
+    assert!(!oldest.contains("/request-0 "));
+
+    assert_eq!(Box::new(upstream).stop().await, 10);
+    log::info!("All done :)");
+}
@@ -0,0 +1,179 @@
+mod common;
+
+use common::{echoed_header, init_logging, BalanceBeam, EchoServer};
+
+/// Sends a GET with the given headers and returns the status and body (which the echo server fills
+/// with the request it got).
+async fn get_with_headers(balancebeam: &BalanceBeam, headers: &[(&str, &str)]) -> (u16, String) {
+    let mut request = reqwest::Client::new().get(format!("http://{}/", balancebeam.address));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    (response.status().as_u16(), response.text().await.unwrap())
+}
+
+/// Make sure the upstream is told who connected to us, over which protocol and for which host.
+/// Lists sent by an untrusted client are extended rather than believed, and the single-valued
+/// headers are overwritten.
+#[tokio::test]
+async fn test_forwarding_headers_from_untrusted_client() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let (status, echoed) = get_with_headers(
+        &balancebeam,
+        &[
+            ("X-Forwarded-For", "203.0.113.9"),
+            ("X-Forwarded-Proto", "https"),
+            ("X-Forwarded-Host", "spoofed.example.com"),
+            ("Forwarded", "for=203.0.113.9"),
+        ],
+    )
+    .await;
+    assert_eq!(status, 200);
+    log::info!("Upstream got:\n{}", echoed);
+    assert_eq!(
+        echoed_header(&echoed, "x-forwarded-for"),
+        Some("203.0.113.9, 127.0.0.1")
+    );
+    assert_eq!(echoed_header(&echoed, "x-forwarded-proto"), Some("http"));
+    assert_eq!(
+        echoed_header(&echoed, "x-forwarded-host"),
+        Some(balancebeam.address.as_str())
+    );
+    assert_eq!(
+        echoed_header(&echoed, "forwarded"),
+        Some(
+            format!(
+                "for=203.0.113.9, for=127.0.0.1;proto=http;host=\"{}\"",
+                balancebeam.address
+            )
+            .as_str()
+        )
+    );
+
+    log::info!("All done :)");
+}
+
+/// Make sure forwarding headers from untrusted clients can be thrown away entirely
+#[tokio::test]
+async fn test_strip_untrusted_forwarding_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--strip-untrusted-forwarded-headers"],
+    )
+    .await;
+
+    let (_, echoed) = get_with_headers(
+        &balancebeam,
+        &[
+            ("X-Forwarded-For", "203.0.113.9"),
+            ("Forwarded", "for=203.0.113.9"),
+        ],
+    )
+    .await;
+    log::info!("Upstream got:\n{}", echoed);
+    assert_eq!(echoed_header(&echoed, "x-forwarded-for"), Some("127.0.0.1"));
+    assert!(echoed_header(&echoed, "forwarded")
+        .unwrap()
+        .starts_with("for=127.0.0.1;"));
+
+    log::info!("All done :)");
+}
+
+/// Make sure a trusted proxy's forwarding headers are kept, and that the client it names is the one
+/// that gets rate limited
+#[tokio::test]
+async fn test_trusted_proxy() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        Some(1),
+        &[
+            "--trusted-proxy",
+            "127.0.0.1",
+            "--trusted-proxy",
+            "10.0.0.0/8",
+            "--strip-untrusted-forwarded-headers",
+        ],
+    )
+    .await;
+
+    // 10.0.0.2 is another trusted proxy, so the client is 203.0.113.9
+    let (status, echoed) = get_with_headers(
+        &balancebeam,
+        &[
+            ("X-Forwarded-For", "198.51.100.1, 203.0.113.9, 10.0.0.2"),
+            ("X-Forwarded-Proto", "https"),
+            ("X-Forwarded-Host", "www.example.com"),
+        ],
+    )
+    .await;
+    assert_eq!(status, 200);
+    log::info!("Upstream got:\n{}", echoed);
+    assert_eq!(
+        echoed_header(&echoed, "x-forwarded-for"),
+        Some("198.51.100.1, 203.0.113.9, 10.0.0.2, 127.0.0.1")
+    );
+    assert_eq!(echoed_header(&echoed, "x-forwarded-proto"), Some("https"));
+    assert_eq!(
+        echoed_header(&echoed, "x-forwarded-host"),
+        Some("www.example.com")
+    );
+
+    // A different client gets its own allowance, even through the same proxy
+    let (status, _) = get_with_headers(&balancebeam, &[("X-Forwarded-For", "198.51.100.1")]).await;
+    assert_eq!(status, 200);
+    // The Forwarded header is used when there is no X-Forwarded-For
+    let (status, _) = get_with_headers(
+        &balancebeam,
+        &[("Forwarded", "for=198.51.100.2, for=\"10.0.0.3:4711\"")],
+    )
+    .await;
+    assert_eq!(status, 200);
+    let (status, _) = get_with_headers(
+        &balancebeam,
+        &[("Forwarded", "for=\"[2001:db8::1]:4711\";proto=https")],
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    // But each client's second request is limited
+    for headers in [
+        &[("X-Forwarded-For", "203.0.113.9")][..],
+        &[("X-Forwarded-For", "198.51.100.2, 10.0.0.3")][..],
+        &[("Forwarded", "for=\"[2001:db8::1]\"")][..],
+    ] {
+        let (status, _) = get_with_headers(&balancebeam, headers).await;
+        assert_eq!(status, 429, "{:?} should be rate limited", headers);
+    }
+
+    log::info!("All done :)");
+}
+
+/// Make sure clients that aren't trusted proxies can't dodge rate limiting by claiming to be
+/// forwarding for someone else
+#[tokio::test]
+async fn test_untrusted_client_cannot_spoof_rate_limit() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, Some(1)).await;
+
+    let (status, _) = get_with_headers(&balancebeam, &[("X-Forwarded-For", "203.0.113.9")]).await;
+    assert_eq!(status, 200);
+    let (status, _) = get_with_headers(&balancebeam, &[("X-Forwarded-For", "203.0.113.10")]).await;
+    assert_eq!(status, 429);
+
+    log::info!("All done :)");
+}
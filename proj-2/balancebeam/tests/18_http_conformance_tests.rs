@@ -0,0 +1,233 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{init_logging, send_raw_request, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Returns the value of header `name` in a request echoed back by the echo server (or in a
+/// response, since both are `name: value` lines).
+fn header_value<'a>(text: &'a str, name: &str) -> Option<&'a str> {
+    text.lines().find_map(|line| {
+        let (line_name, value) = line.split_once(": ")?;
+        line_name.eq_ignore_ascii_case(name).then_some(value)
+    })
+}
+
+/// Splits a raw response into its head and body.
+fn split_response(response: &str) -> (&str, &str) {
+    response
+        .split_once("\r\n\r\n")
+        .expect("Response has no end of headers")
+}
+
+/// Make sure headers that only concern the client's connection to us aren't passed on, and that
+/// the request and response both say they went through us
+#[tokio::test]
+async fn test_hop_by_hop_request_headers_are_stripped() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let response = send_raw_request(
+        &balancebeam.address,
+        b"GET / HTTP/1.1\r\n\
+          Host: example.com\r\n\
+          Connection: close, X-Connection-Secret\r\n\
+          X-Connection-Secret: hunter2\r\n\
+          Keep-Alive: timeout=5\r\n\
+          Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\
+          Proxy-Connection: keep-alive\r\n\
+          TE: trailers\r\n\
+          Via: 1.1 some-other-proxy\r\n\
+          X-Kept: yes\r\n\
+          \r\n",
+    )
+    .await;
+    log::info!("Response:\n{}", response);
+    let (head, echoed) = split_response(&response);
+    assert!(head.starts_with("HTTP/1.1 200"));
+
+    for name in [
+        "connection",
+        "x-connection-secret",
+        "keep-alive",
+        "proxy-authorization",
+        "proxy-connection",
+        "te",
+    ] {
+        assert_eq!(
+            header_value(echoed, name),
+            None,
+            "{} should not have been forwarded",
+            name
+        );
+    }
+    assert_eq!(header_value(echoed, "x-kept"), Some("yes"));
+    assert_eq!(
+        header_value(echoed, "via"),
+        Some("1.1 some-other-proxy, 1.1 balancebeam")
+    );
+    assert_eq!(header_value(head, "via"), Some("1.1 balancebeam"));
+    // The client asked to close the connection, which send_raw_request would have hung on otherwise
+    assert_eq!(header_value(head, "connection"), Some("close"));
+
+    log::info!("All done :)");
+}
+
+/// Make sure hop-by-hop headers sent by the upstream don't reach the client
+#[tokio::test]
+async fn test_hop_by_hop_response_headers_are_stripped() {
+    init_logging();
+    let upstream_listener = TcpListener::bind(common::random_address()).await.unwrap();
+    let upstream_address = upstream_listener.local_addr().unwrap().to_string();
+    let upstream = tokio::spawn(async move {
+        let (mut stream, _) = upstream_listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            let mut byte = [0; 1];
+            if stream.read(&mut byte).await.unwrap() == 0 {
+                break;
+            }
+            request.push(byte[0]);
+        }
+        stream
+            .write_all(
+                b"HTTP/1.1 200 OK\r\n\
+                  Connection: X-Upstream-Secret\r\n\
+                  X-Upstream-Secret: hunter2\r\n\
+                  Keep-Alive: timeout=5, max=100\r\n\
+                  Proxy-Authenticate: Basic realm=\"upstream\"\r\n\
+                  X-Kept: yes\r\n\
+                  Content-Length: 2\r\n\
+                  \r\n\
+                  ok",
+            )
+            .await
+            .unwrap();
+        // Keep the connection open until balancebeam is done with it
+        let _ = stream.read(&mut [0; 1]).await;
+    });
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let response = send_raw_request(
+        &balancebeam.address,
+        b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    log::info!("Response:\n{}", response);
+    let (head, body) = split_response(&response);
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert_eq!(body, "ok");
+    for name in ["x-upstream-secret", "keep-alive", "proxy-authenticate"] {
+        assert_eq!(
+            header_value(head, name),
+            None,
+            "{} should not have been forwarded",
+            name
+        );
+    }
+    assert_eq!(header_value(head, "x-kept"), Some("yes"));
+    assert_eq!(header_value(head, "via"), Some("1.1 balancebeam"));
+
+    drop(balancebeam);
+    upstream.abort();
+    log::info!("All done :)");
+}
+
+/// Make sure HTTP/1.0 requests are passed on as HTTP/1.0, and that the client's connection is
+/// closed after the response unless it asked for keep-alive
+#[tokio::test]
+async fn test_http_10_client() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    // Without keep-alive the connection is closed once the response has been sent. We don't shut
+    // down our side of the connection, so this would hang if balancebeam kept it open.
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"GET /old HTTP/1.0\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam didn't close the HTTP/1.0 connection")
+        .unwrap();
+    let response = String::from_utf8(response).unwrap();
+    log::info!("Response:\n{}", response);
+    let (head, echoed) = split_response(&response);
+    assert_eq!(header_value(head, "connection"), Some("close"));
+    assert!(echoed.starts_with("GET /old HTTP/1.0\n"), "{}", echoed);
+    assert_eq!(header_value(echoed, "via"), Some("1.0 balancebeam"));
+
+    // With keep-alive the connection can carry more requests
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for path in ["/first", "/second"] {
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: example.com\r\nConnection: keep-alive\r\n\r\n",
+            path
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let head = read_head(&mut stream).await;
+        log::info!("Response head:\n{}", head);
+        assert_eq!(header_value(&head, "connection"), Some("keep-alive"));
+        let length: usize = header_value(&head, "content-length")
+            .expect("Response has no Content-Length")
+            .parse()
+            .unwrap();
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.unwrap();
+        assert!(String::from_utf8(body)
+            .unwrap()
+            .starts_with(&format!("GET {} HTTP/1.0\n", path)));
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Make sure an HTTP/1.0 client is never sent a chunked body (which it wouldn't understand): the
+/// body is sent as is, and the connection closed at the end of it
+#[tokio::test]
+async fn test_http_10_client_gets_unchunked_body() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let response = send_raw_request(
+        &balancebeam.address,
+        b"GET /streamed HTTP/1.0\r\n\
+          Host: example.com\r\n\
+          Connection: keep-alive\r\n\
+          X-Echo-Chunked: yes\r\n\
+          \r\n",
+    )
+    .await;
+    log::info!("Response:\n{}", response);
+    let (head, body) = split_response(&response);
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert_eq!(header_value(head, "transfer-encoding"), None);
+    assert_eq!(header_value(head, "connection"), Some("close"));
+    // The body is the echoed request, with no chunk sizes mixed in
+    assert!(body.starts_with("GET /streamed HTTP/1.0\n"), "{}", body);
+    assert!(body.ends_with("\n\n"), "{:?}", body);
+    assert_eq!(header_value(body, "x-echo-chunked"), Some("yes"));
+
+    log::info!("All done :)");
+}
+
+/// Reads a response head (up to and including the blank line) from `stream`.
+async fn read_head(stream: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0; 1];
+        if stream.read(&mut byte).await.unwrap() == 0 {
+            panic!("Connection closed before the end of the response head");
+        }
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
@@ -0,0 +1,123 @@
+mod common;
+
+use common::{init_logging, send_raw_request, BalanceBeam, EchoServer, Server};
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Starts a WebSocket server that sends every message it gets straight back. Returns its address.
+async fn start_websocket_echo_server() -> String {
+    let listener = TcpListener::bind(common::random_address()).await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut websocket = match tokio_tungstenite::accept_async(stream).await {
+                    Ok(websocket) => websocket,
+                    Err(error) => {
+                        log::warn!("WebSocket handshake failed: {}", error);
+                        return;
+                    }
+                };
+                while let Some(Ok(message)) = websocket.next().await {
+                    if message.is_close() {
+                        break;
+                    }
+                    if websocket.send(message).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Make sure a WebSocket connection is upgraded end to end, with messages going both ways through
+/// balancebeam until the client closes it
+#[tokio::test]
+async fn test_websocket_echo() {
+    init_logging();
+    let upstream_address = start_websocket_echo_server().await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let (mut websocket, response) =
+        tokio_tungstenite::connect_async(format!("ws://{}/chat", balancebeam.address))
+            .await
+            .expect("WebSocket handshake through balancebeam failed");
+    assert_eq!(response.status().as_u16(), 101);
+    assert_eq!(
+        response.headers().get("via").unwrap().to_str().unwrap(),
+        "1.1 balancebeam"
+    );
+
+    let messages = [
+        Message::Text("hello".to_string()),
+        Message::Binary(vec![0, 1, 2, 255]),
+        Message::Text("x".repeat(100_000)),
+    ];
+    for message in messages {
+        websocket.send(message.clone()).await.unwrap();
+        let echoed = websocket.next().await.unwrap().unwrap();
+        assert_eq!(echoed, message);
+    }
+    websocket.close(None).await.unwrap();
+    // The close handshake is relayed too, after which the stream ends
+    while let Some(message) = websocket.next().await {
+        assert!(message.map(|message| message.is_close()).unwrap_or(true));
+    }
+
+    // A new connection can be upgraded just the same
+    let (mut websocket, _) =
+        tokio_tungstenite::connect_async(format!("ws://{}/again", balancebeam.address))
+            .await
+            .unwrap();
+    websocket
+        .send(Message::Text("again".to_string()))
+        .await
+        .unwrap();
+    assert_eq!(
+        websocket.next().await.unwrap().unwrap(),
+        Message::Text("again".to_string())
+    );
+
+    log::info!("All done :)");
+}
+
+/// Make sure the upgrade headers reach the upstream, and that an upstream that doesn't switch
+/// protocols answers the request like any other
+#[tokio::test]
+async fn test_upgrade_declined() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let response = send_raw_request(
+        &balancebeam.address,
+        b"GET /chat HTTP/1.1\r\n\
+          Host: example.com\r\n\
+          Connection: Upgrade, close\r\n\
+          Upgrade: websocket\r\n\
+          Sec-WebSocket-Version: 13\r\n\
+          Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+          \r\n",
+    )
+    .await;
+    log::info!("Response:\n{}", response);
+    assert!(response.starts_with("HTTP/1.1 200"));
+    let echoed = response.split_once("\r\n\r\n").unwrap().1;
+    assert!(echoed.contains("\nconnection: upgrade\n"), "{}", echoed);
+    assert!(echoed.contains("\nupgrade: websocket\n"), "{}", echoed);
+
+    // An HTTP/1.0 client can't upgrade its connection, so the headers are dropped
+    let response = send_raw_request(
+        &balancebeam.address,
+        b"GET /chat HTTP/1.0\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n",
+    )
+    .await;
+    let echoed = response.split_once("\r\n\r\n").unwrap().1;
+    assert!(!echoed.contains("\nupgrade: "), "{}", echoed);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
@@ -0,0 +1,246 @@
+mod common;
+
+use std::sync::Arc;
+
+use common::{echoed_header, init_logging, BalanceBeam, EchoServer, Server, TestCertificate};
+use futures_util::future::join_all;
+use hyper::{Body, Request};
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsConnector;
+
+/// Reads an HTTP/2 response, returning the status and body text.
+async fn read_response(
+    response: Result<hyper::Response<Body>, hyper::Error>,
+) -> Result<(u16, String), hyper::Error> {
+    let response = response?;
+    assert_eq!(response.version(), hyper::Version::HTTP_2);
+    let status = response.status().as_u16();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Ok((status, String::from_utf8_lossy(&body).to_string()))
+}
+
+/// Returns a client that speaks HTTP/2 with prior knowledge (h2c), sending all its requests over a
+/// single connection.
+fn h2c_client() -> hyper::Client<hyper::client::HttpConnector> {
+    hyper::Client::builder().http2_only(true).build_http()
+}
+
+/// Sends `request` with an h2c client, returning the status and body text.
+async fn send(
+    client: &hyper::Client<hyper::client::HttpConnector>,
+    request: Request<Body>,
+) -> Result<(u16, String), hyper::Error> {
+    read_response(client.request(request).await).await
+}
+
+/// Make sure plaintext clients can speak HTTP/2 with prior knowledge, with many requests in flight
+/// on one connection, each reaching the upstream as an HTTP/1.1 request
+#[tokio::test]
+async fn test_h2c_prior_knowledge() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let client = h2c_client();
+
+    let responses = join_all((0..20).map(|i| {
+        let request = Request::get(format!("http://{}/stream-{}", balancebeam.address, i))
+            .header("cookie", "a=1")
+            .header("cookie", "b=2")
+            .body(Body::empty())
+            .unwrap();
+        let client = &client;
+        async move { send(client, request).await.unwrap() }
+    }))
+    .await;
+    for (i, (status, echoed)) in responses.into_iter().enumerate() {
+        assert_eq!(status, 200);
+        assert!(
+            echoed.starts_with(&format!("GET /stream-{} HTTP/1.1\n", i)),
+            "{}",
+            echoed
+        );
+        assert_eq!(
+            echoed_header(&echoed, "host"),
+            Some(balancebeam.address.as_str())
+        );
+        assert_eq!(echoed_header(&echoed, "cookie"), Some("a=1; b=2"));
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 20);
+    log::info!("All done :)");
+}
+
+/// Make sure bodies of unknown length go both ways, and that bodies much bigger than the HTTP/2 flow
+/// control windows get through
+#[tokio::test]
+async fn test_h2c_bodies() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let client = h2c_client();
+
+    // A streamed request body, which goes upstream chunked, with a chunked response
+    let (mut body_sender, body) = Body::channel();
+    tokio::spawn(async move {
+        for piece in ["first piece, ", "second piece"] {
+            body_sender.send_data(piece.into()).await.unwrap();
+        }
+    });
+    let request = Request::post(format!("http://{}/streamed", balancebeam.address))
+        .header("x-echo-chunked", "yes")
+        .body(body)
+        .unwrap();
+    let (status, echoed) = send(&client, request).await.unwrap();
+    assert_eq!(status, 200);
+    assert_eq!(echoed_header(&echoed, "transfer-encoding"), Some("chunked"));
+    assert!(
+        echoed.ends_with("\n\nfirst piece, second piece"),
+        "{}",
+        echoed
+    );
+
+    // A large body with a Content-Length, echoed straight back
+    let large_body = "0123456789abcdef".repeat(256 * 1024);
+    let request = Request::post(format!("http://{}/large", balancebeam.address))
+        .header("content-length", large_body.len())
+        .body(Body::from(large_body.clone()))
+        .unwrap();
+    let (status, echoed) = send(&client, request).await.unwrap();
+    assert_eq!(status, 200);
+    assert_eq!(
+        echoed_header(&echoed, "content-length"),
+        Some(large_body.len().to_string().as_str())
+    );
+    assert!(echoed.ends_with(&large_body));
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Make sure HTTP/2 is negotiated through ALPN on the HTTPS listener
+#[tokio::test]
+async fn test_h2_over_tls() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let certificate = TestCertificate::new(&["localhost"]);
+    let tls_address = common::random_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--tls-bind",
+            &tls_address,
+            "--tls-cert",
+            certificate.cert_path.to_str().unwrap(),
+            "--tls-key",
+            certificate.key_path.to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(certificate.der.clone()).unwrap();
+    let mut config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let stream = TcpStream::connect(&tls_address).await.unwrap();
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .expect("TLS handshake failed");
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+    let (mut sender, connection) = hyper::client::conn::Builder::new()
+        .http2_only(true)
+        .handshake(stream)
+        .await
+        .expect("HTTP/2 handshake failed");
+    tokio::spawn(connection);
+
+    let request = Request::get("https://localhost/over-tls")
+        .body(Body::empty())
+        .unwrap();
+    let (status, echoed) = read_response(sender.send_request(request).await)
+        .await
+        .unwrap();
+    assert_eq!(status, 200);
+    assert!(echoed.starts_with("GET /over-tls HTTP/1.1\n"), "{}", echoed);
+    assert_eq!(echoed_header(&echoed, "host"), Some("localhost"));
+    assert_eq!(echoed_header(&echoed, "x-forwarded-proto"), Some("https"));
+
+    drop(balancebeam);
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that answers /broken with a response cut off partway through its body, and
+/// anything else with "ok". Returns its address.
+async fn start_flaky_upstream() -> String {
+    let listener = TcpListener::bind(common::random_address()).await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0; 1];
+                    if stream.read(&mut byte).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    request.push(byte[0]);
+                }
+                let response: &[u8] = if request.starts_with(b"GET /broken ") {
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nonly ten.."
+                } else {
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+                };
+                let _ = stream.write_all(response).await;
+            });
+        }
+    });
+    address
+}
+
+/// Make sure failures are reported on the stream they happen on: a status for errors balancebeam
+/// can answer, or a reset for a response that breaks off after it has started. Other streams on
+/// the connection carry on.
+#[tokio::test]
+async fn test_stream_errors() {
+    init_logging();
+    let upstream_address = start_flaky_upstream().await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+    let client = h2c_client();
+
+    let request = |path: &str| {
+        Request::get(format!("http://{}{}", balancebeam.address, path))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let (broken, fine) = tokio::join!(
+        send(&client, request("/broken")),
+        send(&client, request("/fine")),
+    );
+    assert!(
+        broken.is_err(),
+        "The broken response should have been reset"
+    );
+    assert_eq!(fine.unwrap(), (200, "ok".to_string()));
+    let (status, body) = send(&client, request("/after")).await.unwrap();
+    assert_eq!((status, body.as_str()), (200, "ok"));
+
+    // Errors balancebeam generates itself come through as ordinary responses
+    let balancebeam = BalanceBeam::new(&[&common::random_address()], None, None).await;
+    let request = Request::get(format!("http://{}/", balancebeam.address))
+        .body(Body::empty())
+        .unwrap();
+    let (status, _) = send(&client, request).await.unwrap();
+    assert_eq!(status, 502);
+
+    log::info!("All done :)");
+}
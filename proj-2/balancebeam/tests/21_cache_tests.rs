@@ -0,0 +1,275 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use common::{init_logging, write_config_file, BalanceBeam};
+use hyper::{Body, Request, Response};
+
+/// The path and If-None-Match header of each request an upstream received
+type RequestLog = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+/// An upstream whose responses carry different caching headers depending on the path. It keeps
+/// a log of the requests it received.
+struct CachingUpstream {
+    address: String,
+    requests: RequestLog,
+}
+
+impl CachingUpstream {
+    async fn new() -> CachingUpstream {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let logged_requests = requests.clone();
+        let address =
+            common::start_upstream(move |request| respond(&logged_requests, request)).await;
+        CachingUpstream { address, requests }
+    }
+
+    /// Returns how many requests for `path` reached the upstream.
+    fn count(&self, path: &str) -> usize {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(requested, _)| requested == path)
+            .count()
+    }
+}
+
+fn respond(requests: &RequestLog, request: Request<Body>) -> Response<Body> {
+    let path = request.uri().path().to_string();
+    let if_none_match = request
+        .headers()
+        .get("if-none-match")
+        .map(|value| value.to_str().unwrap().to_string());
+    requests
+        .lock()
+        .unwrap()
+        .push((path.clone(), if_none_match.clone()));
+    let response = Response::builder();
+    let response = match path.as_str() {
+        "/fresh" => response
+            .header("Cache-Control", "max-age=60")
+            .body(Body::from("fresh")),
+        "/no-store" => response
+            .header("Cache-Control", "no-store")
+            .body(Body::from("no-store")),
+        "/private" => response
+            .header("Cache-Control", "private, max-age=60")
+            .body(Body::from("private")),
+        "/expires" => {
+            let expires = chrono::Utc::now() + chrono::Duration::seconds(60);
+            response
+                .header(
+                    "Expires",
+                    expires.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+                )
+                .body(Body::from("expires"))
+        }
+        "/etag" if if_none_match.as_deref() == Some("\"v1\"") => response
+            .status(http::StatusCode::NOT_MODIFIED)
+            .header("ETag", "\"v1\"")
+            .header("Cache-Control", "no-cache")
+            .body(Body::empty()),
+        "/etag" => response
+            .header("ETag", "\"v1\"")
+            .header("Cache-Control", "no-cache")
+            .body(Body::from("etag")),
+        "/vary" => {
+            let language = request.headers().get("accept-language").cloned();
+            response
+                .header("Cache-Control", "max-age=60")
+                .header("Vary", "Accept-Language")
+                .body(Body::from(format!("{:?}", language)))
+        }
+        _ => response
+            .header("Cache-Control", "max-age=60")
+            .body(Body::from("x".repeat(1000))),
+    };
+    response.unwrap()
+}
+
+/// Sends a GET request with the given headers, returning the body along with the X-Cache header.
+async fn get(balancebeam: &BalanceBeam, path: &str, headers: &[(&str, &str)]) -> (String, String) {
+    let mut request = reqwest::Client::new().get(format!("http://{}{}", balancebeam.address, path));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let cache_status = response
+        .headers()
+        .get("x-cache")
+        .map(|value| value.to_str().unwrap().to_string())
+        .unwrap_or_default();
+    (response.text().await.unwrap(), cache_status)
+}
+
+async fn start_balancebeam(upstream: &CachingUpstream, max_size: &str) -> BalanceBeam {
+    BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(0),
+        None,
+        &["--cache-max-size", max_size],
+    )
+    .await
+}
+
+/// Make sure fresh responses are served out of the cache, and responses that may not be stored
+/// aren't
+#[tokio::test]
+async fn test_cache_control() {
+    init_logging();
+    let upstream = CachingUpstream::new().await;
+    let balancebeam = start_balancebeam(&upstream, "1000000").await;
+
+    for path in ["/fresh", "/expires"] {
+        assert_eq!(get(&balancebeam, path, &[]).await.1, "MISS");
+        let (body, cache_status) = get(&balancebeam, path, &[]).await;
+        assert_eq!(body, &path[1..]);
+        assert_eq!(cache_status, "HIT");
+        assert_eq!(upstream.count(path), 1, "{} should have been cached", path);
+    }
+    for path in ["/no-store", "/private"] {
+        for _ in 0..2 {
+            let (body, cache_status) = get(&balancebeam, path, &[]).await;
+            assert_eq!(body, &path[1..]);
+            assert_eq!(cache_status, "MISS");
+        }
+        assert_eq!(
+            upstream.count(path),
+            2,
+            "{} should not have been cached",
+            path
+        );
+    }
+    // Clients can insist on going to the upstream
+    get(&balancebeam, "/fresh", &[("Cache-Control", "no-cache")]).await;
+    assert_eq!(upstream.count("/fresh"), 2);
+
+    log::info!("All done :)");
+}
+
+/// Make sure a stale response with an ETag is revalidated with a conditional request, and sent from
+/// the cache when the upstream answers 304
+#[tokio::test]
+async fn test_revalidation() {
+    init_logging();
+    let upstream = CachingUpstream::new().await;
+    let balancebeam = start_balancebeam(&upstream, "1000000").await;
+
+    assert_eq!(
+        get(&balancebeam, "/etag", &[]).await,
+        ("etag".to_string(), "MISS".to_string())
+    );
+    assert_eq!(
+        get(&balancebeam, "/etag", &[]).await,
+        ("etag".to_string(), "REVALIDATED".to_string())
+    );
+    assert_eq!(
+        *upstream.requests.lock().unwrap(),
+        vec![
+            ("/etag".to_string(), None),
+            ("/etag".to_string(), Some("\"v1\"".to_string()))
+        ]
+    );
+
+    log::info!("All done :)");
+}
+
+/// Make sure responses are stored separately for each value of the headers they vary on
+#[tokio::test]
+async fn test_vary() {
+    init_logging();
+    let upstream = CachingUpstream::new().await;
+    let balancebeam = start_balancebeam(&upstream, "1000000").await;
+
+    let english = [("Accept-Language", "en")];
+    let french = [("Accept-Language", "fr")];
+    assert_eq!(get(&balancebeam, "/vary", &english).await.1, "MISS");
+    assert_eq!(get(&balancebeam, "/vary", &french).await.1, "MISS");
+    let (body, cache_status) = get(&balancebeam, "/vary", &english).await;
+    assert_eq!(cache_status, "HIT");
+    assert_eq!(body, "Some(\"en\")");
+    let (body, cache_status) = get(&balancebeam, "/vary", &french).await;
+    assert_eq!(cache_status, "HIT");
+    assert_eq!(body, "Some(\"fr\")");
+    assert_eq!(upstream.count("/vary"), 2);
+
+    log::info!("All done :)");
+}
+
+/// Make sure the least recently used responses are dropped once the cache is full, and that
+/// requests that may change a resource drop its cached responses
+#[tokio::test]
+async fn test_eviction() {
+    init_logging();
+    let upstream = CachingUpstream::new().await;
+    // Room for two of the 1000-byte responses, but not three
+    let balancebeam = start_balancebeam(&upstream, "2500").await;
+
+    for path in ["/1", "/2", "/1", "/3", "/1", "/2"] {
+        get(&balancebeam, path, &[]).await;
+    }
+    // /2 was the least recently used response when /3 came along
+    assert_eq!(upstream.count("/1"), 1);
+    assert_eq!(upstream.count("/2"), 2);
+    assert_eq!(upstream.count("/3"), 1);
+
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/1", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(get(&balancebeam, "/1", &[]).await.1, "MISS");
+    assert_eq!(upstream.count("/1"), 3);
+
+    log::info!("All done :)");
+}
+
+/// Make sure requests for the same URL that are routed to different pools (here by a header) get
+/// their own cached responses
+#[tokio::test]
+async fn test_pools_cached_separately() {
+    init_logging();
+    let default_upstream = CachingUpstream::new().await;
+    let canary_upstream = CachingUpstream::new().await;
+    let config = format!(
+        r#"
+[health_check]
+interval = 0
+
+[cache]
+max_size = 1000000
+
+[[upstreams]]
+address = "{}"
+
+[[routes]]
+pool = "canary"
+headers = {{ "X-Canary" = "yes" }}
+
+[[pools]]
+name = "canary"
+[[pools.upstreams]]
+address = "{}"
+"#,
+        default_upstream.address, canary_upstream.address
+    );
+    let config_path = write_config_file("balancebeam-cache", "pools.toml", &config);
+    let balancebeam = common::start_balancebeam(&config_path).await;
+
+    let canary = [("X-Canary", "yes")];
+    assert_eq!(get(&balancebeam, "/fresh", &[]).await.1, "MISS");
+    assert_eq!(get(&balancebeam, "/fresh", &canary).await.1, "MISS");
+    assert_eq!(get(&balancebeam, "/fresh", &[]).await.1, "HIT");
+    assert_eq!(get(&balancebeam, "/fresh", &canary).await.1, "HIT");
+    assert_eq!(default_upstream.count("/fresh"), 1);
+    assert_eq!(canary_upstream.count("/fresh"), 1);
+
+    std::fs::remove_file(config_path).unwrap();
+    log::info!("All done :)");
+}
@@ -0,0 +1,233 @@
+mod common;
+
+use std::io::Read;
+
+use common::{init_logging, start_upstream, BalanceBeam};
+use hyper::{Body, Request, Response};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Text that is long enough to be worth compressing
+fn long_text() -> String {
+    "All work and no play makes Jack a dull boy. ".repeat(100)
+}
+
+/// Answers with responses of different types and sizes depending on the path
+fn respond(request: Request<Body>) -> Response<Body> {
+    let response = Response::builder();
+    let response = match request.uri().path() {
+        "/small" => response
+            .header("Content-Type", "text/plain")
+            .body(Body::from("tiny")),
+        "/json" => response
+            .header("Content-Type", "application/json; charset=utf-8")
+            .header("Cache-Control", "max-age=60")
+            .body(Body::from(long_text())),
+        "/streamed" => {
+            // Sent without a Content-Length, so it arrives chunked
+            let chunks = vec![Ok::<_, std::io::Error>(long_text()), Ok(long_text())];
+            response
+                .header("Content-Type", "text/html")
+                .body(Body::wrap_stream(futures_util::stream::iter(chunks)))
+        }
+        "/encoded" => response
+            .header("Content-Type", "text/plain")
+            .header("Content-Encoding", "gzip")
+            .body(Body::from(gzip(long_text().as_bytes()))),
+        "/image" => response
+            .header("Content-Type", "image/png")
+            .body(Body::from(long_text())),
+        _ => response
+            .header("Content-Type", "text/plain")
+            .header("ETag", "\"text\"")
+            .body(Body::from(long_text())),
+    };
+    response.unwrap()
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::read::GzEncoder::new(data, flate2::Compression::default());
+    let mut compressed = Vec::new();
+    encoder.read_to_end(&mut compressed).unwrap();
+    compressed
+}
+
+/// Undoes the Content-Encoding of a body.
+fn decode(encoding: Option<&str>, body: &[u8]) -> String {
+    let mut decoded = String::new();
+    match encoding {
+        None => decoded = String::from_utf8(body.to_vec()).unwrap(),
+        Some("gzip") => {
+            flate2::read::GzDecoder::new(body)
+                .read_to_string(&mut decoded)
+                .unwrap();
+        }
+        Some("br") => {
+            brotli::Decompressor::new(body, 4096)
+                .read_to_string(&mut decoded)
+                .unwrap();
+        }
+        Some(other) => panic!("Unexpected Content-Encoding {}", other),
+    }
+    decoded
+}
+
+/// The parts of a response that compression touches
+struct Fetched {
+    encoding: Option<String>,
+    vary: Option<String>,
+    etag: Option<String>,
+    content_length: Option<String>,
+    body: Vec<u8>,
+}
+
+/// Sends a GET request, optionally with an Accept-Encoding header.
+async fn get(balancebeam: &BalanceBeam, path: &str, accept_encoding: Option<&str>) -> Fetched {
+    let mut request = reqwest::Client::new().get(format!("http://{}{}", balancebeam.address, path));
+    if let Some(accept_encoding) = accept_encoding {
+        request = request.header("Accept-Encoding", accept_encoding);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap().to_string())
+    };
+    Fetched {
+        encoding: header("content-encoding"),
+        vary: header("vary"),
+        etag: header("etag"),
+        content_length: header("content-length"),
+        body: response.bytes().await.unwrap().to_vec(),
+    }
+}
+
+/// Make sure the encoding is picked from what the client accepts, preferring brotli, and that the
+/// headers describe the compressed body
+#[tokio::test]
+async fn test_negotiation() {
+    init_logging();
+    let upstream = start_upstream(respond).await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream], None, None, &["--compress"]).await;
+
+    for (accept_encoding, expected) in [
+        (Some("gzip"), Some("gzip")),
+        (Some("br"), Some("br")),
+        (Some("gzip, deflate, br"), Some("br")),
+        (Some("br;q=0, gzip"), Some("gzip")),
+        (Some("gzip;q=0.5, br;q=0.2"), Some("gzip")),
+        (Some("*"), Some("br")),
+        (Some("identity"), None),
+        (None, None),
+    ] {
+        let fetched = get(&balancebeam, "/", accept_encoding).await;
+        assert_eq!(
+            fetched.encoding.as_deref(),
+            expected,
+            "Wrong encoding for Accept-Encoding {:?}",
+            accept_encoding
+        );
+        assert_eq!(decode(expected, &fetched.body), long_text());
+        if expected.is_some() {
+            assert!(fetched.body.len() < long_text().len());
+            assert_eq!(fetched.vary.as_deref(), Some("Accept-Encoding"));
+            assert_eq!(fetched.etag.as_deref(), Some("W/\"text\""));
+            assert!(fetched.content_length.is_none());
+        } else {
+            assert_eq!(fetched.etag.as_deref(), Some("\"text\""));
+        }
+    }
+
+    log::info!("All done :)");
+}
+
+/// Make sure small, already encoded and incompressible responses are passed through untouched,
+/// and that nothing is compressed unless compression is turned on
+#[tokio::test]
+async fn test_skipped_responses() {
+    init_logging();
+    let upstream = start_upstream(respond).await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream], None, None, &["--compress"]).await;
+
+    let fetched = get(&balancebeam, "/small", Some("gzip")).await;
+    assert_eq!(fetched.encoding, None);
+    assert_eq!(fetched.body, b"tiny");
+    let fetched = get(&balancebeam, "/encoded", Some("gzip, br")).await;
+    assert_eq!(fetched.encoding.as_deref(), Some("gzip"));
+    assert_eq!(decode(Some("gzip"), &fetched.body), long_text());
+    let fetched = get(&balancebeam, "/image", Some("gzip")).await;
+    assert_eq!(fetched.encoding, None);
+    assert_eq!(fetched.body, long_text().as_bytes());
+
+    let uncompressed = BalanceBeam::new(&[&upstream], None, None).await;
+    let fetched = get(&uncompressed, "/", Some("gzip")).await;
+    assert_eq!(fetched.encoding, None);
+    assert_eq!(fetched.content_length, Some(long_text().len().to_string()));
+
+    log::info!("All done :)");
+}
+
+/// Make sure bodies that arrive chunked are compressed as they stream through, and that responses
+/// sent out of the cache are compressed too
+#[tokio::test]
+async fn test_streamed_and_cached_responses() {
+    init_logging();
+    let upstream = start_upstream(respond).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        None,
+        None,
+        &["--compress", "--cache-max-size", "1000000"],
+    )
+    .await;
+
+    let fetched = get(&balancebeam, "/streamed", Some("gzip")).await;
+    assert_eq!(fetched.encoding.as_deref(), Some("gzip"));
+    assert_eq!(decode(Some("gzip"), &fetched.body), long_text().repeat(2));
+
+    // The cache keeps the uncompressed response, so each client gets the encoding it asked for
+    for accept_encoding in [Some("br"), Some("gzip"), None] {
+        let fetched = get(&balancebeam, "/json", accept_encoding).await;
+        assert_eq!(fetched.encoding.as_deref(), accept_encoding);
+        assert_eq!(decode(accept_encoding, &fetched.body), long_text());
+    }
+
+    log::info!("All done :)");
+}
+
+/// Make sure HTTP/1.0 clients, which don't understand chunked bodies, get the compressed body
+/// delimited by the connection closing
+#[tokio::test]
+async fn test_http_10_client() {
+    init_logging();
+    let upstream = start_upstream(respond).await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream], None, None, &["--compress"]).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.0\r\nAccept-Encoding: gzip\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let end_of_head = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .expect("Response has no end of headers");
+    let head = String::from_utf8_lossy(&response[..end_of_head]).to_lowercase();
+    log::info!("Response head:\n{}", head);
+    assert!(head.contains("content-encoding: gzip"));
+    assert!(!head.contains("transfer-encoding"));
+    assert!(!head.contains("content-length"));
+    assert_eq!(
+        decode(Some("gzip"), &response[end_of_head + 4..]),
+        long_text()
+    );
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,178 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{init_logging, start_balancebeam, start_upstream, write_config_file};
+use hyper::{Body, Request, Response};
+
+/// Echoes back the headers of each request, one `name: value` per line, and identifies the
+/// upstream in the response headers
+fn respond(request: Request<Body>) -> Response<Body> {
+    let mut echoed = String::new();
+    for (name, value) in request.headers() {
+        echoed += &format!("{}: {}\n", name, value.to_str().unwrap());
+    }
+    Response::builder()
+        .header("Server", "upstream/1.0")
+        .header("X-Powered-By", "hamsters")
+        .header("X-Powered-By", "wheels")
+        .body(Body::from(echoed))
+        .unwrap()
+}
+
+/// Returns every value of header `name` in text echoed back by the upstream.
+fn echoed_values<'a>(echoed: &'a str, name: &str) -> Vec<&'a str> {
+    echoed
+        .lines()
+        .filter_map(|line| line.split_once(": "))
+        .filter(|(line_name, _)| line_name.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value)
+        .collect()
+}
+
+/// Make sure the rules of the route a request takes are applied to the forwarded request and the
+/// response, with template variables filled in, and that other routes are left alone
+#[tokio::test]
+async fn test_header_rules() {
+    init_logging();
+    let upstream = start_upstream(respond).await;
+    let config = format!(
+        r#"
+[health_check]
+interval = 0
+
+[[upstreams]]
+address = "{0}"
+
+[[routes]]
+pool = "default"
+path_prefix = "/rewritten"
+
+[[routes.request_headers]]
+action = "set"
+name = "X-Request-Id"
+value = "{{request_id}}"
+
+[[routes.request_headers]]
+action = "add"
+name = "X-Tags"
+value = "client={{client_ip}} upstream={{upstream}}"
+
+[[routes.request_headers]]
+action = "remove"
+name = "X-Secret"
+
+[[routes.request_headers]]
+action = "rename"
+name = "X-Old"
+to = "X-New"
+
+[[routes.response_headers]]
+action = "remove"
+name = "Server"
+
+[[routes.response_headers]]
+action = "set"
+name = "Strict-Transport-Security"
+value = "max-age=31536000"
+
+[[routes.response_headers]]
+action = "rename"
+name = "X-Powered-By"
+to = "X-Backend"
+
+[[routes.response_headers]]
+action = "set"
+name = "X-Served-By"
+value = "{{upstream}}"
+"#,
+        upstream
+    );
+    let config_path = write_config_file("balancebeam-header-rules", "rules.toml", &config);
+    let balancebeam = start_balancebeam(&config_path).await;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!("http://{}/rewritten", balancebeam.address))
+        .header("X-Tags", "original")
+        .header("X-Secret", "hunter2")
+        .header("X-Old", "renamed")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let headers = response.headers().clone();
+    let echoed = response.text().await.unwrap();
+    log::info!("Echoed request headers:\n{}", echoed);
+
+    let request_id = echoed_values(&echoed, "x-request-id");
+    assert_eq!(request_id.len(), 1);
+    assert_eq!(request_id[0].len(), 32);
+    assert_eq!(
+        echoed_values(&echoed, "x-tags"),
+        vec![
+            "original",
+            &format!("client=127.0.0.1 upstream={}", upstream)
+        ]
+    );
+    assert!(echoed_values(&echoed, "x-secret").is_empty());
+    assert!(echoed_values(&echoed, "x-old").is_empty());
+    assert_eq!(echoed_values(&echoed, "x-new"), vec!["renamed"]);
+
+    assert!(headers.get("server").is_none());
+    assert_eq!(headers["strict-transport-security"], "max-age=31536000");
+    assert!(headers.get("x-powered-by").is_none());
+    let backends: Vec<_> = headers.get_all("x-backend").iter().collect();
+    assert_eq!(backends, vec!["hamsters", "wheels"]);
+    assert_eq!(headers["x-served-by"], upstream.as_str());
+
+    // Requests no route matches don't go through any rules
+    let response = client
+        .get(format!("http://{}/other", balancebeam.address))
+        .header("X-Secret", "hunter2")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.headers()["server"], "upstream/1.0");
+    let echoed = response.text().await.unwrap();
+    assert_eq!(echoed_values(&echoed, "x-secret"), vec!["hunter2"]);
+    assert!(echoed_values(&echoed, "x-request-id").is_empty());
+
+    let _ = std::fs::remove_file(config_path);
+    log::info!("All done :)");
+}
+
+/// Make sure a configuration with a rule that refers to an unknown variable or touches the framing
+/// of the body is refused
+#[tokio::test]
+async fn test_invalid_rules() {
+    init_logging();
+    for (name, rule) in [
+        (
+            "variable",
+            "action = \"set\"\nname = \"X-Id\"\nvalue = \"{nope}\"",
+        ),
+        ("framing", "action = \"remove\"\nname = \"Content-Length\""),
+        ("action", "action = \"replace\"\nname = \"X-Id\""),
+    ] {
+        let config = format!(
+            "[[upstreams]]\naddress = \"127.0.0.1:1\"\n\n[[routes]]\npool = \"default\"\n\n\
+             [[routes.response_headers]]\n{}\n",
+            rule
+        );
+        let config_path = write_config_file(
+            "balancebeam-header-rules",
+            &format!("{}.toml", name),
+            &config,
+        );
+        let mut balancebeam = start_balancebeam(&config_path).await;
+        let status = balancebeam
+            .wait_for_exit(Duration::from_secs(5))
+            .await
+            .expect("balancebeam should have refused the configuration");
+        assert!(!status.success(), "{} rule was accepted", name);
+        let _ = std::fs::remove_file(config_path);
+    }
+
+    log::info!("All done :)");
+}
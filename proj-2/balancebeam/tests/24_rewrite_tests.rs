@@ -0,0 +1,140 @@
+mod common;
+
+use common::{init_logging, start_balancebeam, write_config_file, BalanceBeam};
+use hyper::{Body, Request, Response};
+
+/// Starts an upstream that answers with `respond`, returning its address
+async fn start_upstream() -> String {
+    let address = common::random_address();
+    let own_address = address.clone();
+    common::start_upstream_at(address, move |request| respond(&own_address, request)).await
+}
+
+/// Answers with the path and query that were asked for, except that paths starting with /redirect
+/// get a redirect to the Location the rest of the path names
+fn respond(own_address: &str, request: Request<Body>) -> Response<Body> {
+    let path_and_query = request.uri().path_and_query().unwrap().as_str();
+    let host = request.headers()["host"].to_str().unwrap();
+    let location = match request.uri().path() {
+        "/redirect/relative" => "/users/1".to_string(),
+        "/redirect/upstream" => format!("http://{}/users/2?tab=posts", own_address),
+        "/redirect/host" => format!("http://{}/users/3", host),
+        "/redirect/elsewhere" => "https://example.org/users/4".to_string(),
+        _ => return Response::new(Body::from(path_and_query.to_string())),
+    };
+    Response::builder()
+        .status(http::StatusCode::FOUND)
+        .header("Location", location)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Sends a GET request without following redirects, returning the body and the Location header.
+async fn get(balancebeam: &BalanceBeam, path: &str) -> (String, Option<String>) {
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let response = client
+        .get(format!("http://{}{}", balancebeam.address, path))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let location = response
+        .headers()
+        .get("location")
+        .map(|value| value.to_str().unwrap().to_string());
+    (response.text().await.unwrap(), location)
+}
+
+fn config(upstream: &str) -> String {
+    format!(
+        r#"
+[health_check]
+interval = 0
+
+[[upstreams]]
+address = "{}"
+
+[[routes]]
+pool = "default"
+path_prefix = "/api"
+strip_path_prefix = true
+
+[[routes]]
+pool = "default"
+path_prefix = "/legacy"
+
+[[routes.rewrites]]
+pattern = "^/legacy/item/([0-9]+)"
+replacement = "/items?id=$1"
+
+[[routes.rewrites]]
+pattern = "^/items\\?id=0$"
+replacement = "/items/first"
+"#,
+        upstream
+    )
+}
+
+/// Make sure routes strip their path prefix and apply their rewrites before forwarding, leaving
+/// requests other routes take alone
+#[tokio::test]
+async fn test_uri_rewriting() {
+    init_logging();
+    let upstream = start_upstream().await;
+    let config_path = write_config_file("balancebeam-rewrite", "uri.toml", &config(&upstream));
+    let balancebeam = start_balancebeam(&config_path).await;
+
+    for (path, forwarded) in [
+        ("/api/users?page=2", "/users?page=2"),
+        ("/api", "/"),
+        ("/api?page=2", "/?page=2"),
+        ("/legacy/item/42", "/items?id=42"),
+        ("/legacy/item/0", "/items/first"),
+        ("/legacy/other", "/legacy/other"),
+        ("/apiary", "/apiary"),
+    ] {
+        assert_eq!(get(&balancebeam, path).await.0, forwarded, "for {}", path);
+    }
+
+    let _ = std::fs::remove_file(config_path);
+    log::info!("All done :)");
+}
+
+/// Make sure redirects from the upstream are pointed back through balancebeam, with the stripped
+/// prefix put back
+#[tokio::test]
+async fn test_location_rewriting() {
+    init_logging();
+    let upstream = start_upstream().await;
+    let config_path = write_config_file("balancebeam-rewrite", "location.toml", &config(&upstream));
+    let balancebeam = start_balancebeam(&config_path).await;
+
+    let host = &balancebeam.address;
+    for (path, location) in [
+        ("/api/redirect/relative", "/api/users/1".to_string()),
+        (
+            "/api/redirect/upstream",
+            "/api/users/2?tab=posts".to_string(),
+        ),
+        ("/api/redirect/host", format!("http://{}/api/users/3", host)),
+        (
+            "/api/redirect/elsewhere",
+            "https://example.org/users/4".to_string(),
+        ),
+        // Without a stripped prefix, only links to the upstream itself need changing
+        ("/redirect/relative", "/users/1".to_string()),
+        ("/redirect/upstream", "/users/2?tab=posts".to_string()),
+    ] {
+        assert_eq!(
+            get(&balancebeam, path).await.1,
+            Some(location),
+            "for {}",
+            path
+        );
+    }
+
+    let _ = std::fs::remove_file(config_path);
+    log::info!("All done :)");
+}
@@ -0,0 +1,47 @@
+use std::path::PathBuf;
+
+use rustls::pki_types::CertificateDer;
+
+/// A self-signed certificate for some hosts, written to PEM files in the temp directory
+#[allow(dead_code)]
+pub struct TestCertificate {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub der: CertificateDer<'static>,
+}
+
+impl TestCertificate {
+    #[allow(dead_code)]
+    pub fn new(hosts: &[&str]) -> TestCertificate {
+        let hosts: Vec<String> = hosts.iter().map(|host| host.to_string()).collect();
+        let certified_key =
+            rcgen::generate_simple_self_signed(hosts).expect("Could not generate certificate");
+        let path = |extension: &str| {
+            std::env::temp_dir().join(format!(
+                "balancebeam-{}-{}.{}",
+                std::process::id(),
+                rand::random::<u32>(),
+                extension
+            ))
+        };
+        let cert_path = path("crt");
+        let key_path = path("key");
+        std::fs::write(&cert_path, certified_key.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified_key.key_pair.serialize_pem()).unwrap();
+        TestCertificate {
+            cert_path,
+            key_path,
+            der: certified_key.cert.der().clone(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn sni_arg(&self, host: &str) -> String {
+        format!(
+            "{}={},{}",
+            host,
+            self.cert_path.display(),
+            self.key_path.display()
+        )
+    }
+}
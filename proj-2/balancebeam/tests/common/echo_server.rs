@@ -1,16 +1,18 @@
+use crate::common::random_address;
 use crate::common::server::Server;
 use async_trait::async_trait;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response};
-use rand::Rng;
 use std::sync::{atomic, Arc};
 use tokio::sync::oneshot;
 
+#[allow(dead_code)]
 #[derive(Debug)]
 struct ServerState {
     pub requests_received: atomic::AtomicUsize,
 }
 
+#[allow(dead_code)]
 async fn echo(
     server_state: Arc<ServerState>,
     req: Request<Body>,
@@ -27,11 +29,26 @@ async fn echo(
         );
     }
     req_text += "\n";
+    let send_chunked = req.headers().contains_key("x-echo-chunked");
     let mut req_as_bytes = req_text.into_bytes();
     req_as_bytes.extend(hyper::body::to_bytes(req.into_body()).await?);
+    if send_chunked {
+        // Stream the response in several pieces without a Content-Length, which makes hyper send it
+        // with chunked transfer encoding
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for piece in req_as_bytes.chunks(16) {
+                if sender.send_data(piece.to_vec().into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        return Ok(Response::new(body));
+    }
     Ok(Response::new(Body::from(req_as_bytes)))
 }
 
+#[allow(dead_code)]
 pub struct EchoServer {
     shutdown_signal_sender: oneshot::Sender<()>,
     server_task: tokio::task::JoinHandle<()>,
@@ -39,10 +56,10 @@ pub struct EchoServer {
     state: Arc<ServerState>,
 }
 
+#[allow(dead_code)]
 impl EchoServer {
     pub async fn new() -> EchoServer {
-        let mut rng = rand::thread_rng();
-        EchoServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024..65535))).await
+        EchoServer::new_at_address(random_address()).await
     }
 
     pub async fn new_at_address(bind_addr_string: String) -> EchoServer {
@@ -1,17 +1,120 @@
 mod balancebeam;
+mod certificate;
 mod echo_server;
 mod error_server;
 mod server;
+mod slow_server;
 
-use std::sync;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use rand::Rng;
+use std::path::{Path, PathBuf};
+use std::sync::{self, Arc};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 pub use balancebeam::BalanceBeam;
+#[allow(unused_imports)]
+pub use certificate::TestCertificate;
+#[allow(unused_imports)]
 pub use echo_server::EchoServer;
+#[allow(unused_imports)]
 pub use error_server::ErrorServer;
+#[allow(unused_imports)]
 pub use server::Server;
+#[allow(unused_imports)]
+pub use slow_server::SlowServer;
 
 static INIT_TESTS: sync::Once = sync::Once::new();
 
+/// Picks a random loopback address for a test server to listen on. Ports are drawn from below
+/// Linux's ephemeral port range (32768-60999) so that they don't collide with the source ports of
+/// the many client connections the tests open.
+pub fn random_address() -> String {
+    format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..32768))
+}
+
+/// Starts an upstream that answers every request with whatever `respond` returns for it, returning
+/// the upstream's address. Unlike the test servers, it keeps running until the test ends.
+#[allow(dead_code)]
+pub async fn start_upstream<F>(respond: F) -> String
+where
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + 'static,
+{
+    start_upstream_at(random_address(), respond).await
+}
+
+/// Like `start_upstream`, but listens on `address`. Useful for upstreams that need to know their
+/// own address.
+#[allow(dead_code)]
+pub async fn start_upstream_at<F>(address: String, respond: F) -> String
+where
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + 'static,
+{
+    let bind_addr = address.parse().unwrap();
+    let respond = Arc::new(respond);
+    let service = make_service_fn(move |_| {
+        let respond = respond.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |request| {
+                let response = respond(request);
+                async move { Ok::<_, hyper::Error>(response) }
+            }))
+        }
+    });
+    tokio::spawn(hyper::Server::bind(&bind_addr).serve(service));
+    address
+}
+
+/// Sends `request` to `address` over a fresh connection exactly as given, then closes our side of
+/// the connection and returns everything the server sends back before it hangs up. This is useful
+/// for requests that an HTTP client library won't produce.
+#[allow(dead_code)]
+pub async fn send_raw_request(address: &str, request: &[u8]) -> String {
+    let mut stream = tokio::net::TcpStream::connect(address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(request)
+        .await
+        .expect("Could not send request");
+    stream.shutdown().await.expect("Could not shut down stream");
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .await
+        .expect("Could not read response");
+    String::from_utf8_lossy(&response).to_string()
+}
+
+/// Returns the value of header `name` in a request echoed back by an `EchoServer`.
+#[allow(dead_code)]
+pub fn echoed_header<'a>(echoed: &'a str, name: &str) -> Option<&'a str> {
+    echoed
+        .lines()
+        .find_map(|line| line.strip_prefix(&format!("{}: ", name)))
+}
+
+/// Writes `contents` to a config file in the temp directory, returning its path. The file is named
+/// `{prefix}-{process ID}-{name}`, so `name` should end in the extension of the file's format.
+#[allow(dead_code)]
+pub fn write_config_file(prefix: &str, name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("{}-{}-{}", prefix, std::process::id(), name));
+    std::fs::write(&path, contents).expect("Could not write config file");
+    path
+}
+
+/// Starts balancebeam with nothing but the config file at `config_path`.
+#[allow(dead_code)]
+pub async fn start_balancebeam(config_path: &Path) -> BalanceBeam {
+    BalanceBeam::new_with_args(
+        &[],
+        None,
+        None,
+        &["--config", config_path.to_str().unwrap()],
+    )
+    .await
+}
+
 pub fn init_logging() {
     INIT_TESTS.call_once(|| {
         pretty_env_logger::formatted_builder()
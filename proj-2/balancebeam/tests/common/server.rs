@@ -2,6 +2,8 @@ use async_trait::async_trait;
 
 #[async_trait]
 pub trait Server {
+    #[allow(dead_code)]
     async fn stop(self: Box<Self>) -> usize;
+    #[allow(dead_code)]
     fn address(&self) -> String;
 }